@@ -1,4 +1,6 @@
+#[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
+#[cfg(feature = "rpc")]
 use reqwest::Client;
 use serde_json::json;
 use serde::{Deserialize, Serialize};
@@ -6,20 +8,22 @@ use serde::{Deserialize, Serialize};
 use rlp::RlpStream;
 use rlp::Rlp;
 use std::str::FromStr;
+use std::sync::RwLock;
 use ethereum_types::{Address, U256};
 use bitcoin::bip32::Xpub;
 use bitcoin::bip32::DerivationPath;
 use bitcoin::PublicKey;
 use tiny_keccak::Keccak;
 use tiny_keccak::Hasher;
+#[cfg(feature = "rpc")]
 use reqwest::header::CONTENT_TYPE;
 use serde_json::Value;
 use ethers_core::{
-    types::transaction::eip712::{TypedData, Eip712},
+    types::transaction::eip712::{encode_field, hash_type, TypedData, Types},
     utils::keccak256,
 };
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 pub struct Wallet{
     infura_url: String,
     xpub : String,
@@ -31,11 +35,19 @@ pub struct Wallet{
     balance: String,
     gas_price: String,
     max_priority_fee_per_gas : String,
+    multicall_override : Option<String>,
+    known_tokens : Vec<KnownToken>,
+    listed_tokens : Vec<ListedToken>,
+    address_book : Vec<AddressBookEntry>,
+    pending_txs : Vec<PendingTx>,
+    sent_txs : Vec<PendingTx>,
+    ipfs_gateway : String,
+    arweave_gateway : String,
 }
 
-#[wasm_bindgen]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
 impl Wallet {
-    #[wasm_bindgen(constructor)]
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
     pub fn new(xpub: String, account_derivation_path : String, infura_url: String, chain_id: u64) -> Wallet { //Acount derivation paths must be in the format m/x/y eg: "m/0/0" or "m/1/2"
         Wallet {
             infura_url,
@@ -48,105 +60,14 @@ impl Wallet {
             balance: "0".to_string(),
             gas_price: "0".to_string(),
             max_priority_fee_per_gas: "0".to_string(),
-        }
-    }
-    pub async fn sync(&mut self) -> String {
-        let url = self.infura_url.clone();
-        let client = reqwest::Client::new();
-        let addr = self.address(); 
-
-        // Batch JSON-RPC request
-        let request_body = json!([
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_getBalance",
-                "params": [addr.clone(), "latest"],
-                "id": 1,
-            },
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_getTransactionCount",
-                "params": [addr.clone(), "latest"],
-                "id": 2,
-            },
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_gasPrice",
-                "params": [],
-                "id": 3,
-            },
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_maxPriorityFeePerGas",
-                "params": [],
-                "id": 4,
-            }
-        ]);
-
-        let response = match client.post(&url)
-            .header(CONTENT_TYPE, "application/json")
-            .json(&request_body)
-            .send()
-            .await {
-                Ok(resp) => resp,
-                Err(_) => return "Error: Infura error.".to_string(),
-            };
-
-        if response.status().is_success() {
-            let body = response.text().await.unwrap();
-            let parsed: Value = match serde_json::from_str(&body) {
-                Ok(val) => val,
-                Err(_) => return "Error: JSON parse error.".to_string(),
-            };
-
-            let responses = match parsed.as_array() {
-                Some(arr) => arr,
-                None => return "Error: Unexpected JSON format.".to_string(),
-            };
-
-            for resp in responses {
-                let id = resp["id"].as_i64().unwrap_or_default();
-                let result = match resp["result"].as_str() {
-                    Some(r) => r,
-                    None => continue,
-                };
-                match id {
-                    1 => { // eth_getBalance
-                        let balance = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
-                            Ok(val) => val,
-                            Err(_) => return "Error: Balance parse error.".to_string(),
-                        };
-                        self.balance = gas_price_to_string(balance);
-                        self.eth_balance = wei_to_eth(balance);
-                    },
-                    2 => { // eth_getTransactionCount (nonce)
-                        let nonce = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
-                            Ok(val) => val,
-                            Err(_) => return "Error: Nonce parse error.".to_string(),
-                        };
-                        self.nonce = nonce.low_u64();
-                    },
-                    3 => { // eth_gasPrice
-                        let gas_price = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
-                            Ok(val) => val,
-                            Err(_) => return "Error: Gas price parse error.".to_string(),
-                        };
-                        self.gas_price = gas_price_to_string(gas_price);
-                    },
-                    4 => {
-                        // eth_maxPriorityFeePerGas
-                        let mpf = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
-                            Ok(v) => v,
-                            Err(_) => return "Error: Priority fee parse error.".to_string(),
-                        };
-                        self.max_priority_fee_per_gas = gas_price_to_string(mpf);
-                    }
-                    _ => {}
-                }
-            }
-            return "Sync successful.".to_string();
-        } else {
-            return "Error: Infura error.".to_string();
+            multicall_override: None,
+            known_tokens: Vec::new(),
+            listed_tokens: Vec::new(),
+            address_book: Vec::new(),
+            pending_txs: Vec::new(),
+            sent_txs: Vec::new(),
+            ipfs_gateway: DEFAULT_IPFS_GATEWAY.to_string(),
+            arweave_gateway: DEFAULT_ARWEAVE_GATEWAY.to_string(),
         }
     }
     //fee rate determines tx fee, 0 = slow, 1 = medium, 2 = fast
@@ -266,10 +187,14 @@ impl Wallet {
             }
         };
 
-        // 2) Parse the “to” address
-        let to_addr = match Address::from_str(&to) {
-            Ok(a) => a,
-            Err(_) => return "Error: Failed to parse the recipient address.".to_string(),
+        // 2) Parse the “to” address, an empty “to” is a contract creation
+        let to_addr = if to.trim().is_empty() {
+            None
+        } else {
+            match Address::from_str(&to) {
+                Ok(a) => Some(a),
+                Err(_) => return "Error: Failed to parse the recipient address.".to_string(),
+            }
         };
 
         // 3) Decode the data payload
@@ -324,7 +249,10 @@ impl Wallet {
         stream.append(&pri);
         stream.append(&fee);
         stream.append(&gas_limit_u256);
-        stream.append(&to_addr);
+        match &to_addr {
+            Some(a) => stream.append(a),
+            None => stream.append_empty_data(),
+        };
         stream.append(&value_u256);
         stream.append(&data_bytes);
         stream.begin_list(0);
@@ -359,9 +287,9 @@ impl Wallet {
         };
 
         // 2) Compute the digest (this is already keccak256(0x19||domain||message))
-        let digest: [u8; 32] = match typed.encode_eip712() {
+        let digest: [u8; 32] = match typed_data_digest(&typed, TypedDataVersion::V4) {
             Ok(d) => d,
-            Err(_) => return "Error: Failed to encode EIP-712 payload.".into(),
+            Err(e) => return e,
         };
 
         // 3) Build the to-sign buffer: [ digest || derivation bytes ]
@@ -381,6 +309,51 @@ impl Wallet {
         // 6) Return exactly as before: "{hex-digest}:&{base64(digest||derivation)}"
         format!("{}:&{}", payload_hex, b64)
     }
+    //Legacy eth_signTypedData (v1), the typed data is an array of {type, name, value}
+    pub fn prepare_sign_typed_data_v1(&self, typed_data_json: String) -> String {
+        // 1) Parse the legacy array
+        let fields: Vec<Value> = match serde_json::from_str(&typed_data_json) {
+            Ok(Value::Array(arr)) => arr,
+            _ => return "Error: Failed to parse typed data JSON.".into(),
+        };
+
+        // 2) keccak256(keccak256(schema) || keccak256(values)) as done by eth-sig-util
+        let digest = match typed_data_v1_digest(&fields) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+
+        // 3) Same device payload as v4: "{hex-digest}:&{base64(digest||derivation)}"
+        digest_payload(&digest, &self.account_derivation_path)
+    }
+    //eth_signTypedData_v3 has no arrays and skips missing members instead of hashing them as zero
+    pub fn prepare_sign_typed_data_v3(&self, typed_data_json: String) -> String {
+        let typed: TypedData = match serde_json::from_str(&typed_data_json) {
+            Ok(td) => td,
+            Err(_) => return "Error: Failed to parse typed data JSON.".into(),
+        };
+        match typed_data_digest(&typed, TypedDataVersion::V3) {
+            Ok(digest) => digest_payload(&digest, &self.account_derivation_path),
+            Err(e) => e,
+        }
+    }
+    //Picks the typed data version from the Wallet Connect method name
+    pub fn prepare_sign_typed_data(&self, method: String, typed_data_json: String) -> String {
+        match method.as_str() {
+            "eth_signTypedData_v4" => self.prepare_sign_typed_data_v4(typed_data_json),
+            "eth_signTypedData_v3" => self.prepare_sign_typed_data_v3(typed_data_json),
+            "eth_signTypedData_v1" => self.prepare_sign_typed_data_v1(typed_data_json),
+            "eth_signTypedData" => {
+                // Plain eth_signTypedData is v1 when given an array, otherwise dapps mean v4
+                if typed_data_json.trim_start().starts_with('[') {
+                    self.prepare_sign_typed_data_v1(typed_data_json)
+                } else {
+                    self.prepare_sign_typed_data_v4(typed_data_json)
+                }
+            }
+            _ => "Error: Unsupported typed data method.".into(),
+        }
+    }
     pub fn signature_hex_from_b64(&self, tx_signature_b64: String) -> String {
         // 1) Decode base64
         let sig = match base64::decode(&tx_signature_b64) {
@@ -436,6 +409,58 @@ impl Wallet {
         let b64 = base64::encode(&to_sign);
         format!("{}:&{}", payload_hex, b64)
     }
+    //Sign-In with Ethereum (EIP-4361), message can be hex or plain text
+    pub fn parse_siwe_message(&self, message: String) -> String {
+        let text = match message_text(&message) {
+            Some(t) => t,
+            None => return "Error: Message is not valid UTF-8.".to_string(),
+        };
+        match SiweMessage::parse(&text) {
+            Ok(siwe) => serde_json::to_string(&siwe).unwrap_or_else(|_| "Error: Failed to serialize SIWE message.".to_string()),
+            Err(e) => e,
+        }
+    }
+    //Checks a SIWE message against this wallet and the requesting origin before it is sent to the device.
+    //now is the current unix time in seconds, returns {"message":{..},"warnings":[{code,message}..]}
+    pub fn validate_siwe(&mut self, message: String, origin: String, now: u64) -> String {
+        let text = match message_text(&message) {
+            Some(t) => t,
+            None => return "Error: Message is not valid UTF-8.".to_string(),
+        };
+        let siwe = match SiweMessage::parse(&text) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let address = self.address();
+        if address.starts_with("Error") { return address; }
+
+        let warnings = siwe.warnings(&address, self.chain_id, &origin, now);
+        json!({
+            "message": siwe,
+            "warnings": warnings,
+        }).to_string()
+    }
+    //Verifies a completed SIWE signature, signature can be hex or base64 from the device
+    pub fn verify_siwe(&self, message: String, signature: String) -> String {
+        let text = match message_text(&message) {
+            Some(t) => t,
+            None => return "Error: Message is not valid UTF-8.".to_string(),
+        };
+        let siwe = match SiweMessage::parse(&text) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        let digest = keccak256(eip191_bytes(text.as_bytes()));
+        let signer = match recover_signer(&digest, &signature) {
+            Ok(s) => s,
+            Err(e) => return e,
+        };
+        json!({
+            "valid": signer.eq_ignore_ascii_case(&siwe.address),
+            "signer": signer,
+            "address": siwe.address,
+        }).to_string()
+    }
     //Use this to handle simple transfer functions from Wallet connect using EIP 1559
     pub fn prepare_eip1559_transfer(&self, to: String, value: String, data: String) -> String {
         // 1) Parse the value
@@ -496,71 +521,1183 @@ impl Wallet {
         let b64 = base64::encode(&to_sign);
         format!("{}:&{}", unsigned_hex, b64)
     }
-    /// Reconstruct & broadcast a signed EIP-1559 tx from `<hex-rlp>` + base64 signature.
-    pub async fn broadcast_eip1559(&mut self, unsigned_tx: String, tx_signature: String) -> String {
-        // 1) decode the RLP payload
-        let hex_str = unsigned_tx.trim_start_matches("0x");
-        let mut raw = match hex::decode(hex_str) {
+    pub fn erc20_transfer(&self, contract_address: String, recipient: String, token_amount: &str, fee_rate: i32) -> String {
+        // Use a higher gas limit for token transfers.
+        let gas_limit: u64 = 160000;
+        let token_amount_u256 = U256::from_dec_str(token_amount).unwrap_or(U256::zero());
+        // Encode the ERC20 transfer data.
+        let data = encode_transfer(&recipient, token_amount_u256);
+        let new_gas_price;
+        let self_gas = gas_price_from_string(&self.gas_price);
+         match fee_rate{
+            0 => new_gas_price = &self_gas * U256::from(10) / U256::from(10),
+            1 => new_gas_price = self_gas * U256::from(15) / U256::from(10),
+            2 => new_gas_price = &self_gas * U256::from(20) / U256::from(10) ,
+            _ => new_gas_price = self_gas,
+        }
+
+        let mut stream = RlpStream::new_list(9);
+        stream.append(&U256::from(self.nonce));
+        stream.append(&new_gas_price);
+        stream.append(&U256::from(gas_limit));
+        let contract_addr = Address::from_str(&contract_address).unwrap_or(Address::zero());
+        stream.append(&contract_addr);
+        // For token transfers, ETH value is zero.
+        stream.append(&U256::zero());
+        stream.append(&data);
+        stream.append(&self.chain_id);
+        stream.append(&0u8);
+        stream.append(&0u8);
+        let rlp_encoded = stream.out();
+        let mut hasher = Keccak::v256();
+        let mut tx_hash = [0u8; 32];
+        hasher.update(&rlp_encoded);
+        hasher.finalize(&mut tx_hash);
+        let mut total_bytes : Vec<u8> = Vec::new();
+        total_bytes.extend_from_slice(&tx_hash);
+        match extract_u16s(&self.account_derivation_path) {
+            Ok((first, second)) => append_integers_as_bytes(&mut total_bytes,first,second),
+            Err(_) => return "Error: Derivation path error.".to_string(),
+        }
+        let unsigned_tx = hex::encode(rlp_encoded);
+        let final_str = unsigned_tx + ":&" + &base64::encode(&total_bytes);
+        return final_str;
+    }
+    pub fn construct_signed_tx(&self, unsigned_tx: String, tx_signature: String) -> String {
+        // 1. strip 0x and hex-decode
+        let unsigned_hex = unsigned_tx.trim_start_matches("0x");
+        let mut tx_bytes = match hex::decode(unsigned_hex) {
             Ok(b) => b,
             Err(_) => return "Error: Failed to decode the unsigned transaction.".to_string(),
         };
-        // if it starts with the type‐2 marker, strip it off:
-        if raw.first() == Some(&0x02) {
-            raw = raw[1..].to_vec();
+
+        // 2. detect EIP-1559 (with or without the 0x02 tag) & strip the tag
+        let is_eip1559 = tx_payload_type(&tx_bytes).0 == 2;
+        if tx_bytes.first() == Some(&0x02) {
+            // drop the 0x02 tag
+            tx_bytes = tx_bytes.split_off(1);
         }
-        let rlp = Rlp::new(&raw);
 
-        // 2) extract each field with explicit matches
-        let chain_id = match rlp.at(0).and_then(|f| f.as_val::<U256>()) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the chain ID.".to_string(),
-        };
-        let nonce = match rlp.at(1).and_then(|f| f.as_val::<U256>()) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the nonce.".to_string(),
-        };
-        let max_prio = match rlp.at(2).and_then(|f| f.as_val::<U256>()) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the max priority fee.".to_string(),
-        };
-        let max_fee = match rlp.at(3).and_then(|f| f.as_val::<U256>()) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the max fee.".to_string(),
-        };
-        let gas_limit = match rlp.at(4).and_then(|f| f.as_val::<U256>()) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the gas limit.".to_string(),
-        };
-        let to_addr = match rlp.at(5).and_then(|f| f.data().map(|d| d.to_vec())) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the to address.".to_string(),
-        };
-        let value = match rlp.at(6).and_then(|f| f.as_val::<U256>()) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the value.".to_string(),
-        };
-        let data_field = match rlp.at(7).and_then(|f| f.data().map(|d| d.to_vec())) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the data field.".to_string(),
-        };
-        // 8th element is the accessList — we know it was empty, so skip explicit decode
+        // 3. RLP-decode
+        let rlp = Rlp::new(&tx_bytes);
 
-        // 3) decode the signature blob
-        let sig = match base64::decode(&tx_signature) {
-            Ok(b) => b,
-            Err(_) => return "Error: Failed to decode the transaction signature.".to_string(),
+        // 4. helpers for per-field error handling
+        let get_u256 = |idx: usize, msg: &str| {
+            rlp.at(idx)
+                .and_then(|f| f.as_val::<U256>())
+                .map_err(|_| msg.to_string())
+        };
+        let get_bytes = |idx: usize, msg: &str| {
+            rlp.at(idx)
+                .and_then(|f| f.data())
+                .map(|d| d.to_vec())
+                .map_err(|_| msg.to_string())
         };
-        if sig.len() < 65 {
-            return "Error: Signature length is invalid.".to_string();
-        }
-        let r_sig = &sig[0..32];
-        let s_sig = &sig[32..64];
-        let v_raw = sig[64];
-        let rec_id = if v_raw > 1 { v_raw - 27 } else { v_raw };
 
-        // 4) RLP-encode the signed tx:
-        let mut stre = RlpStream::new_list(12);
-        stre.append(&chain_id);
+        // 5. pull out fields in the correct order
+        let (chain_id, nonce, max_priority_fee, max_fee, gas_limit, to, value, data_field) =
+            if is_eip1559 {
+                // type-2 fields: [chainId, nonce, maxPriorityFeePerGas,
+                //                   maxFeePerGas, gasLimit, to, value, data, accessList…]
+                let chain_id = match get_u256(0, "Error: Failed to decode the chain ID.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let nonce = match get_u256(1, "Error: Failed to decode the nonce.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let max_priority_fee = match get_u256(
+                    2,
+                    "Error: Failed to decode the max priority fee per gas.",
+                ) {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let max_fee = match get_u256(3, "Error: Failed to decode the max fee per gas.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let gas_limit = match get_u256(4, "Error: Failed to decode the gas limit.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let to = match get_bytes(5, "Error: Failed to decode the output.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let value = match get_u256(6, "Error: Failed to decode the value.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let data_field = match get_bytes(7, "Error: Failed to decode the data field.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                (chain_id, nonce, max_priority_fee, max_fee, gas_limit, to, value, data_field)
+            } else {
+                // legacy fields: [nonce, gasPrice, gasLimit, to, value, data, chainId…]
+                let nonce = match get_u256(0, "Error: Failed to decode the nonce.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let gas_price = match get_u256(1, "Error: Failed to decode the gas price.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let gas_limit = match get_u256(2, "Error: Failed to decode the gas limit.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let to = match get_bytes(3, "Error: Failed to decode the output.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let value = match get_u256(4, "Error: Failed to decode the value.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let data_field = match get_bytes(5, "Error: Failed to decode the data field.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                let chain_id = match get_u256(6, "Error: Failed to decode the chain ID.") {
+                    Ok(v) => v,
+                    Err(e) => return e,
+                };
+                // for legacy, both priority & max fees are simply gasPrice
+                (chain_id, nonce, gas_price, gas_price, gas_limit, to, value, data_field)
+            };
+
+        // 6. decode the signature
+        let sig_bytes = match base64::decode(&tx_signature) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the transaction signature.".to_string(),
+        };
+        if sig_bytes.len() < 65 {
+            return "Error: Failed to decode the transaction signature.".to_string();
+        }
+        let r_sig = &sig_bytes[0..32];
+        let s_sig = &sig_bytes[32..64];
+        let v_raw = sig_bytes[64];
+        let recovery_id = if v_raw > 1 { v_raw - 27 } else { v_raw };
+        let v_calc = chain_id.low_u64() * 2 + 35 + recovery_id as u64;
+
+        if is_eip1559 {
+            U256::from(recovery_id as u64)
+        } else {
+            U256::from(chain_id.low_u64() * 2 + 35 + recovery_id as u64)
+        };
+
+        // 7. rebuild the signed RLP
+        let mut stream = if is_eip1559 {
+            RlpStream::new_list(12)
+        } else {
+            RlpStream::new_list(9)
+        };
+
+        if is_eip1559 {
+            stream.append(&chain_id);
+            stream.append(&nonce);
+            stream.append(&max_priority_fee);
+            stream.append(&max_fee);
+            stream.append(&gas_limit);
+            stream.append(&to);
+            stream.append(&value);
+            stream.append(&data_field);
+            stream.begin_list(0); // empty accessList
+            stream.append_raw(&[recovery_id], 1);
+            stream.append(&r_sig);
+            stream.append(&s_sig);
+        } else {
+            stream.append(&nonce);
+            stream.append(&max_priority_fee); // gasPrice
+            stream.append(&gas_limit);
+            stream.append(&to);
+            stream.append(&value);
+            stream.append(&data_field);
+            stream.append(&v_calc);
+            stream.append(&r_sig);
+            stream.append(&s_sig);
+        }
+
+        let mut signed_bytes = stream.out().to_vec();
+        if is_eip1559 {
+            // re-prefix with 0x02
+            let mut pref = vec![0x02];
+            pref.append(&mut signed_bytes);
+            signed_bytes = pref;
+        }
+
+        format!("0x{}", hex::encode(&signed_bytes))
+    }
+    pub fn hex_to_b64(&self, tx_hash : String) -> String{
+        let hex_str = tx_hash.strip_prefix("0x").unwrap_or(&tx_hash);
+        let bytes = match hex::decode(hex_str){
+            Ok(hex) => hex,
+            Err(_) => return "Error: Invalid hex string.".to_string(),
+        };
+        return base64::encode(&bytes);
+    }
+    pub fn get_nonce(&self) -> u64{
+        return self.nonce;
+    }
+    pub fn get_chain_id(&self) -> u64{
+        return self.chain_id;
+    }
+    //Registry entry for this wallet's chain as JSON
+    pub fn get_chain_info(&self) -> String {
+        serde_json::to_string(&chain_info(self.chain_id)).unwrap_or_else(|_| "Error: Failed to serialize chain info.".to_string())
+    }
+    //Adds or replaces a chain in the registry, chain_json uses the same fields as get_chain_info
+    pub fn register_chain(chain_json: String) -> String {
+        let info: ChainInfo = match serde_json::from_str(&chain_json) {
+            Ok(c) => c,
+            Err(_) => return "Error: Failed to parse chain info.".to_string(),
+        };
+        register_chain(info);
+        "Chain registered.".to_string()
+    }
+    //Lists every known chain, built-in and registered
+    pub fn supported_chains() -> String {
+        serde_json::to_string(&all_chains()).unwrap_or_else(|_| "Error: Failed to serialize chain info.".to_string())
+    }
+    pub fn address(&mut self) -> String{
+        let address = match derive_address(&self.xpub, &self.account_derivation_path) {
+            Ok(a) => a,
+            Err(e) => return e,
+        };
+        self.address = address.clone();
+        return address;
+    }
+    //Switch the wallet to another account (for example one returned by discover_accounts)
+    pub fn set_account_derivation_path(&mut self, account_derivation_path: String) -> String {
+        if extract_u16s(&account_derivation_path).is_err() {
+            return "Error: Derivation path error.".to_string();
+        }
+        self.account_derivation_path = account_derivation_path;
+        self.address = "".to_string();
+        self.nonce = 0;
+        self.eth_balance = 0.0;
+        self.balance = "0".to_string();
+        self.pending_txs.clear();
+        self.sent_txs.clear();
+        self.address()
+    }
+    pub fn get_account_derivation_path(&self) -> String {
+        self.account_derivation_path.clone()
+    }
+    //Serialises the wallet state (sync data, known tokens, address book, pending transactions) so it can be restored without a sync
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.state()).unwrap_or_else(|_| "Error: Failed to serialize wallet state.".to_string())
+    }
+    //Restores a wallet from to_json output, returns undefined if the state is invalid or from a newer version
+    pub fn from_json(state_json: String) -> Option<Wallet> {
+        let state: WalletState = serde_json::from_str(&state_json).ok()?;
+        if state.version > WALLET_STATE_VERSION {
+            return None;
+        }
+        let balance = U256::from_dec_str(&state.balance).ok()?;
+        Some(Wallet {
+            infura_url: state.infura_url,
+            xpub: state.xpub,
+            account_derivation_path: state.account_derivation_path,
+            address: state.address,
+            chain_id: state.chain_id,
+            nonce: state.nonce,
+            eth_balance: wei_to_eth(balance),
+            balance: state.balance,
+            gas_price: state.gas_price,
+            max_priority_fee_per_gas: state.max_priority_fee_per_gas,
+            // States saved before the lookup moved to call time hold a copy of the registry address
+            multicall_override: state.multicall_address.filter(|a| Some(a) != chain_info(state.chain_id).multicall_address.as_ref()),
+            // Earlier versions also cached failed validations as decimals 0 with no symbol or name
+            known_tokens: state.known_tokens.into_iter().filter(|t| !(t.symbol.is_empty() && t.name.is_empty())).collect(),
+            listed_tokens: state.listed_tokens,
+            address_book: state.address_book,
+            pending_txs: state.pending_txs,
+            sent_txs: state.sent_txs,
+            ipfs_gateway: state.ipfs_gateway,
+            arweave_gateway: state.arweave_gateway,
+        })
+    }
+    pub fn known_tokens(&self) -> String {
+        serde_json::to_string(&self.known_tokens).unwrap_or_else(|_| "Error: Failed to serialize known tokens.".to_string())
+    }
+    pub fn pending_transactions(&self) -> String {
+        serde_json::to_string(&self.pending_txs).unwrap_or_else(|_| "Error: Failed to serialize pending transactions.".to_string())
+    }
+    pub fn balance(&self) -> String {
+        self.eth_balance.to_string()
+    }
+    //fee rate, 0 = slow, 1 = medium, 2 = fast
+    pub fn estimate_fee(&self, fee_rate : i32, gas_limit : i32) -> String{
+        let mut new_gas_price;
+        let self_gas = gas_price_from_string(&self.gas_price);
+        match fee_rate{
+            0 => new_gas_price = &self_gas * U256::from(10) / U256::from(10),
+            1 => new_gas_price = &self_gas * U256::from(15) / U256::from(10),
+            2 => new_gas_price = &self_gas * U256::from(20) / U256::from(10),
+            _ => new_gas_price = self_gas,
+        }
+        new_gas_price = new_gas_price * U256::from(gas_limit);
+        return format!("{}", wei_to_eth(new_gas_price));
+    }
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+    //NFT methods ERC721 + ERC1155
+    pub fn erc721_transfer(&self,contract_address: String,to: String, token_id: &str,fee_rate: i32,) -> String {
+        let gas_limit: u64 = 100_000;
+        let token_id_u256 = match U256::from_dec_str(token_id) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse token ID.".to_string(),
+        };
+
+        let data = encode_erc721_transfer(&self.address, &to, token_id_u256);
+
+        // gas price adjustment
+        let self_gas = gas_price_from_string(&self.gas_price);
+        let new_gas_price = match fee_rate {
+            1 => self_gas * U256::from(15) / U256::from(10),
+            2 => self_gas * U256::from(20) / U256::from(10),
+            _ => self_gas,
+        };
+
+        // RLP‐encode: [nonce, gasPrice, gasLimit, to=contract, value=0, data, chain_id, 0, 0]
+        let mut stream = RlpStream::new_list(9);
+        stream.append(&U256::from(self.nonce));
+        stream.append(&new_gas_price);
+        stream.append(&U256::from(gas_limit));
+        let caddr = Address::from_str(&contract_address).unwrap_or(Address::zero());
+        stream.append(&caddr);
+        stream.append(&U256::zero());
+        stream.append(&data);
+        stream.append(&self.chain_id);
+        stream.append(&0u8);
+        stream.append(&0u8);
+        let rlp = stream.out();
+
+        // Keccak + derivation‐bytes
+        let mut h = Keccak::v256();
+        let mut tx_hash = [0u8; 32];
+        h.update(&rlp);
+        h.finalize(&mut tx_hash);
+        let mut blob = tx_hash.to_vec();
+        if let Ok((h1, h2)) = extract_u16s(&self.account_derivation_path) {
+            append_integers_as_bytes(&mut blob, h1, h2);
+        } else {
+            return "Error: Derivation path error.".to_string();
+        }
+
+        format!("{}:&{}", hex::encode(rlp), base64::encode(&blob))
+    }
+
+    // Send an ERC-1155 `safeTransferFrom(self.address, to, tokenId, amount, bytes)`.
+    pub fn erc1155_transfer(&self,contract_address: String,to: String,token_id: &str,amount: &str,fee_rate: i32,) -> String {
+        let gas_limit: u64 = 200_000;
+        let tid = match U256::from_dec_str(token_id) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse token ID.".to_string(),
+        };
+        let amt = match U256::from_dec_str(amount) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse amount.".to_string(),
+        };
+
+        let data = encode_erc1155_transfer(&self.address, &to, tid, amt);
+
+        let self_gas = gas_price_from_string(&self.gas_price);
+        let new_gas_price = match fee_rate {
+            1 => self_gas * U256::from(15) / U256::from(10),
+            2 => self_gas * U256::from(20) / U256::from(10),
+            _ => self_gas,
+        };
+
+        let mut stream = RlpStream::new_list(9);
+        stream.append(&U256::from(self.nonce));
+        stream.append(&new_gas_price);
+        stream.append(&U256::from(gas_limit));
+        let caddr = Address::from_str(&contract_address).unwrap_or(Address::zero());
+        stream.append(&caddr);
+        stream.append(&U256::zero());
+        stream.append(&data);
+        stream.append(&self.chain_id);
+        stream.append(&0u8);
+        stream.append(&0u8);
+        let rlp = stream.out();
+
+        let mut h = Keccak::v256();
+        let mut tx_hash = [0u8; 32];
+        h.update(&rlp);
+        h.finalize(&mut tx_hash);
+        let mut blob = tx_hash.to_vec();
+        if let Ok((h1, h2)) = extract_u16s(&self.account_derivation_path) {
+            append_integers_as_bytes(&mut blob, h1, h2);
+        } else {
+            return "Error: Derivation path error.".to_string();
+        }
+
+        format!("{}:&{}", hex::encode(rlp), base64::encode(&blob))
+    }
+    //EIP 1559 version of erc721_transfer
+    pub fn erc721_transfer_eip1559(&self, contract_address: String, to: String, token_id: &str, fee_rate: i32) -> String {
+        // The sender is only known after sync, an empty one would leave the from word out of the calldata
+        let data = match parse_address(&self.address, "sender")
+            .and_then(|_| parse_token_id(token_id))
+            .and_then(|id| parse_address(&to, "recipient").map(|_| encode_erc721_transfer(&self.address, &to, id)))
+        {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.token_contract_call(&contract_address, &data, 100_000, fee_rate, true)
+    }
+    //ERC721 safeTransferFrom, which reverts if the recipient contract can't receive NFTs.
+    //An empty data uses safeTransferFrom(address,address,uint256), otherwise the hex data is passed to the receiver.
+    pub fn erc721_safe_transfer(&self, contract_address: String, to: String, token_id: &str, data: String, fee_rate: i32) -> String {
+        match encode_erc721_safe_transfer(&self.address, &to, token_id, &data) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, 150_000, fee_rate, false),
+            Err(e) => e,
+        }
+    }
+    //EIP 1559 version of erc721_safe_transfer
+    pub fn erc721_safe_transfer_eip1559(&self, contract_address: String, to: String, token_id: &str, data: String, fee_rate: i32) -> String {
+        match encode_erc721_safe_transfer(&self.address, &to, token_id, &data) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, 150_000, fee_rate, true),
+            Err(e) => e,
+        }
+    }
+    //ERC721 approve, the zero address clears the approval
+    pub fn erc721_approve(&self, contract_address: String, spender: String, token_id: &str, fee_rate: i32) -> String {
+        match encode_erc721_approve(&spender, token_id) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, 80_000, fee_rate, false),
+            Err(e) => e,
+        }
+    }
+    //EIP 1559 version of erc721_approve
+    pub fn erc721_approve_eip1559(&self, contract_address: String, spender: String, token_id: &str, fee_rate: i32) -> String {
+        match encode_erc721_approve(&spender, token_id) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, 80_000, fee_rate, true),
+            Err(e) => e,
+        }
+    }
+    //setApprovalForAll, works for both ERC721 and ERC1155 collections
+    pub fn set_approval_for_all(&self, contract_address: String, operator: String, approved: bool, fee_rate: i32) -> String {
+        match encode_set_approval_for_all(&operator, approved) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, 80_000, fee_rate, false),
+            Err(e) => e,
+        }
+    }
+    //EIP 1559 version of set_approval_for_all
+    pub fn set_approval_for_all_eip1559(&self, contract_address: String, operator: String, approved: bool, fee_rate: i32) -> String {
+        match encode_set_approval_for_all(&operator, approved) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, 80_000, fee_rate, true),
+            Err(e) => e,
+        }
+    }
+    //ERC1155 safeBatchTransferFrom, sends many ids in one transaction. token_ids and amounts are decimal strings
+    //of the same length, data is hex passed to the receiver (empty for none).
+    pub fn erc1155_batch_transfer(&self, contract_address: String, to: String, token_ids: Vec<String>, amounts: Vec<String>, data: String, fee_rate: i32) -> String {
+        match encode_erc1155_batch_transfer(&self.address, &to, &token_ids, &amounts, &data) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, erc1155_batch_gas_limit(token_ids.len()), fee_rate, false),
+            Err(e) => e,
+        }
+    }
+    //EIP 1559 version of erc1155_batch_transfer
+    pub fn erc1155_batch_transfer_eip1559(&self, contract_address: String, to: String, token_ids: Vec<String>, amounts: Vec<String>, data: String, fee_rate: i32) -> String {
+        match encode_erc1155_batch_transfer(&self.address, &to, &token_ids, &amounts, &data) {
+            Ok(d) => self.token_contract_call(&contract_address, &d, erc1155_batch_gas_limit(token_ids.len()), fee_rate, true),
+            Err(e) => e,
+        }
+    }
+    // Zero value call to a token contract at the wallet's nonce and fee rate
+    fn token_contract_call(&self, contract_address: &str, data: &[u8], gas_limit: u64, fee_rate: i32, eip1559: bool) -> String {
+        let contract = match parse_address(contract_address, "contract") {
+            Ok(a) => a,
+            Err(e) => return e,
+        };
+        if eip1559 {
+            let (max_priority_fee, max_fee) = eip1559_fees(&self.gas_price, &self.max_priority_fee_per_gas, fee_rate);
+            build_eip1559_tx(self.chain_id, U256::from(self.nonce), max_priority_fee, max_fee, U256::from(gas_limit), Some(contract), U256::zero(), data, &self.account_derivation_path)
+        } else {
+            let gas_price = fee_rate_gas_price(gas_price_from_string(&self.gas_price), fee_rate);
+            build_legacy_tx(U256::from(self.nonce), gas_price, U256::from(gas_limit), Some(contract), U256::zero(), data, self.chain_id, &self.account_derivation_path)
+        }
+    }
+    //Multicall3 address used by the read helpers instead of the chain registry's, an empty string disables it and
+    //uses JSON-RPC batches instead
+    pub fn set_multicall_address(&mut self, multicall_address: String) {
+        self.multicall_override = Some(multicall_address);
+    }
+    //Gateways used to fetch ipfs:// and ar:// NFT metadata and images, such as "https://ipfs.io/ipfs/"
+    pub fn set_ipfs_gateway(&mut self, gateway: String) {
+        self.ipfs_gateway = gateway;
+    }
+    pub fn get_ipfs_gateway(&self) -> String {
+        self.ipfs_gateway.clone()
+    }
+    pub fn set_arweave_gateway(&mut self, gateway: String) {
+        self.arweave_gateway = gateway;
+    }
+    pub fn get_arweave_gateway(&self) -> String {
+        self.arweave_gateway.clone()
+    }
+    pub fn get_multicall_address(&self) -> String {
+        self.multicall_address()
+    }
+    //Imports a Uniswap Token List (https://tokenlists.org), keeping the valid tokens for this chain. Re-importing a
+    //list with the same name replaces it. Returns {"name","imported","other_chains","invalid":[{"index","error"}]}
+    pub fn import_token_list(&mut self, list_json: String) -> String {
+        let (list, invalid, other_chains) = match parse_token_list(&list_json, self.chain_id) {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        let imported = list.tokens.len();
+        self.listed_tokens.retain(|t| t.list != list.name);
+        self.listed_tokens.extend(list.tokens);
+        json!({
+            "name": list.name,
+            "imported": imported,
+            "other_chains": other_chains,
+            "invalid": invalid,
+        }).to_string()
+    }
+    pub fn remove_token_list(&mut self, name: String) {
+        self.listed_tokens.retain(|t| t.list != name);
+    }
+    //Names of the imported token lists
+    pub fn token_lists(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for token in &self.listed_tokens {
+            if !names.contains(&token.list) {
+                names.push(token.list.clone());
+            }
+        }
+        names
+    }
+    //Imported list tokens merged with the tokens added through validate_contract, as an array of RegistryToken
+    pub fn token_registry(&self) -> String {
+        serde_json::to_string(&merge_token_registry(&self.known_tokens, &self.listed_tokens))
+            .unwrap_or_else(|_| "Error: Failed to serialize the token registry.".to_string())
+    }
+    //Adds or replaces the address book entry for an address. `chains` limits it to those chain ids, empty means
+    //every chain; `ens` and `notes` may be empty. Returns the entry as JSON
+    pub fn add_contact(&mut self, label: String, address: String, ens: String, chains: Vec<u64>, notes: String) -> String {
+        let entry = match address_book_entry(&label, &address, &ens, chains, &notes) {
+            Ok(e) => e,
+            Err(e) => return e,
+        };
+        self.address_book.retain(|c| !c.address.eq_ignore_ascii_case(&entry.address));
+        self.address_book.push(entry.clone());
+        serde_json::to_string(&entry).unwrap_or_else(|_| "Error: Failed to serialize the contact.".to_string())
+    }
+    pub fn remove_contact(&mut self, address: String) {
+        self.address_book.retain(|c| !c.address.eq_ignore_ascii_case(address.trim()));
+    }
+    //The address book entry for an address on this chain, undefined if there is none
+    pub fn lookup_contact(&self, address: String) -> Option<String> {
+        lookup_contact(&self.address_book, &address, self.chain_id).and_then(|c| serde_json::to_string(c).ok())
+    }
+    //The whole address book as a JSON array, for export
+    pub fn address_book(&self) -> String {
+        serde_json::to_string(&self.address_book).unwrap_or_else(|_| "Error: Failed to serialize the address book.".to_string())
+    }
+    //Imports an address_book export, entries replace existing ones with the same address.
+    //Returns {"imported","invalid":[{"index","error"}]}
+    pub fn import_address_book(&mut self, book_json: String) -> String {
+        let entries: Vec<Value> = match serde_json::from_str(&book_json) {
+            Ok(e) => e,
+            Err(_) => return "Error: Failed to parse the address book.".to_string(),
+        };
+        let mut imported = 0;
+        let mut invalid = Vec::new();
+        for (index, entry) in entries.iter().enumerate() {
+            let text = |name: &str| entry[name].as_str().unwrap_or_default().to_string();
+            let chains = entry["chains"].as_array().map(|c| c.iter().filter_map(|id| id.as_u64()).collect()).unwrap_or_default();
+            match address_book_entry(&text("label"), &text("address"), &text("ens"), chains, &text("notes")) {
+                Ok(contact) => {
+                    self.address_book.retain(|c| !c.address.eq_ignore_ascii_case(&contact.address));
+                    self.address_book.push(contact);
+                    imported += 1;
+                }
+                Err(e) => invalid.push(json!({ "index": index, "error": e })),
+            }
+        }
+        json!({
+            "imported": imported,
+            "invalid": invalid,
+        }).to_string()
+    }
+    //Offline builders, every parameter is explicit (decimal strings in wei) so they never depend on sync or the network
+    pub fn send_offline(&self, to: String, value: &str, nonce: u64, gas_price: &str, gas_limit: &str, chain_id: u64) -> String {
+        self.prepare_offline(to, value, String::new(), nonce, gas_price, gas_limit, chain_id)
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_eip1559_offline(&self, to: String, value: &str, nonce: u64, max_priority_fee_per_gas: &str, max_fee_per_gas: &str, gas_limit: &str, chain_id: u64) -> String {
+        self.prepare_eip1559_offline(to, value, String::new(), nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, chain_id)
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn erc20_transfer_offline(&self, contract_address: String, recipient: String, token_amount: &str, nonce: u64, gas_price: &str, gas_limit: &str, chain_id: u64) -> String {
+        let data = match erc20_transfer_calldata(&recipient, token_amount) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.prepare_offline(contract_address, "0", data, nonce, gas_price, gas_limit, chain_id)
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn erc20_transfer_eip1559_offline(&self, contract_address: String, recipient: String, token_amount: &str, nonce: u64, max_priority_fee_per_gas: &str, max_fee_per_gas: &str, gas_limit: &str, chain_id: u64) -> String {
+        let data = match erc20_transfer_calldata(&recipient, token_amount) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.prepare_eip1559_offline(contract_address, "0", data, nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, chain_id)
+    }
+    //NFT transfers send from the account derived from the xpub, so they work before sync too
+    #[allow(clippy::too_many_arguments)]
+    pub fn erc721_transfer_offline(&self, contract_address: String, to: String, token_id: &str, nonce: u64, gas_price: &str, gas_limit: &str, chain_id: u64) -> String {
+        let data = match derive_address(&self.xpub, &self.account_derivation_path).and_then(|from| erc721_transfer_calldata(&from, &to, token_id)) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.prepare_offline(contract_address, "0", data, nonce, gas_price, gas_limit, chain_id)
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn erc721_transfer_eip1559_offline(&self, contract_address: String, to: String, token_id: &str, nonce: u64, max_priority_fee_per_gas: &str, max_fee_per_gas: &str, gas_limit: &str, chain_id: u64) -> String {
+        let data = match derive_address(&self.xpub, &self.account_derivation_path).and_then(|from| erc721_transfer_calldata(&from, &to, token_id)) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.prepare_eip1559_offline(contract_address, "0", data, nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, chain_id)
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn erc1155_transfer_offline(&self, contract_address: String, to: String, token_id: &str, amount: &str, nonce: u64, gas_price: &str, gas_limit: &str, chain_id: u64) -> String {
+        let data = match derive_address(&self.xpub, &self.account_derivation_path).and_then(|from| erc1155_transfer_calldata(&from, &to, token_id, amount)) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.prepare_offline(contract_address, "0", data, nonce, gas_price, gas_limit, chain_id)
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn erc1155_transfer_eip1559_offline(&self, contract_address: String, to: String, token_id: &str, amount: &str, nonce: u64, max_priority_fee_per_gas: &str, max_fee_per_gas: &str, gas_limit: &str, chain_id: u64) -> String {
+        let data = match derive_address(&self.xpub, &self.account_derivation_path).and_then(|from| erc1155_transfer_calldata(&from, &to, token_id, amount)) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.prepare_eip1559_offline(contract_address, "0", data, nonce, max_priority_fee_per_gas, max_fee_per_gas, gas_limit, chain_id)
+    }
+    //Legacy transaction with arbitrary calldata (hex), an empty `to` is a contract creation
+    #[allow(clippy::too_many_arguments)]
+    pub fn prepare_offline(&self, to: String, value: &str, data: String, nonce: u64, gas_price: &str, gas_limit: &str, chain_id: u64) -> String {
+        let fields = (parse_optional_address(&to), parse_wei(value, "value"), parse_wei(gas_price, "gas price"), parse_wei(gas_limit, "gas limit"));
+        let (to_addr, value_u256, gas_price_u256, gas_limit_u256) = match fields {
+            (Ok(a), Ok(v), Ok(g), Ok(l)) => (a, v, g, l),
+            (Err(e), ..) | (_, Err(e), ..) | (_, _, Err(e), _) | (.., Err(e)) => return e,
+        };
+        let data_bytes = match hex::decode(data.trim_start_matches("0x")) {
+            Ok(d) => d,
+            Err(_) => return "Error: Failed to decode the data field.".to_string(),
+        };
+        build_legacy_tx(U256::from(nonce), gas_price_u256, gas_limit_u256, to_addr, value_u256, &data_bytes, chain_id, &self.account_derivation_path)
+    }
+    //EIP 1559 transaction with arbitrary calldata (hex), an empty `to` is a contract creation
+    #[allow(clippy::too_many_arguments)]
+    pub fn prepare_eip1559_offline(&self, to: String, value: &str, data: String, nonce: u64, max_priority_fee_per_gas: &str, max_fee_per_gas: &str, gas_limit: &str, chain_id: u64) -> String {
+        let fields = (parse_optional_address(&to), parse_wei(value, "value"), parse_wei(max_priority_fee_per_gas, "max priority fee"), parse_wei(max_fee_per_gas, "max fee"), parse_wei(gas_limit, "gas limit"));
+        let (to_addr, value_u256, pri, fee, gas_limit_u256) = match fields {
+            (Ok(a), Ok(v), Ok(p), Ok(f), Ok(l)) => (a, v, p, f, l),
+            (Err(e), ..) | (_, Err(e), ..) | (_, _, Err(e), ..) | (_, _, _, Err(e), _) | (.., Err(e)) => return e,
+        };
+        if pri > fee {
+            return "Error: Max priority fee is higher than the max fee.".to_string();
+        }
+        let data_bytes = match hex::decode(data.trim_start_matches("0x")) {
+            Ok(d) => d,
+            Err(_) => return "Error: Failed to decode the data field.".to_string(),
+        };
+        build_eip1559_tx(chain_id, U256::from(nonce), pri, fee, gas_limit_u256, to_addr, value_u256, &data_bytes, &self.account_derivation_path)
+    }
+    //Native transfer using the chain's default transaction type (legacy or EIP 1559)
+    pub fn send_auto(&self, to: String, value: &str, fee_rate: i32) -> String {
+        if chain_info(self.chain_id).default_tx_type == 2 {
+            self.send_eip1559(to, value, fee_rate)
+        } else {
+            self.send(to, value, fee_rate)
+        }
+    }
+    //ERC20 transfer using the chain's default transaction type
+    pub fn erc20_transfer_auto(&self, contract_address: String, recipient: String, token_amount: &str, fee_rate: i32) -> String {
+        if chain_info(self.chain_id).default_tx_type != 2 {
+            return self.erc20_transfer(contract_address, recipient, token_amount, fee_rate);
+        }
+        let contract_addr = match Address::from_str(&contract_address) {
+            Ok(a) => a,
+            Err(_) => return "Error: Failed to parse the contract address.".to_string(),
+        };
+        if Address::from_str(&recipient).is_err() {
+            return "Error: Failed to parse the recipient address.".to_string();
+        }
+        let token_amount_u256 = U256::from_dec_str(token_amount).unwrap_or(U256::zero());
+        let data = encode_transfer(&recipient, token_amount_u256);
+        let (max_priority_fee, max_fee) = eip1559_fees(&self.gas_price, &self.max_priority_fee_per_gas, fee_rate);
+        build_eip1559_tx(self.chain_id, U256::from(self.nonce), max_priority_fee, max_fee, U256::from(160_000u64), Some(contract_addr), U256::zero(), &data, &self.account_derivation_path)
+    }
+    //Contract deployment using the chain's default transaction type
+    pub fn deploy_contract_auto(&self, bytecode: String, constructor_args: String, value: &str, gas_limit: &str, fee_rate: i32) -> String {
+        if chain_info(self.chain_id).default_tx_type == 2 {
+            self.deploy_contract_eip1559(bytecode, constructor_args, value, gas_limit, fee_rate)
+        } else {
+            self.deploy_contract(bytecode, constructor_args, value, gas_limit, fee_rate)
+        }
+    }
+    //ERC721 transferFrom using the chain's default transaction type
+    pub fn erc721_transfer_auto(&self, contract_address: String, to: String, token_id: &str, fee_rate: i32) -> String {
+        // erc721_transfer and erc1155_transfer encode the sender without checking it, it is empty before sync
+        if let Err(e) = parse_address(&self.address, "sender") {
+            return e;
+        }
+        if chain_info(self.chain_id).default_tx_type == 2 {
+            self.erc721_transfer_eip1559(contract_address, to, token_id, fee_rate)
+        } else {
+            self.erc721_transfer(contract_address, to, token_id, fee_rate)
+        }
+    }
+    //ERC721 safeTransferFrom using the chain's default transaction type
+    pub fn erc721_safe_transfer_auto(&self, contract_address: String, to: String, token_id: &str, data: String, fee_rate: i32) -> String {
+        if chain_info(self.chain_id).default_tx_type == 2 {
+            self.erc721_safe_transfer_eip1559(contract_address, to, token_id, data, fee_rate)
+        } else {
+            self.erc721_safe_transfer(contract_address, to, token_id, data, fee_rate)
+        }
+    }
+    //ERC1155 safeTransferFrom using the chain's default transaction type
+    pub fn erc1155_transfer_auto(&self, contract_address: String, to: String, token_id: &str, amount: &str, fee_rate: i32) -> String {
+        // erc721_transfer and erc1155_transfer encode the sender without checking it, it is empty before sync
+        if let Err(e) = parse_address(&self.address, "sender") {
+            return e;
+        }
+        if chain_info(self.chain_id).default_tx_type != 2 {
+            return self.erc1155_transfer(contract_address, to, token_id, amount, fee_rate);
+        }
+        let tid = match U256::from_dec_str(token_id) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse token ID.".to_string(),
+        };
+        let amt = match U256::from_dec_str(amount) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse amount.".to_string(),
+        };
+        if let Err(e) = parse_address(&to, "recipient") {
+            return e;
+        }
+        let data = encode_erc1155_transfer(&self.address, &to, tid, amt);
+        self.token_contract_call(&contract_address, &data, 200_000, fee_rate, true)
+    }
+    //ERC1155 safeBatchTransferFrom using the chain's default transaction type
+    pub fn erc1155_batch_transfer_auto(&self, contract_address: String, to: String, token_ids: Vec<String>, amounts: Vec<String>, data: String, fee_rate: i32) -> String {
+        if chain_info(self.chain_id).default_tx_type == 2 {
+            self.erc1155_batch_transfer_eip1559(contract_address, to, token_ids, amounts, data, fee_rate)
+        } else {
+            self.erc1155_batch_transfer(contract_address, to, token_ids, amounts, data, fee_rate)
+        }
+    }
+    //Contract deployment, bytecode is the init code and constructor_args the ABI encoded arguments (hex).
+    //Returns {"tx":"unsignedRlpHex:&base64","contract_address":"0x.."}
+    pub fn deploy_contract(&self, bytecode: String, constructor_args: String, value: &str, gas_limit: &str, fee_rate: i32) -> String {
+        let (init_code, value_u256, gas_limit_u256) = match parse_deployment(&bytecode, &constructor_args, value, gas_limit) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        // The contract address comes from the sender and nonce, both are only known after sync
+        if Address::from_str(&self.address).is_err() {
+            return "Error: Sender address and nonce are unknown, sync the wallet first.".to_string();
+        }
+        let gas_price = fee_rate_gas_price(gas_price_from_string(&self.gas_price), fee_rate);
+        let tx = build_legacy_tx(U256::from(self.nonce), gas_price, gas_limit_u256, None, value_u256, &init_code, self.chain_id, &self.account_derivation_path);
+        if tx.starts_with("Error") { return tx; }
+        json!({
+            "tx": tx,
+            "contract_address": create_address(&self.address, self.nonce),
+        }).to_string()
+    }
+    //EIP 1559 version of deploy_contract
+    pub fn deploy_contract_eip1559(&self, bytecode: String, constructor_args: String, value: &str, gas_limit: &str, fee_rate: i32) -> String {
+        let (init_code, value_u256, gas_limit_u256) = match parse_deployment(&bytecode, &constructor_args, value, gas_limit) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        // The contract address comes from the sender and nonce, both are only known after sync
+        if Address::from_str(&self.address).is_err() {
+            return "Error: Sender address and nonce are unknown, sync the wallet first.".to_string();
+        }
+        let (max_priority_fee, max_fee) = eip1559_fees(&self.gas_price, &self.max_priority_fee_per_gas, fee_rate);
+        let tx = build_eip1559_tx(self.chain_id, U256::from(self.nonce), max_priority_fee, max_fee, gas_limit_u256, None, value_u256, &init_code, &self.account_derivation_path);
+        if tx.starts_with("Error") { return tx; }
+        json!({
+            "tx": tx,
+            "contract_address": create_address(&self.address, self.nonce),
+        }).to_string()
+    }
+    //ABI encode constructor arguments, types like ["address","uint256"] and values as strings
+    pub fn encode_constructor_args(&self, types: Vec<String>, values: Vec<String>) -> String {
+        match encode_abi_args(&types, &values) {
+            Ok(encoded) => format!("0x{}", hex::encode(encoded)),
+            Err(e) => e,
+        }
+    }
+    //Address the next CREATE from this wallet will deploy to
+    pub fn predict_contract_address(&mut self) -> String {
+        let address = self.address();
+        if address.starts_with("Error") { return address; }
+        create_address(&address, self.nonce)
+    }
+    //CREATE2 address for a factory deployment, init_code is the full init code (bytecode + args)
+    pub fn predict_create2_address(&self, factory: String, salt: String, init_code: String) -> String {
+        let code = match hex::decode(init_code.trim_start_matches("0x")) {
+            Ok(c) => c,
+            Err(_) => return "Error: Failed to decode the init code.".to_string(),
+        };
+        create2_address(&factory, &salt, &keccak256(&code))
+    }
+    //Contract call with raw calldata (hex), value and gas limit are decimal strings
+    pub fn contract_call(&self, to: String, value: &str, data: String, gas_limit: &str, fee_rate: i32) -> String {
+        let (to_addr, value_u256, data_bytes, gas_limit_u256) = match parse_contract_call(&to, value, &data, gas_limit) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        let gas_price = fee_rate_gas_price(gas_price_from_string(&self.gas_price), fee_rate);
+        build_legacy_tx(U256::from(self.nonce), gas_price, gas_limit_u256, Some(to_addr), value_u256, &data_bytes, self.chain_id, &self.account_derivation_path)
+    }
+    //EIP 1559 version of contract_call
+    pub fn contract_call_eip1559(&self, to: String, value: &str, data: String, gas_limit: &str, fee_rate: i32) -> String {
+        let (to_addr, value_u256, data_bytes, gas_limit_u256) = match parse_contract_call(&to, value, &data, gas_limit) {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        let (max_priority_fee, max_fee) = eip1559_fees(&self.gas_price, &self.max_priority_fee_per_gas, fee_rate);
+        build_eip1559_tx(self.chain_id, U256::from(self.nonce), max_priority_fee, max_fee, gas_limit_u256, Some(to_addr), value_u256, &data_bytes, &self.account_derivation_path)
+    }
+    //Decodes an unsigned transaction ("unsignedRlpHex" or the full "unsignedRlpHex:&base64" payload) into JSON
+    //Calls to a registry token also get "token" and, for ERC20 amounts, a "formatted_amount"
+    pub fn decode_unsigned_tx(&self, payload: String) -> String {
+        match decode_unsigned_tx(&payload) {
+            Ok(mut decoded) => {
+                let registry = merge_token_registry(&self.known_tokens, &self.listed_tokens);
+                let to = decoded["to"].as_str().unwrap_or_default().to_string();
+                if let Some(token) = registry.iter().find(|t| t.address.eq_ignore_ascii_case(&to)) {
+                    let function = decoded["call"]["function"].as_str().unwrap_or_default();
+                    if ["transfer(", "approve(", "transferFrom("].iter().any(|f| function.starts_with(f)) {
+                        let amount = decoded["call"]["args"].as_array()
+                            .and_then(|args| args.last())
+                            .and_then(|a| a.as_str())
+                            .and_then(|a| U256::from_dec_str(a).ok());
+                        if let Some(amount) = amount {
+                            decoded["call"]["formatted_amount"] = json!(format_units(amount, token.decimals));
+                        }
+                    }
+                    decoded["token"] = json!(token);
+                }
+                decoded.to_string()
+            }
+            Err(e) => e,
+        }
+    }
+    //Exports get_tx_history entries (a JSON array, or a page with "entries") for accounting,
+    //format is "csv", "koinly" or "cointracking"
+    pub fn export_tx_history(&self, history_json: String, format: String) -> String {
+        let value: Value = match serde_json::from_str(&history_json) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse the transaction history.".to_string(),
+        };
+        let entries = value.get("entries").cloned().unwrap_or(value);
+        let entries: Vec<EtherscanTx> = match serde_json::from_value(entries) {
+            Ok(e) => e,
+            Err(_) => return "Error: Failed to parse the transaction history.".to_string(),
+        };
+        let format = match ExportFormat::parse(&format) {
+            Some(f) => f,
+            None => return "Error: Unknown export format.".to_string(),
+        };
+        export_history(&entries, format, &chain_info(self.chain_id))
+    }
+    //Spam/scam scores for tokens, takes a JSON array of objects with "address", "symbol" and optionally "name"
+    //(validate_contracts, token_registry or discover_token_balances output) and returns an array of TokenRisk
+    pub fn assess_tokens(&self, tokens_json: String) -> String {
+        let tokens: Vec<Value> = match serde_json::from_str(&tokens_json) {
+            Ok(t) => t,
+            Err(_) => return "Error: Failed to parse the tokens.".to_string(),
+        };
+        let registry = merge_token_registry(&self.known_tokens, &self.listed_tokens);
+        let risks: Vec<TokenRisk> = tokens.iter().map(|t| assess_token(
+            t["address"].as_str().unwrap_or_default(),
+            t["symbol"].as_str().unwrap_or_default(),
+            t["name"].as_str().unwrap_or_default(),
+            self.chain_id,
+            &registry,
+        )).collect();
+        serde_json::to_string(&risks).unwrap_or_else(|_| "Error: Failed to serialize the risk scores.".to_string())
+    }
+    //Spam/scam scores for get_tx_history entries (a JSON array, or a page with "entries"), one TransferRisk per entry
+    pub fn assess_tx_history(&self, history_json: String) -> String {
+        let value: Value = match serde_json::from_str(&history_json) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse the transaction history.".to_string(),
+        };
+        let entries = value.get("entries").cloned().unwrap_or(value);
+        let entries: Vec<EtherscanTx> = match serde_json::from_value(entries) {
+            Ok(e) => e,
+            Err(_) => return "Error: Failed to parse the transaction history.".to_string(),
+        };
+        let registry = merge_token_registry(&self.known_tokens, &self.listed_tokens);
+        serde_json::to_string(&assess_transfers(&entries, self.chain_id, &registry))
+            .unwrap_or_else(|_| "Error: Failed to serialize the risk scores.".to_string())
+    }
+}
+
+//Methods that talk to the RPC node, these need the "rpc" feature
+#[cfg(feature = "rpc")]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl Wallet {
+    pub async fn sync(&mut self) -> String {
+        let url = self.infura_url.clone();
+        let client = reqwest::Client::new();
+        let addr = self.address(); 
+
+        // Batch JSON-RPC request
+        let request_body = json!([
+            {
+                "jsonrpc": "2.0",
+                "method": "eth_getBalance",
+                "params": [addr.clone(), "latest"],
+                "id": 1,
+            },
+            {
+                "jsonrpc": "2.0",
+                "method": "eth_getTransactionCount",
+                "params": [addr.clone(), "latest"],
+                "id": 2,
+            },
+            {
+                "jsonrpc": "2.0",
+                "method": "eth_gasPrice",
+                "params": [],
+                "id": 3,
+            },
+            {
+                "jsonrpc": "2.0",
+                "method": "eth_maxPriorityFeePerGas",
+                "params": [],
+                "id": 4,
+            }
+        ]);
+
+        let response = match client.post(&url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&request_body)
+            .send()
+            .await {
+                Ok(resp) => resp,
+                Err(_) => return "Error: Infura error.".to_string(),
+            };
+
+        if response.status().is_success() {
+            let body = response.text().await.unwrap();
+            let parsed: Value = match serde_json::from_str(&body) {
+                Ok(val) => val,
+                Err(_) => return "Error: JSON parse error.".to_string(),
+            };
+
+            let responses = match parsed.as_array() {
+                Some(arr) => arr,
+                None => return "Error: Unexpected JSON format.".to_string(),
+            };
+
+            for resp in responses {
+                let id = resp["id"].as_i64().unwrap_or_default();
+                let result = match resp["result"].as_str() {
+                    Some(r) => r,
+                    None => continue,
+                };
+                match id {
+                    1 => { // eth_getBalance
+                        let balance = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
+                            Ok(val) => val,
+                            Err(_) => return "Error: Balance parse error.".to_string(),
+                        };
+                        self.balance = gas_price_to_string(balance);
+                        self.eth_balance = wei_to_eth(balance);
+                    },
+                    2 => { // eth_getTransactionCount (nonce)
+                        let nonce = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
+                            Ok(val) => val,
+                            Err(_) => return "Error: Nonce parse error.".to_string(),
+                        };
+                        self.nonce = nonce.low_u64();
+                    },
+                    3 => { // eth_gasPrice
+                        let gas_price = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
+                            Ok(val) => val,
+                            Err(_) => return "Error: Gas price parse error.".to_string(),
+                        };
+                        self.gas_price = gas_price_to_string(gas_price);
+                    },
+                    4 => {
+                        // eth_maxPriorityFeePerGas
+                        let mpf = match U256::from_str_radix(result.trim_start_matches("0x"), 16) {
+                            Ok(v) => v,
+                            Err(_) => return "Error: Priority fee parse error.".to_string(),
+                        };
+                        self.max_priority_fee_per_gas = gas_price_to_string(mpf);
+                    }
+                    _ => {}
+                }
+            }
+            // Anything below the confirmed nonce has been mined (or replaced)
+            let confirmed_nonce = self.nonce;
+            self.pending_txs.retain(|tx| tx.nonce >= confirmed_nonce);
+            return "Sync successful.".to_string();
+        } else {
+            return "Error: Infura error.".to_string();
+        }
+    }
+    //Checks a personal_sign signature against an address, contracts are checked with EIP-1271
+    pub async fn verify_personal_sign(&self, message: String, signature: String, address: String) -> String {
+        let digest = keccak256(eip191_bytes(&message_bytes(&message)));
+        self.verify_digest(digest, signature, address).await
+    }
+    //Checks an eth_signTypedData signature (v1 array or v4 object) against an address
+    pub async fn verify_typed_data(&self, typed_data_json: String, signature: String, address: String) -> String {
+        let digest = if typed_data_json.trim_start().starts_with('[') {
+            let fields: Vec<Value> = match serde_json::from_str(&typed_data_json) {
+                Ok(f) => f,
+                Err(_) => return "Error: Failed to parse typed data JSON.".to_string(),
+            };
+            match typed_data_v1_digest(&fields) {
+                Ok(d) => d,
+                Err(e) => return e,
+            }
+        } else {
+            let typed: TypedData = match serde_json::from_str(&typed_data_json) {
+                Ok(td) => td,
+                Err(_) => return "Error: Failed to parse typed data JSON.".to_string(),
+            };
+            match typed_data_digest(&typed, TypedDataVersion::V4) {
+                Ok(d) => d,
+                Err(e) => return e,
+            }
+        };
+        self.verify_digest(digest, signature, address).await
+    }
+    //Checks an eth_signTypedData_v3 signature, v3 hashes missing or null members differently from v4
+    pub async fn verify_typed_data_v3(&self, typed_data_json: String, signature: String, address: String) -> String {
+        let typed: TypedData = match serde_json::from_str(&typed_data_json) {
+            Ok(td) => td,
+            Err(_) => return "Error: Failed to parse typed data JSON.".to_string(),
+        };
+        let digest = match typed_data_digest(&typed, TypedDataVersion::V3) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        self.verify_digest(digest, signature, address).await
+    }
+    // Returns {"valid":bool,"method":"ecrecover"|"eip1271","signer":..}
+    async fn verify_digest(&self, digest: [u8; 32], signature: String, address: String) -> String {
+        let expected = match Address::from_str(&address) {
+            Ok(a) => format!("{:?}", a),
+            Err(_) => return "Error: Failed to parse the address.".to_string(),
+        };
+
+        // 1) Plain ecrecover for EOAs
+        let signer = recover_signer(&digest, &signature).ok();
+        if signer.as_deref() == Some(expected.as_str()) {
+            return json!({ "valid": true, "method": "ecrecover", "signer": signer }).to_string();
+        }
+
+        // 2) Contract wallets: isValidSignature(bytes32,bytes) must return the 0x1626ba7e magic value
+        let code = match rpc_call(&self.infura_url, "eth_getCode", json!([expected, "latest"])).await {
+            Ok(c) => c.as_str().unwrap_or("0x").to_string(),
+            Err(e) => return e,
+        };
+        if code.trim_start_matches("0x").is_empty() {
+            return json!({ "valid": false, "method": "ecrecover", "signer": signer }).to_string();
+        }
+        let sig_bytes = match decode_hex_or_b64(&signature) {
+            Some(b) => b,
+            None => return "Error: Failed to decode the signature.".to_string(),
+        };
+        let call_data = format!("0x{}", hex::encode(encode_is_valid_signature(&digest, &sig_bytes)));
+        let result = match rpc_call(&self.infura_url, "eth_call", json!([{ "to": expected, "data": call_data }, "latest"])).await {
+            Ok(r) => r.as_str().unwrap_or("").to_string(),
+            // A revert means the contract rejected the signature
+            Err(_) => String::new(),
+        };
+        let valid = result.trim_start_matches("0x").starts_with("1626ba7e");
+        json!({ "valid": valid, "method": "eip1271", "signer": signer }).to_string()
+    }
+    /// Reconstruct & broadcast a signed EIP-1559 tx from `<hex-rlp>` + base64 signature.
+    pub async fn broadcast_eip1559(&mut self, unsigned_tx: String, tx_signature: String) -> String {
+        // 1) decode the RLP payload
+        let hex_str = unsigned_tx.trim_start_matches("0x");
+        let mut raw = match hex::decode(hex_str) {
+            Ok(b) => b,
+            Err(_) => return "Error: Failed to decode the unsigned transaction.".to_string(),
+        };
+        // if it starts with the type‐2 marker, strip it off:
+        if raw.first() == Some(&0x02) {
+            raw = raw[1..].to_vec();
+        }
+        let rlp = Rlp::new(&raw);
+
+        // 2) extract each field with explicit matches
+        let chain_id = match rlp.at(0).and_then(|f| f.as_val::<U256>()) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the chain ID.".to_string(),
+        };
+        let nonce = match rlp.at(1).and_then(|f| f.as_val::<U256>()) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the nonce.".to_string(),
+        };
+        let max_prio = match rlp.at(2).and_then(|f| f.as_val::<U256>()) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the max priority fee.".to_string(),
+        };
+        let max_fee = match rlp.at(3).and_then(|f| f.as_val::<U256>()) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the max fee.".to_string(),
+        };
+        let gas_limit = match rlp.at(4).and_then(|f| f.as_val::<U256>()) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the gas limit.".to_string(),
+        };
+        let to_addr = match rlp.at(5).and_then(|f| f.data().map(|d| d.to_vec())) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the to address.".to_string(),
+        };
+        let value = match rlp.at(6).and_then(|f| f.as_val::<U256>()) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the value.".to_string(),
+        };
+        let data_field = match rlp.at(7).and_then(|f| f.data().map(|d| d.to_vec())) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to decode the data field.".to_string(),
+        };
+        // 8th element is the accessList — we know it was empty, so skip explicit decode
+
+        // 3) decode the signature blob
+        let sig = match base64::decode(&tx_signature) {
+            Ok(b) => b,
+            Err(_) => return "Error: Failed to decode the transaction signature.".to_string(),
+        };
+        if sig.len() < 65 {
+            return "Error: Signature length is invalid.".to_string();
+        }
+        let r_sig = &sig[0..32];
+        let s_sig = &sig[32..64];
+        let v_raw = sig[64];
+        let rec_id = if v_raw > 1 { v_raw - 27 } else { v_raw };
+
+        // 4) RLP-encode the signed tx:
+        let mut stre = RlpStream::new_list(12);
+        stre.append(&chain_id);
         stre.append(&nonce);
         stre.append(&max_prio);
         stre.append(&max_fee);
@@ -574,1129 +1711,5325 @@ impl Wallet {
         stre.append(&s_sig);
         let signed_rlp = stre.out().to_vec();
 
-        // 5) prepend type byte and hex
-        let mut raw = Vec::with_capacity(signed_rlp.len() + 1);
-        raw.push(0x02);
-        raw.extend(&signed_rlp);
-        let raw_hex = format!("0x{}", hex::encode(raw));
+        // 5) prepend type byte and hex
+        let mut raw = Vec::with_capacity(signed_rlp.len() + 1);
+        raw.push(0x02);
+        raw.extend(&signed_rlp);
+        let raw_hex = format!("0x{}", hex::encode(raw));
+
+        //let raw_hex = format!("0x{}", hex::encode(raw_tx));
+        // 👉 DEBUG: print (or even return) the fully signed RLP so you can inspect it
+        //println!("DEBUG signed_raw_tx: {}", raw_hex);
+        //return raw_hex;   // <— you can early‐return here for testing
+        // 6) broadcast
+        let client = Client::new();
+        let body = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_sendRawTransaction",
+            "params": [ raw_hex ],
+            "id": 1
+        });
+        if let Ok(resp) = client.post(&self.infura_url).json(&body).send().await {
+            if let Ok(j) = resp.json::<serde_json::Value>().await {
+                if let Some(r) = j.get("result").and_then(|r| r.as_str()) {
+                    self.record_sent(PendingTx { hash: r.to_string(), nonce: nonce.low_u64(), raw_tx: raw_hex.clone() });
+                    self.nonce = self.nonce + 1;
+                    return r.to_string();
+                }
+                if let Some(e) = j.get("error") {
+                    return format!("Error: {:?}", e);
+                }
+            }
+        }
+        return "Error: Failed to broadcast transaction.".to_string()
+    }
+    pub async fn validate_contract(&mut self, contract_address: String) -> String {
+        // decimals(), symbol() and name() in one Multicall3 call (or a JSON-RPC batch without it)
+        let calls = vec![
+            (contract_address.clone(), hex::decode("313ce567").unwrap()), // decimals()
+            (contract_address.clone(), hex::decode("95d89b41").unwrap()), // symbol()
+            (contract_address.clone(), hex::decode("06fdde03").unwrap()), // name()
+        ];
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(_) => return "Error: Infura error during batch request.".to_string(),
+        };
+        // A reverted or empty call means the contract doesn't implement it, same as validate_contracts
+        let (decimals_hex, symbol_hex, name_hex) = match (results.first(), results.get(1), results.get(2)) {
+            (Some(Ok(d)), Some(Ok(s)), Some(Ok(n))) if d.len() >= 32 && !s.is_empty() && !n.is_empty() => {
+                (format!("0x{}", hex::encode(d)), format!("0x{}", hex::encode(s)), format!("0x{}", hex::encode(n)))
+            }
+            _ => return "Error: Not an ERC20 contract.".to_string(),
+        };
+
+        // Decode decimals using hex_to_vec directly.
+        let decimals = match hex_to_vec(decimals_hex.trim_start_matches("0x")) {
+            Some(mut bytes) => {
+                // Remove any leading zeros.
+                while !bytes.is_empty() && bytes[0] == 0 {
+                    bytes.remove(0);
+                }
+                let value = if bytes.is_empty() {
+                    0u64
+                } else {
+                    bytes.into_iter().fold(0u64, |acc, b| acc * 256 + b as u64)
+                };
+                if value <= u8::MAX as u64 {
+                    value as u8
+                } else {
+                    return "Error: Decimals value out of range.".to_string();
+                }
+            },
+            None => return "Error: Failed to decode decimals.".to_string(),
+        };
+
+        let symbol = match decode_abi_string(&symbol_hex) {
+            Some(s) => s,
+            None => return "Error: Failed to decode symbol.".to_string(),
+        };
+
+        let name = match decode_abi_string(&name_hex) {
+            Some(n) => n,
+            None => return "Error: Failed to decode name.".to_string(),
+        };
+
+        // A contract that answers with an empty symbol and name didn't really validate
+        if symbol.is_empty() && name.is_empty() {
+            return "Error: Not an ERC20 contract.".to_string();
+        }
+
+        // Assemble the contract data into a JSON object and return it as a string.
+        // Cache the metadata so it survives to_json / from_json, the token registry trusts these entries
+        self.known_tokens.retain(|t| !t.address.eq_ignore_ascii_case(&contract_address));
+        self.known_tokens.push(KnownToken {
+            address: contract_address.clone(),
+            decimals,
+            symbol: symbol.clone(),
+            name: name.clone(),
+        });
+
+        let contract_data = json!({
+             "address": contract_address,
+             "decimals": decimals,
+             "symbol": symbol,
+             "name": name,
+        });
+        return contract_data.to_string();
+    }
+    //This function now always accepts and returns a list of balances for a list of contracts
+    pub async fn erc20_balance(&self, contract_addresses: Vec<String>) -> Vec<String> {
+        // Clean and pad the wallet address
+        let wallet_addr_clean = self.address.trim_start_matches("0x");
+        let padded_wallet_addr = format!("{:0>64}", wallet_addr_clean);
+
+        // Build the call data using the ERC20 balanceOf selector (0x70a08231)
+        let call_data = match hex::decode(format!("70a08231{}", padded_wallet_addr)) {
+            Ok(d) => d,
+            Err(_) => return vec!["Error: Address error.".to_string()],
+        };
+        let calls: Vec<(String, Vec<u8>)> = contract_addresses.iter().map(|c| (c.clone(), call_data.clone())).collect();
+
+        // One Multicall3 aggregate3 call, or a JSON-RPC batch on chains without Multicall3
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return vec![e],
+        };
+
+        // Prepend "Success" as the first element, failed calls get their own "Error: .." entry
+        let mut result_vec = vec!["Success".to_string()];
+        result_vec.extend(results.into_iter().map(|r| match r {
+            Ok(bytes) if bytes.len() >= 32 => U256::from_big_endian(&bytes[..32]).to_string(),
+            Ok(_) => "Error: Balance parse error.".to_string(),
+            Err(e) => e,
+        }));
+        result_vec
+    }
+    pub async fn broadcast(&mut self, unsigned_tx: String, tx_signature : String) -> String {
+        let unsigned_tx_hex = unsigned_tx.trim_start_matches("0x");
+        let unsigned_tx_bytes = match hex::decode(unsigned_tx_hex){
+            Ok(bytes) => bytes,
+            Err(_) => return "Error: Failed to decode the unsigned transaction.".to_string(),
+        };
+
+        // Decode the unsigned transaction RLP.
+        // This unsigned tx is expected to have 9 fields:
+        // [nonce, gasPrice, gasLimit, to, value, data, v, r, s]
+        // In the unsigned tx, the v, r, s fields are placeholders (usually 0x80).
+        let rlp_unsigned = Rlp::new(&unsigned_tx_bytes);
+        let base_bytes = match base64::decode(&tx_signature){
+            Ok(bytes) => bytes,
+            Err(_) => return "Error: Failed to decode the transaction signature.".to_string()
+        };
+
+        let nonce = match rlp_unsigned.at(0) {
+            Ok(field) => match field.as_val::<U256>() {
+                Ok(val) => val,
+                Err(_) => return "Error: Failed to decode the nonce.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the nonce.".to_string(),
+        };
+
+        let gas_price = match rlp_unsigned.at(1) {
+            Ok(field) => match field.as_val::<U256>() {
+                Ok(val) => val,
+                Err(_) =>return "Error: Failed to decode the gas price.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the gas price.".to_string(),
+        };
+
+        let gas_limit = match rlp_unsigned.at(2) {
+            Ok(field) => match field.as_val::<U256>() {
+                Ok(val) => val,
+                Err(_) => return "Error: Failed to decode the gas limit.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the gas limit.".to_string(),
+        };
+
+        let to = match rlp_unsigned.at(3) {
+            Ok(field) => match field.data() {
+                Ok(data) => data.to_vec(),
+                Err(_) => return "Error: Failed to decode the output.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the output.".to_string(),
+        };
+
+        let value = match rlp_unsigned.at(4) {
+            Ok(field) => match field.as_val::<U256>() {
+                Ok(val) => val,
+                Err(_) => return "Error: Failed to decode the value.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the value.".to_string(),
+        };
+
+        let data_field = match rlp_unsigned.at(5) {
+            Ok(field) => match field.data() {
+                Ok(data) => data.to_vec(),
+                Err(_) => return "Error: Failed to decode the data field.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the data field.".to_string(),
+        };
+
+        let chain_id = match rlp_unsigned.at(6) {
+            Ok(field) => match field.as_val::<U256>() {
+                Ok(val) => val,
+                Err(_) => return "Error: Failed to decode the chain ID.".to_string(),
+            },
+            Err(_) => return "Error: Failed to decode the chain ID.".to_string(),
+        };
+
+        let r_sig = &base_bytes[0..32];
+        let s_sig = &base_bytes[32..64];
+        let v_sig = base_bytes[64];
+        
+        let recovery_id = if v_sig > 1 { v_sig - 27 } else { v_sig };
+        let v_eip155 = chain_id.low_u64() * 2 + 35 + recovery_id as u64;
+        let mut stream = RlpStream::new_list(9);
+        stream.append(&nonce);
+        stream.append(&gas_price);
+        stream.append(&gas_limit);
+        stream.append(&to);
+        stream.append(&value);
+        stream.append(&data_field);
+        stream.append(&v_eip155);
+        stream.append(&r_sig);
+        stream.append(&s_sig);
+
+        let signed_tx_bytes = stream.out().to_vec();
+        let signed_tx_hex = format!("0x{}", hex::encode(&signed_tx_bytes));
+        
+        let client = Client::new();
+        let req_body = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_sendRawTransaction",
+            "params": [signed_tx_hex],
+            "id": 1
+        });
+        let resp = client.post(&self.infura_url).json(&req_body).send().await;
+        if let Ok(response) = resp {
+            if let Ok(resp_json) = response.json::<serde_json::Value>().await {
+                if let Some(result) = resp_json.get("result").and_then(|r| r.as_str()) {
+                    self.record_sent(PendingTx { hash: result.to_string(), nonce: nonce.low_u64(), raw_tx: signed_tx_hex.clone() });
+                    self.nonce = self.nonce + 1;
+                    return result.to_string();
+                } else if let Some(error) = resp_json.get("error") {
+                    return format!("Error: {:?}", error);
+                }
+            }
+        }
+        return "Error: Failed to broadcast transaction.".to_string();
+    }
+    //Scans m/branch/0.. for used accounts (nonce or balance above zero) on branches 0..max_branches.
+    //A branch stops after gap_limit (20 when 0, at most 1000) unused addresses in a row and the scan stops at the first branch with no used accounts.
+    //Returns a JSON array of {derivation_path, address, nonce, balance}
+    pub async fn discover_accounts(&self, gap_limit: u32, max_branches: u32) -> String {
+        let url = self.infura_url.clone();
+        let activity = |addresses: Vec<String>| {
+            let url = url.clone();
+            async move {
+                let mut requests = Vec::new();
+                for address in &addresses {
+                    requests.push(("eth_getTransactionCount", json!([address, "latest"])));
+                    requests.push(("eth_getBalance", json!([address, "latest"])));
+                }
+                let results = batch_rpc(&url, &requests).await?;
+                let parse = |r: &Result<Value, String>| r.as_ref().ok()
+                    .and_then(|v| v.as_str())
+                    .and_then(|h| U256::from_str_radix(h.trim_start_matches("0x"), 16).ok());
+                results.chunks(2)
+                    .map(|pair| match (parse(&pair[0]), pair.get(1).and_then(parse)) {
+                        (Some(n), Some(b)) => Ok((n, b)),
+                        _ => Err("Error: Failed to query account activity.".to_string()),
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+            }
+        };
+        match scan_accounts(&self.xpub, gap_limit, max_branches, activity).await {
+            Ok(used) => serde_json::to_string(&used).unwrap_or_else(|_| "Error: Failed to serialize accounts.".to_string()),
+            Err(e) => e,
+        }
+    }
+    //First page of get_tx_history_page, as a JSON array of EtherscanTx
+    pub async fn get_tx_history(&mut self, base_url: &str, api_key: &str, limit: u32) -> String {
+        let page = self.get_tx_history_page(base_url, api_key, String::new(), limit).await;
+        match serde_json::from_str::<Value>(&page) {
+            Ok(p) => p["entries"].to_string(),
+            Err(_) => page,
+        }
+    }
+    //Native, internal, ERC20, ERC721 and ERC1155 history from the explorer API merged newest first.
+    //`cursor` is the next_cursor of the previous page, empty for the newest transactions.
+    //Returns {"entries":[EtherscanTx],"next_cursor":"12345"|null}
+    pub async fn get_tx_history_page(&mut self, base_url: &str, api_key: &str, cursor: String, limit: u32) -> String {
+        let address = self.address();
+        if address.starts_with("Error") { return address; }
+
+        // An empty base_url uses the explorer API from the chain registry
+        let chain = chain_info(self.chain_id);
+        let base_url = if base_url.is_empty() { chain.explorer_api_url.as_str() } else { base_url };
+        if base_url.is_empty() { return "Error: No explorer API for this chain.".to_string(); }
+        let end_block = if cursor.trim().is_empty() {
+            None
+        } else {
+            match cursor.trim().parse::<u64>() {
+                Ok(n) => Some(n),
+                Err(_) => return "Error: Invalid cursor.".to_string(),
+            }
+        };
+        let limit = if limit == 0 { 10 } else { limit as usize };
+
+        let pages = match fetch_history_pages(base_url, api_key, &chain, &address, 0, end_block, limit).await {
+            Ok(p) => p,
+            Err(e) => return e,
+        };
+        let (mut entries, next_cursor) = match merge_history_pages(pages, limit) {
+            Ok(page) => page,
+            Err(block) => {
+                // A full page that is all one block may have left rows of that block out, so fetch it on its own
+                let pages = match fetch_history_pages(base_url, api_key, &chain, &address, block, Some(block), EXPLORER_MAX_OFFSET).await {
+                    Ok(p) => p,
+                    Err(e) => return e,
+                };
+                if pages.iter().any(|(_, full)| *full) {
+                    return format!("Error: Block {} has more than {} transactions for this address.", block, EXPLORER_MAX_OFFSET);
+                }
+                let mut entries: Vec<EtherscanTx> = pages.into_iter().flat_map(|(entries, _)| entries).collect();
+                entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+                (entries, block.checked_sub(1))
+            }
+        };
+        label_history(&mut entries, &self.address_book, self.chain_id);
+
+        json!({
+            "entries": entries,
+            "next_cursor": next_cursor.map(|b| b.to_string()),
+        }).to_string()
+    }
+
+    //Transfer history from ERC20/721/1155 logs on the RPC node, no explorer needed. Scans down from `cursor`
+    //(a block number, empty for latest) to `from_block` and stops once `limit` entries are found.
+    //Transactions broadcast from this wallet are merged into the page covering their block, unmined ones into the first.
+    //Returns {"entries":[HistoryEntry],"next_cursor":"12345"|null}
+    pub async fn get_log_history(&mut self, cursor: String, limit: u32, from_block: u64) -> String {
+        let address = self.address();
+        if address.starts_with("Error") { return address; }
+        let limit = if limit == 0 { 50 } else { limit as usize };
+
+        let start_block = if cursor.trim().is_empty() {
+            match rpc_call(&self.infura_url, "eth_blockNumber", json!([])).await {
+                Ok(v) => match v.as_str().and_then(|h| u64::from_str_radix(h.trim_start_matches("0x"), 16).ok()) {
+                    Some(n) => n,
+                    None => return "Error: Failed to parse the block number.".to_string(),
+                },
+                Err(e) => return e,
+            }
+        } else {
+            match cursor.trim().parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => return "Error: Invalid cursor.".to_string(),
+            }
+        };
+
+        let (mut entries, next_cursor) = match scan_transfer_logs(&self.infura_url, &address, &[], start_block, from_block, limit).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        // Sent transactions go on the page whose block range holds them, unmined ones on the first page
+        let oldest = next_cursor.map(|b| b + 1).unwrap_or(from_block);
+        match local_history_entries(&self.infura_url, &address, &self.sent_txs).await {
+            Ok(local) => entries.extend(local.into_iter().filter(|e| match e.block_number {
+                Some(block) => block >= oldest && block <= start_block,
+                None => cursor.trim().is_empty(),
+            })),
+            Err(e) => return e,
+        }
+        sort_history(&mut entries);
+        add_block_timestamps(&self.infura_url, &mut entries).await;
+
+        json!({
+            "entries": entries,
+            "next_cursor": next_cursor.map(|b| b.to_string()),
+        }).to_string()
+    }
+
+    // Batch-query ERC-721 `balanceOf(owner)` for each contract.
+    pub async fn erc721_balance(&self,contract_addresses: Vec<String>,) -> Vec<String> {
+        let owner_clean = self.address.trim_start_matches("0x");
+        let padded_owner = format!("{:0>64}", owner_clean);
+        let call_data = match hex::decode(format!("70a08231{}", padded_owner)) {
+            Ok(d) => d,
+            Err(_) => return vec!["Error: Address error.".to_string()],
+        };
+        let calls: Vec<(String, Vec<u8>)> = contract_addresses.iter().map(|c| (c.clone(), call_data.clone())).collect();
+
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return vec![e],
+        };
+
+        let mut out = vec!["Success".to_string()];
+        for r in results {
+            match r {
+                Ok(bytes) if bytes.len() >= 32 => out.push(U256::from_big_endian(&bytes[..32]).to_string()),
+                Ok(_) => out.push("Error: Unexpected result length.".to_string()),
+                Err(e) => out.push(e),
+            }
+        }
+        out
+    }
+
+    // Batch-query ERC-721 `ownerOf(tokenId)` for (contract, tokenId) pairs.
+    pub async fn erc721_owners_of(&self, contract_addresses: Vec<String>, token_ids: Vec<String>) -> Vec<String> {
+        if contract_addresses.len() != token_ids.len() {
+            return vec!["Error: Number of contracts and token IDs do not match.".to_string()];
+        }
+        let mut calls = Vec::new();
+        for (contract, token_id) in contract_addresses.iter().zip(token_ids.iter()) {
+            let id = match U256::from_dec_str(token_id) {
+                Ok(v) => v,
+                Err(_) => return vec!["Error: Failed to parse token ID.".to_string()],
+            };
+            let mut d = hex::decode("6352211e").unwrap();
+            let mut id_bytes = [0u8; 32];
+            id.to_big_endian(&mut id_bytes);
+            d.extend(&id_bytes);
+            calls.push((contract.clone(), d));
+        }
+
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return vec![e],
+        };
+
+        let mut out = vec!["Success".to_string()];
+        for r in results {
+            match r {
+                Ok(bytes) if bytes.len() >= 32 => out.push(format!("0x{}", hex::encode(&bytes[12..32]))),
+                Ok(_) => out.push("Error: Unexpected result length.".to_string()),
+                Err(e) => out.push(e),
+            }
+        }
+        out
+    }
+
+    // Query ERC-721 `ownerOf(tokenId)`.
+    pub async fn erc721_owner_of(&self,contract_address: String,token_id: String,) -> String {
+        let id = match U256::from_dec_str(&token_id) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse token ID.".to_string(),
+        };
+        let mut id_bytes = [0u8; 32];
+        id.to_big_endian(&mut id_bytes);
+
+        // build call_data = 0x6352211e + padded tokenId
+        let call_data = {
+            let mut d = Vec::new();
+            d.extend(hex::decode("6352211e").unwrap());
+            d.extend(&id_bytes);
+            format!("0x{}", hex::encode(d))
+        };
+
+        let req = json!({
+            "jsonrpc":"2.0",
+            "method":"eth_call",
+            "params":[{ "to": contract_address, "data": call_data }, "latest"],
+            "id": 1
+        });
+        let client = reqwest::Client::new();
+        let resp = match client
+            .post(&self.infura_url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&req)
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(_) => return "Error: Infura error.".to_string(),
+        };
+        if !resp.status().is_success() {
+            return "Error: Infura error.".to_string();
+        }
+        let j: Value = match resp.json().await {
+            Ok(v) => v,
+            Err(_) => return "Error: JSON parse error.".to_string(),
+        };
+        let result = j.get("result").and_then(|v| v.as_str()).unwrap_or("");
+        let bytes = hex::decode(result.trim_start_matches("0x")).unwrap_or_default();
+        if bytes.len() < 20 {
+            return "Error: Unexpected result length.".to_string();
+        }
+        let addr = &bytes[bytes.len() - 20..];
+        format!("0x{}", hex::encode(addr))
+    }
+
+    // ERC721 getApproved(tokenId), the zero address when nobody is approved
+    pub async fn erc721_get_approved(&self, contract_address: String, token_id: String) -> String {
+        let data = match parse_token_id(&token_id) {
+            Ok(id) => ethabi::encode(&[abi_uint(id)]),
+            Err(e) => return e,
+        };
+        match self.eth_call_word(&contract_address, "081812fc", &data).await {
+            Ok(word) => format!("0x{}", hex::encode(&word[12..])),
+            Err(e) => e,
+        }
+    }
+    // isApprovedForAll(owner, operator) for ERC721 and ERC1155, returns "true" or "false"
+    pub async fn is_approved_for_all(&self, contract_address: String, owner: String, operator: String) -> String {
+        let data = match (parse_address(&owner, "owner"), parse_address(&operator, "operator")) {
+            (Ok(o), Ok(op)) => ethabi::encode(&[abi_address(&o), abi_address(&op)]),
+            (Err(e), _) | (_, Err(e)) => return e,
+        };
+        match self.eth_call_word(&contract_address, "e985e9c5", &data).await {
+            Ok(word) => (word[31] == 1).to_string(),
+            Err(e) => e,
+        }
+    }
+    // NFTs held in the given ERC721/ERC1155 contracts. ERC721Enumerable contracts (found with ERC-165) are listed with
+    // tokenOfOwnerByIndex, the rest are rebuilt from Transfer/TransferSingle/TransferBatch logs since from_block and
+    // checked against ownerOf/balanceOf. Returns {"holdings":[NftHolding],"errors":[...]}
+    pub async fn nft_inventory(&mut self, contract_addresses: Vec<String>, from_block: u64) -> String {
+        let owner = self.address();
+        let owner_address = match parse_address(&owner, "wallet") {
+            Ok(a) => a,
+            Err(e) => return e,
+        };
+        let mut contracts = Vec::new();
+        for c in &contract_addresses {
+            match parse_address(c, "contract") {
+                Ok(_) => contracts.push(c.trim().to_lowercase()),
+                Err(e) => return e,
+            }
+        }
+        let mut holdings: Vec<NftHolding> = Vec::new();
+        let mut errors: Vec<String> = Vec::new();
+
+        // ERC-165 supportsInterface for ERC721, ERC721Enumerable and ERC1155
+        let interfaces = [ERC721_INTERFACE_ID, ERC721_ENUMERABLE_INTERFACE_ID, ERC1155_INTERFACE_ID];
+        let calls: Vec<(String, Vec<u8>)> = contracts.iter()
+            .flat_map(|c| interfaces.iter().map(move |id| (c.clone(), encode_supports_interface(id))))
+            .collect();
+        let supports = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r.into_iter().map(|r| matches!(r, Ok(b) if b.len() >= 32 && b[31] == 1)).collect::<Vec<_>>(),
+            Err(e) => return e,
+        };
+        let (mut enumerable, mut by_logs) = (Vec::new(), Vec::new());
+        for (contract, flags) in contracts.iter().zip(supports.chunks(3)) {
+            if flags[0] && flags[1] { enumerable.push(contract.clone()); } else { by_logs.push(contract.clone()); }
+        }
+
+        // Enumerable: balanceOf, then tokenOfOwnerByIndex for every index
+        let balance_calls: Vec<(String, Vec<u8>)> = enumerable.iter()
+            .map(|c| (c.clone(), [&hex::decode("70a08231").expect("Invalid selector")[..], &ethabi::encode(&[abi_address(&owner_address)])].concat()))
+            .collect();
+        let balances = match multicall(&self.infura_url, &self.multicall_address(), &balance_calls).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        let mut index_calls: Vec<(String, Vec<u8>)> = Vec::new();
+        for (contract, balance) in enumerable.iter().zip(balances) {
+            let count = match balance {
+                Ok(b) if b.len() >= 32 => U256::from_big_endian(&b[..32]),
+                _ => {
+                    errors.push(format!("{}: balanceOf failed", contract));
+                    continue;
+                }
+            };
+            if count > U256::from(NFT_INVENTORY_MAX_ENUMERATED) {
+                errors.push(format!("{}: only the first {} of {} tokens are listed", contract, NFT_INVENTORY_MAX_ENUMERATED, count));
+            }
+            for i in 0..count.min(U256::from(NFT_INVENTORY_MAX_ENUMERATED)).low_u64() {
+                let mut data = hex::decode("2f745c59").expect("Invalid selector");
+                data.extend(ethabi::encode(&[
+                    abi_address(&owner_address),
+                    abi_uint(U256::from(i)),
+                ]));
+                index_calls.push((contract.clone(), data));
+            }
+        }
+        let token_ids = match multicall(&self.infura_url, &self.multicall_address(), &index_calls).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        for ((contract, _), id) in index_calls.iter().zip(token_ids) {
+            match id {
+                Ok(b) if b.len() >= 32 => holdings.push(NftHolding {
+                    contract_address: contract.clone(),
+                    standard: "erc721".to_string(),
+                    token_id: U256::from_big_endian(&b[..32]).to_string(),
+                    balance: "1".to_string(),
+                    source: "enumerable".to_string(),
+                }),
+                _ => errors.push(format!("{}: tokenOfOwnerByIndex failed", contract)),
+            }
+        }
+
+        // Everything else: net transfers from the logs, then confirmed on-chain
+        if !by_logs.is_empty() {
+            let latest = match rpc_call(&self.infura_url, "eth_blockNumber", json!([])).await {
+                Ok(v) => match v.as_str().and_then(|h| u64::from_str_radix(h.trim_start_matches("0x"), 16).ok()) {
+                    Some(n) => n,
+                    None => return "Error: Failed to parse the block number.".to_string(),
+                },
+                Err(e) => return e,
+            };
+            let mut entries = Vec::new();
+            let mut cursor = Some(latest);
+            while let Some(start) = cursor {
+                match scan_transfer_logs(&self.infura_url, &owner, &by_logs, start, from_block, usize::MAX).await {
+                    Ok((page, next)) => {
+                        entries.extend(page);
+                        cursor = next;
+                    }
+                    Err(e) => return e,
+                }
+            }
+            let candidates = nft_holding_candidates(&entries);
+            let checks: Vec<(String, Vec<u8>)> = candidates.iter().map(|(contract, standard, id)| {
+                let data = if standard == "erc721" {
+                    [&hex::decode("6352211e").expect("Invalid selector")[..], &ethabi::encode(&[abi_uint(*id)])].concat()
+                } else {
+                    encode_erc1155_balance(&owner, *id)
+                };
+                (contract.clone(), data)
+            }).collect();
+            let results = match multicall(&self.infura_url, &self.multicall_address(), &checks).await {
+                Ok(r) => r,
+                Err(e) => return e,
+            };
+            for ((contract, standard, id), result) in candidates.into_iter().zip(results) {
+                let word = match result {
+                    Ok(b) if b.len() >= 32 => b,
+                    _ => {
+                        errors.push(format!("{}: failed to check token {}", contract, id));
+                        continue;
+                    }
+                };
+                let balance = if standard == "erc721" {
+                    if word[12..32] == owner_address[..] { U256::one() } else { U256::zero() }
+                } else {
+                    U256::from_big_endian(&word[..32])
+                };
+                if !balance.is_zero() {
+                    holdings.push(NftHolding {
+                        contract_address: contract,
+                        standard,
+                        token_id: id.to_string(),
+                        balance: balance.to_string(),
+                        source: "logs".to_string(),
+                    });
+                }
+            }
+        }
+
+        json!({
+            "holdings": holdings,
+            "errors": errors,
+        }).to_string()
+    }
+    // ERC1155 balanceOfBatch in one eth_call. owners and token_ids pair up by position, a single owner is
+    // used for every id. Returns a JSON array of Erc1155Balance.
+    pub async fn erc1155_balance_of_batch(&self, contract_address: String, owners: Vec<String>, token_ids: Vec<String>) -> String {
+        let owners = if owners.len() == 1 { vec![owners[0].clone(); token_ids.len()] } else { owners };
+        let call_data = match encode_erc1155_balance_of_batch(&owners, &token_ids) {
+            Ok(d) => d,
+            Err(e) => return e,
+        };
+        if let Err(e) = parse_address(&contract_address, "contract") { return e; }
+        let result = match rpc_call(&self.infura_url, "eth_call", json!([{ "to": contract_address, "data": format!("0x{}", hex::encode(call_data)) }, "latest"])).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        let bytes = result.as_str()
+            .and_then(|r| hex::decode(r.trim_start_matches("0x")).ok())
+            .unwrap_or_default();
+        let balances = match ethabi::decode(&[ethabi::ParamType::Array(Box::new(ethabi::ParamType::Uint(256)))], &bytes).ok().and_then(|t| t.into_iter().next()) {
+            Some(ethabi::Token::Array(items)) if items.len() == token_ids.len() => items,
+            _ => return "Error: Failed to decode the balances.".to_string(),
+        };
+        let typed: Vec<Erc1155Balance> = owners.iter().zip(token_ids.iter()).zip(balances).map(|((owner, id), balance)| Erc1155Balance {
+            owner: owner.to_lowercase(),
+            token_id: id.trim().to_string(),
+            balance: match balance {
+                ethabi::Token::Uint(v) => v.to_string(),
+                _ => "0".to_string(),
+            },
+        }).collect();
+        serde_json::to_string(&typed).unwrap_or_else(|_| "Error: Failed to serialize balances.".to_string())
+    }
+    // NFT metadata from ERC721 tokenURI, or ERC1155 uri(id) when the contract has no tokenURI.
+    // Returns NftMetadata as JSON.
+    pub async fn nft_metadata(&self, contract_address: String, token_id: String) -> String {
+        let id = match parse_token_id(&token_id) {
+            Ok(id) => id,
+            Err(e) => return e,
+        };
+        let args = ethabi::encode(&[abi_uint(id)]);
+        let token_uri = match self.eth_call_string(&contract_address, "c87b56dd", &args).await {
+            Ok(uri) if !uri.is_empty() => uri,
+            _ => match self.eth_call_string(&contract_address, "0e89341c", &args).await {
+                Ok(uri) if !uri.is_empty() => uri,
+                Ok(_) => return "Error: The contract has no token URI.".to_string(),
+                Err(e) => return e,
+            },
+        };
+        match fetch_nft_metadata(&token_uri, id, &self.ipfs_gateway, &self.arweave_gateway).await {
+            Ok(mut metadata) => {
+                metadata.contract_address = contract_address.to_lowercase();
+                serde_json::to_string(&metadata).unwrap_or_else(|_| "Error: Failed to serialize metadata.".to_string())
+            }
+            Err(e) => e,
+        }
+    }
+    // eth_call of selector + encoded args returning an ABI string
+    async fn eth_call_string(&self, contract_address: &str, selector: &str, args: &[u8]) -> Result<String, String> {
+        parse_address(contract_address, "contract")?;
+        let call_data = format!("0x{}{}", selector, hex::encode(args));
+        let result = rpc_call(&self.infura_url, "eth_call", json!([{ "to": contract_address, "data": call_data }, "latest"])).await?;
+        result.as_str()
+            .and_then(decode_abi_string)
+            .ok_or_else(|| "Error: Failed to decode the token URI.".to_string())
+    }
+    // eth_call of selector + encoded args returning the first 32 byte word
+    async fn eth_call_word(&self, contract_address: &str, selector: &str, args: &[u8]) -> Result<Vec<u8>, String> {
+        parse_address(contract_address, "contract")?;
+        let call_data = format!("0x{}{}", selector, hex::encode(args));
+        let result = rpc_call(&self.infura_url, "eth_call", json!([{ "to": contract_address, "data": call_data }, "latest"])).await?;
+        let bytes = result.as_str()
+            .and_then(|r| hex::decode(r.trim_start_matches("0x")).ok())
+            .unwrap_or_default();
+        if bytes.len() < 32 {
+            return Err("Error: Unexpected result length.".to_string());
+        }
+        Ok(bytes[..32].to_vec())
+    }
+
+    // Query ERC-1155 `balanceOf(owner, tokenId)`.
+    pub async fn erc1155_balance_of(&self,contract_address: String,owner: String,token_id: String,) -> String {
+        let id = match U256::from_dec_str(&token_id) {
+            Ok(v) => v,
+            Err(_) => return "Error: Failed to parse token ID.".to_string(),
+        };
+        let mut id_bytes = [0u8; 32];
+        id.to_big_endian(&mut id_bytes);
+
+        let call_data = {
+            let mut d = Vec::new();
+            d.extend(hex::decode("00fdd58e").unwrap());  // balanceOf selector
+            let owner_clean = owner.trim_start_matches("0x");
+            let owner_bytes = hex::decode(owner_clean).unwrap_or_default();
+            let mut pad_o = vec![0u8; 12];
+            pad_o.extend(owner_bytes);
+            d.extend(pad_o);
+            d.extend(&id_bytes);
+            format!("0x{}", hex::encode(d))
+        };
+
+        let req = json!({
+            "jsonrpc":"2.0",
+            "method":"eth_call",
+            "params":[{ "to": contract_address, "data": call_data }, "latest"],
+            "id": 1
+        });
+        let client = reqwest::Client::new();
+        let resp = match client
+            .post(&self.infura_url)
+            .header(CONTENT_TYPE, "application/json")
+            .json(&req)
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(_) => return "Error: Infura error.".to_string(),
+        };
+        if !resp.status().is_success() {
+            return "Error: Infura error.".to_string();
+        }
+        let j: Value = match resp.json().await {
+            Ok(v) => v,
+            Err(_) => return "Error: JSON parse error.".to_string(),
+        };
+        let result = j.get("result").and_then(|v| v.as_str()).unwrap_or("");
+        let bal = U256::from_str_radix(result.trim_start_matches("0x"), 16)
+            .unwrap_or(U256::zero());
+        bal.to_string()
+    }
+    //Generic read batching, calls_json is [{"to":"0x..","data":"0x.."}..].
+    //Returns [{"success":bool,"data":"0x.."}..] or [{"success":false,"error":".."}..] per call
+    pub async fn multicall(&self, calls_json: String) -> String {
+        let raw: Vec<Value> = match serde_json::from_str(&calls_json) {
+            Ok(v) => v,
+            Err(_) => return "Error: JSON parse error.".to_string(),
+        };
+        let mut calls = Vec::with_capacity(raw.len());
+        for call in raw {
+            let to = call["to"].as_str().unwrap_or("").to_string();
+            let data = match hex::decode(call["data"].as_str().unwrap_or("").trim_start_matches("0x")) {
+                Ok(d) => d,
+                Err(_) => return "Error: Failed to decode the data field.".to_string(),
+            };
+            calls.push((to, data));
+        }
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        let out: Vec<Value> = results.into_iter().map(|r| match r {
+            Ok(bytes) => json!({ "success": true, "data": format!("0x{}", hex::encode(bytes)) }),
+            Err(e) => json!({ "success": false, "error": e }),
+        }).collect();
+        Value::Array(out).to_string()
+    }
+    //validate_contract for many contracts in one call, returns a JSON array with an "error" entry for failures
+    pub async fn validate_contracts(&self, contract_addresses: Vec<String>) -> String {
+        let mut calls = Vec::with_capacity(contract_addresses.len() * 3);
+        for contract in &contract_addresses {
+            calls.push((contract.clone(), hex::decode("313ce567").unwrap())); // decimals()
+            calls.push((contract.clone(), hex::decode("95d89b41").unwrap())); // symbol()
+            calls.push((contract.clone(), hex::decode("06fdde03").unwrap())); // name()
+        }
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        let out: Vec<Value> = contract_addresses.iter().zip(results.chunks(3)).map(|(contract, r)| {
+            let decoded = match (&r[0], &r[1], &r[2]) {
+                (Ok(d), Ok(s), Ok(n)) if d.len() >= 32 && d[..31].iter().all(|b| *b == 0) => {
+                    decode_abi_string(&hex::encode(s)).zip(decode_abi_string(&hex::encode(n))).map(|(symbol, name)| (d[31], symbol, name))
+                }
+                _ => None,
+            };
+            match decoded {
+                Some((decimals, symbol, name)) => json!({
+                    "address": contract,
+                    "decimals": decimals,
+                    "symbol": symbol,
+                    "name": name,
+                }),
+                None => json!({ "address": contract, "error": "Error: Not an ERC20 contract." }),
+            }
+        }).collect();
+        Value::Array(out).to_string()
+    }
+    // Works out what kind of contract an address is: ERC-165 interfaces, ERC20 selectors, EIP-1967/EIP-1167 proxies
+    // and bytes32 name()/symbol(). Returns a ContractInfo whose `builder` names the transfer builder to use
+    pub async fn inspect_contract(&self, contract_address: String) -> String {
+        if let Err(e) = parse_address(&contract_address, "contract") {
+            return e;
+        }
+        let requests = [
+            ("eth_getCode", json!([contract_address, "latest"])),
+            ("eth_getStorageAt", json!([contract_address, EIP1967_IMPLEMENTATION_SLOT, "latest"])),
+            ("eth_getStorageAt", json!([contract_address, EIP1967_BEACON_SLOT, "latest"])),
+        ];
+        let results = match batch_rpc(&self.infura_url, &requests).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        let as_bytes = |i: usize| match &results[i] {
+            Ok(v) => v.as_str().and_then(|h| hex::decode(h.trim_start_matches("0x")).ok()),
+            Err(_) => None,
+        };
+        let code = match as_bytes(0) {
+            Some(c) => c,
+            None => return "Error: Failed to read the contract code.".to_string(),
+        };
+        if code.is_empty() {
+            return "Error: The address has no contract code.".to_string();
+        }
+
+        let mut proxy = None;
+        if let Some(implementation) = eip1167_implementation(&code) {
+            proxy = Some(ProxyInfo { kind: "eip1167".to_string(), implementation: Some(implementation), beacon: None });
+        } else if let Some(implementation) = as_bytes(1).as_deref().and_then(slot_address) {
+            proxy = Some(ProxyInfo { kind: "eip1967".to_string(), implementation: Some(implementation), beacon: None });
+        } else if let Some(beacon) = as_bytes(2).as_deref().and_then(slot_address) {
+            // implementation()
+            let implementation = self.eth_call_word(&beacon, "5c60da1b", &[]).await.ok().as_deref().and_then(slot_address);
+            proxy = Some(ProxyInfo { kind: "eip1967_beacon".to_string(), implementation, beacon: Some(beacon) });
+        }
+
+        // A proxy delegates these calls, so they are asked of the proxy itself
+        let mut calls: Vec<(String, Vec<u8>)> = [ERC165_INTERFACE_ID, "ffffffff", ERC721_INTERFACE_ID, ERC721_METADATA_INTERFACE_ID,
+            ERC721_ENUMERABLE_INTERFACE_ID, ERC1155_INTERFACE_ID, ERC1155_METADATA_URI_INTERFACE_ID]
+            .iter()
+            .map(|id| (contract_address.clone(), encode_supports_interface(id)))
+            .collect();
+        for selector in ["313ce567", "95d89b41", "06fdde03", "18160ddd"] { // decimals(), symbol(), name(), totalSupply()
+            calls.push((contract_address.clone(), hex::decode(selector).expect("Invalid selector")));
+        }
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        classify_contract(&contract_address, &results, proxy).to_json()
+    }
+    // Reads decimals(), symbol() and name() of every imported list token and flags the ones that differ from
+    // their list entry. Returns the token registry
+    pub async fn verify_token_lists(&mut self) -> String {
+        let mut addresses: Vec<String> = Vec::new();
+        for token in &self.listed_tokens {
+            if !addresses.iter().any(|a| a.eq_ignore_ascii_case(&token.address)) {
+                addresses.push(token.address.clone());
+            }
+        }
+        let mut calls = Vec::with_capacity(addresses.len() * 3);
+        for address in &addresses {
+            calls.push((address.clone(), hex::decode("313ce567").unwrap())); // decimals()
+            calls.push((address.clone(), hex::decode("95d89b41").unwrap())); // symbol()
+            calls.push((address.clone(), hex::decode("06fdde03").unwrap())); // name()
+        }
+        let results = match multicall(&self.infura_url, &self.multicall_address(), &calls).await {
+            Ok(r) => r,
+            Err(e) => return e,
+        };
+        for (address, r) in addresses.iter().zip(results.chunks(3)) {
+            let decimals = match &r[0] {
+                Ok(d) if d.len() >= 32 && d[..31].iter().all(|b| *b == 0) => Some(d[31]),
+                _ => None,
+            };
+            let symbol = match &r[1] { Ok(b) => decode_string_or_bytes32(b), Err(_) => None };
+            let name = match &r[2] { Ok(b) => decode_string_or_bytes32(b), Err(_) => None };
+            for token in self.listed_tokens.iter_mut().filter(|t| t.address.eq_ignore_ascii_case(address)) {
+                token.mismatches = token_mismatches(token, decimals, symbol.as_deref(), name.as_deref());
+            }
+        }
+        self.token_registry()
+    }
+    // erc20_balance over every registry token, returns the non-zero ones as an array of
+    // {"address","symbol","name","decimals","balance","formatted","flags"}
+    pub async fn discover_token_balances(&self) -> String {
+        let registry = merge_token_registry(&self.known_tokens, &self.listed_tokens);
+        let balances = self.erc20_balance(registry.iter().map(|t| t.address.clone()).collect()).await;
+        if balances.first().map(|s| s.as_str()) != Some("Success") {
+            return balances.into_iter().next().unwrap_or_else(|| "Error: Missing response.".to_string());
+        }
+        let found: Vec<Value> = registry.iter().zip(balances.iter().skip(1)).filter_map(|(token, balance)| {
+            let amount = U256::from_dec_str(balance).ok().filter(|b| !b.is_zero())?;
+            Some(json!({
+                "address": token.address,
+                "symbol": token.symbol,
+                "name": token.name,
+                "decimals": token.decimals,
+                "balance": amount.to_string(),
+                "formatted": format_units(amount, token.decimals),
+                "flags": token.flags,
+            }))
+        }).collect();
+        Value::Array(found).to_string()
+    }
+    // Checks a recipient before send or erc20_transfer: look-alikes of address book entries and addresses the wallet
+    // has paid (from history_json, get_tx_history output or "", and pending transactions), first-time recipients, contracts
+    // (eth_getCode) and token contracts. Returns a RecipientCheck
+    pub async fn check_recipient(&mut self, recipient: String, history_json: String) -> String {
+        if let Err(e) = parse_address(&recipient, "recipient") {
+            return e;
+        }
+        let history: Vec<EtherscanTx> = if history_json.trim().is_empty() {
+            Vec::new()
+        } else {
+            let value: Value = match serde_json::from_str(&history_json) {
+                Ok(v) => v,
+                Err(_) => return "Error: Failed to parse the transaction history.".to_string(),
+            };
+            match serde_json::from_value(value.get("entries").cloned().unwrap_or(value)) {
+                Ok(e) => e,
+                Err(_) => return "Error: Failed to parse the transaction history.".to_string(),
+            }
+        };
+        let code = match rpc_call(&self.infura_url, "eth_getCode", json!([recipient.trim(), "latest"])).await {
+            Ok(v) => v.as_str().map(|c| !c.trim_start_matches("0x").is_empty()),
+            Err(e) => return e,
+        };
+        let own = self.address();
+        let registry = merge_token_registry(&self.known_tokens, &self.listed_tokens);
+        let mut check = assess_recipient(&recipient, &self.paid_counterparties(&history), &self.address_book, &registry, self.chain_id, &own);
+        if let Some(is_contract) = code {
+            add_contract_warning(&mut check, is_contract);
+        }
+        serde_json::to_string(&check).unwrap_or_else(|_| "Error: Failed to serialize the recipient check.".to_string())
+    }
+}
+
+//Signature utilities, accepts 65 byte r||s||v or 64 byte EIP-2098 r||yParityAndS in hex or base64
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvmSignature {
+    r: [u8; 32],
+    s: [u8; 32],
+    y_parity: u8,
+}
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl EvmSignature {
+    //Returns undefined when the input isn't a 64 or 65 byte signature
+    pub fn parse(signature: String) -> Option<EvmSignature> {
+        let trimmed = signature.trim();
+        let bytes = match trimmed.len() {
+            // Unprefixed hex would otherwise also be valid base64
+            128 | 130 if trimmed.chars().all(|c| c.is_ascii_hexdigit()) => hex::decode(trimmed).ok()?,
+            _ => decode_hex_or_b64(trimmed)?,
+        };
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        match bytes.len() {
+            65 => {
+                r.copy_from_slice(&bytes[..32]);
+                s.copy_from_slice(&bytes[32..64]);
+                let y_parity = match bytes[64] {
+                    v @ 0..=1 => v,
+                    v @ 27..=28 => v - 27,
+                    v if v >= 35 => (v - 35) % 2,
+                    _ => return None,
+                };
+                Some(EvmSignature { r, s, y_parity })
+            }
+            64 => {
+                // EIP-2098: the top bit of s carries yParity
+                r.copy_from_slice(&bytes[..32]);
+                s.copy_from_slice(&bytes[32..]);
+                let y_parity = s[0] >> 7;
+                s[0] &= 0x7f;
+                Some(EvmSignature { r, s, y_parity })
+            }
+            _ => None,
+        }
+    }
+    pub fn r(&self) -> String {
+        format!("0x{}", hex::encode(self.r))
+    }
+    pub fn s(&self) -> String {
+        format!("0x{}", hex::encode(self.s))
+    }
+    pub fn y_parity(&self) -> u8 {
+        self.y_parity
+    }
+    //Legacy 27/28 v
+    pub fn v(&self) -> u8 {
+        self.y_parity + 27
+    }
+    //EIP-155 v = chain_id * 2 + 35 + yParity
+    pub fn v_eip155(&self, chain_id: u64) -> u64 {
+        chain_id * 2 + 35 + self.y_parity as u64
+    }
+    pub fn is_low_s(&self) -> bool {
+        U256::from_big_endian(&self.s) <= secp256k1_half_order()
+    }
+    //Flips s to n - s and toggles yParity when s is in the upper half of the curve order
+    pub fn to_low_s(&self) -> EvmSignature {
+        if self.is_low_s() {
+            return *self;
+        }
+        let mut s = [0u8; 32];
+        (secp256k1_order() - U256::from_big_endian(&self.s)).to_big_endian(&mut s);
+        EvmSignature { r: self.r, s, y_parity: self.y_parity ^ 1 }
+    }
+    //65 bytes with v as 27/28
+    pub fn to_hex(&self) -> String {
+        format!("0x{}", hex::encode(self.bytes_with_v(self.v())))
+    }
+    //65 bytes with v as 0/1
+    pub fn to_hex_y_parity(&self) -> String {
+        format!("0x{}", hex::encode(self.bytes_with_v(self.y_parity)))
+    }
+    //r||s followed by the big-endian EIP-155 v
+    pub fn to_hex_eip155(&self, chain_id: u64) -> String {
+        let mut out = self.r.to_vec();
+        out.extend_from_slice(&self.s);
+        let v = self.v_eip155(chain_id).to_be_bytes();
+        let first = v.iter().position(|b| *b != 0).unwrap_or(7);
+        out.extend_from_slice(&v[first..]);
+        format!("0x{}", hex::encode(out))
+    }
+    //64 byte EIP-2098 compact signature, s must be low for the parity bit to fit
+    pub fn to_compact(&self) -> String {
+        let low = self.to_low_s();
+        let mut out = low.r.to_vec();
+        let mut s = low.s;
+        s[0] |= low.y_parity << 7;
+        out.extend_from_slice(&s);
+        format!("0x{}", hex::encode(out))
+    }
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.bytes_with_v(self.v()))
+    }
+}
+
+impl EvmSignature {
+    fn bytes_with_v(&self, v: u8) -> Vec<u8> {
+        let mut out = Vec::with_capacity(65);
+        out.extend_from_slice(&self.r);
+        out.extend_from_slice(&self.s);
+        out.push(v);
+        out
+    }
+}
+
+fn secp256k1_order() -> U256 {
+    U256::from_str_radix("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141", 16).unwrap()
+}
+
+fn secp256k1_half_order() -> U256 {
+    secp256k1_order() / 2
+}
+
+pub fn convert_to_xpub(xpub_str : String) -> String{
+    let zpub_bytes = match bs58::decode(&xpub_str).with_check(None).into_vec(){
+        Ok(zpub_bytes) => zpub_bytes,
+        Err(_) => return "Error: Invalid extended public key.".to_string(),
+    };
+    let new_bytes = &zpub_bytes[4..];
+    let new_prefix = hex_to_vec("0488b21e").unwrap();
+    let mut vec = Vec::from(new_bytes);
+    for i in (0..new_prefix.len()).rev() {
+        vec.insert(0, new_prefix[i]);
+    }
+    return bs58::encode(vec).with_check().into_string();
+}
+#[cfg(feature = "rpc")]
+fn gas_price_to_string(gas_price: U256) -> String {
+    return gas_price.to_string();
+}
+// Convert a decimal string to a U256 gas price.
+fn gas_price_from_string(s: &str) -> U256 {
+    let res = match U256::from_dec_str(s){
+        Ok(res) => res,
+        Err(_) => U256::zero(),
+    };
+    return res;
+}
+pub fn hex_to_vec(hex_string: &str) -> Option<Vec<u8>> {
+    if hex_string.len() % 2 != 0 { return None; }
+    let mut bytes = Vec::new();
+    for chunk in hex_string.as_bytes().chunks(2) {
+        if let Ok(byte) = u8::from_str_radix(std::str::from_utf8(chunk).unwrap(), 16) {
+            bytes.push(byte);
+        }else{
+            return None; 
+        }
+    }
+    return Some(bytes);
+}
+#[cfg_attr(not(feature = "rpc"), allow(dead_code))]
+fn decode_abi_string(hex_str: &str) -> Option<String> {
+    let hex = hex_str.trim_start_matches("0x");
+    if hex.len() >= 128 {
+        // The first 64 characters are the offset; the next 64 characters contain the string length.
+        let len_hex = &hex[64..128];
+        let len = usize::from_str_radix(len_hex, 16).ok()?;
+        let start = 128;
+        let end = start + len * 2;
+        if hex.len() < end {
+            return None;
+        }
+        let data_hex = &hex[start..end];
+        let bytes = hex_to_vec(data_hex)?;
+        return String::from_utf8(bytes).ok();
+    } else {
+        let bytes = hex_to_vec(hex)?;
+        let s = bytes.into_iter().take_while(|&b| b != 0).collect::<Vec<u8>>();
+        return String::from_utf8(s).ok();
+    }
+}
+pub fn wei_to_eth(wei: U256) -> f64 {
+    // Convert U256 to a string, then parse as f64.
+    // Note: This approach works well for typical balances,
+    // but may lose precision for extremely large values.
+    let wei_str = wei.to_string();
+    let wei_f64: f64 = match wei_str.parse(){
+        Ok(f6) => f6,
+        Err(_) => return 0.0,
+    };
+    // Divide by 10^18 to get Ether
+    return wei_f64 / 1e18;
+}
+pub fn chunk_and_label(final_str: &str, chunk_size: usize) -> Vec<String> {
+    let total_chunks = (final_str.len() + chunk_size - 1) / chunk_size; // Calculate the number of chunks
+    final_str
+        .chars() // Iterate over characters to respect character boundaries
+        .collect::<Vec<_>>() // Collect characters into a vector for chunking
+        .chunks(chunk_size) // Chunk the vector
+        .enumerate() // Provide index for each chunk
+        .map(|(index, chunk)| {
+            let chunk_str = chunk.iter().collect::<String>(); // Convert chunk to string
+            format!("({}/{}){}", index, total_chunks, chunk_str) // Format with index and total
+        })
+        .collect() // Collect into a vector of strings
+}
+// Reassembles the chunks made by chunk_and_label, in any order and with repeated scans allowed
+pub fn join_qr_chunks(chunks: &[String]) -> Result<String, String> {
+    let mut total: Option<usize> = None;
+    let mut parts: Vec<Option<String>> = Vec::new();
+    for chunk in chunks {
+        let chunk = chunk.trim();
+        if chunk.is_empty() { continue; }
+        let (label, body) = chunk.strip_prefix('(')
+            .and_then(|rest| rest.split_once(')'))
+            .ok_or_else(|| format!("Error: Chunk is missing its (index/total) label: {}", chunk))?;
+        let (index, count) = label.split_once('/')
+            .and_then(|(i, n)| Some((i.parse::<usize>().ok()?, n.parse::<usize>().ok()?)))
+            .ok_or_else(|| format!("Error: Invalid chunk label ({}).", label))?;
+        match total {
+            None => {
+                total = Some(count);
+                parts = vec![None; count];
+            }
+            Some(n) if n != count => return Err("Error: Chunks come from different payloads.".to_string()),
+            _ => {}
+        }
+        if index >= count {
+            return Err(format!("Error: Chunk index {} is out of range.", index));
+        }
+        match &parts[index] {
+            Some(existing) if existing != body => return Err(format!("Error: Chunk {} was scanned twice with different contents.", index)),
+            _ => parts[index] = Some(body.to_string()),
+        }
+    }
+    if total.is_none() {
+        return Err("Error: No chunks given.".to_string());
+    }
+    let missing: Vec<String> = parts.iter().enumerate().filter(|(_, p)| p.is_none()).map(|(i, _)| i.to_string()).collect();
+    if !missing.is_empty() {
+        return Err(format!("Error: Missing chunks {}.", missing.join(", ")));
+    }
+    Ok(parts.into_iter().flatten().collect())
+}
+// Helper function to encode ERC20 transfer data.
+pub fn encode_transfer(recipient: &str, amount: U256) -> Vec<u8> {
+    let mut data = Vec::new();
+    // Function selector for transfer(address,uint256): "a9059cbb"
+    let selector = hex::decode("a9059cbb").expect("Invalid function selector");
+    data.extend(selector);
+
+    // Encode recipient address.
+    let recipient_clean = recipient.trim_start_matches("0x");
+    let recipient_bytes = hex::decode(recipient_clean).expect("Invalid recipient address");
+    let mut padded_recipient = vec![0u8; 12]; // 32 - 20 = 12 bytes of zero padding.
+    padded_recipient.extend(recipient_bytes);
+    data.extend(padded_recipient);
+
+    // Encode amount as a 32-byte big-endian integer.
+    let mut amount_bytes = [0u8; 32];
+    amount.to_big_endian(&mut amount_bytes);
+    data.extend_from_slice(&amount_bytes);
+
+    return data;
+}
+pub fn extract_u16s(input: &str) -> Result<(u16, u16), &'static str> {
+        let parts: Vec<&str> = input.split('/').collect();
+        if parts.len() != 3 {
+            return Err("Error: Invalid format.");
+        }
+        let first_u16 = parts[1].parse::<u16>().map_err(|_| "Error: Failed to parse first number.")?;
+        let second_u16 = parts[2].parse::<u16>().map_err(|_| "Error: Failed to parse second number.")?;
+        return Ok((first_u16, second_u16))
+}
+pub fn append_integers_as_bytes(vec: &mut Vec<u8>, addressdepth: u16, changedepth: u16) {
+    let addressdepth_bytes = addressdepth.to_le_bytes();
+    let changedepth_bytes = changedepth.to_le_bytes();
+    vec.extend_from_slice(&addressdepth_bytes);
+    vec.extend_from_slice(&changedepth_bytes);
+}
+
+//NFT Encoding helper functions, ERC721 ERC1155
+pub fn encode_erc721_transfer(from: &str, to: &str, token_id: U256) -> Vec<u8> {
+    let mut data = Vec::new();
+    // function selector for transferFrom(address,address,uint256)
+    data.extend(hex::decode("23b872dd").expect("Invalid selector"));
+
+    // from
+    let from_clean = from.trim_start_matches("0x");
+    let from_bytes = hex::decode(from_clean).expect("Invalid from address");
+    let mut pad_from = vec![0u8; 12];
+    pad_from.extend(from_bytes);
+    data.extend(pad_from);
+
+    // to
+    let to_clean = to.trim_start_matches("0x");
+    let to_bytes = hex::decode(to_clean).expect("Invalid to address");
+    let mut pad_to = vec![0u8; 12];
+    pad_to.extend(to_bytes);
+    data.extend(pad_to);
+
+    // tokenId
+    let mut id_bytes = [0u8; 32];
+    token_id.to_big_endian(&mut id_bytes);
+    data.extend_from_slice(&id_bytes);
+
+    data
+}
+
+// safeTransferFrom(from, to, tokenId) or, with non-empty hex data, safeTransferFrom(from, to, tokenId, data)
+pub fn encode_erc721_safe_transfer(from: &str, to: &str, token_id: &str, data: &str) -> Result<Vec<u8>, String> {
+    let from = parse_address(from, "sender")?;
+    let to = parse_address(to, "recipient")?;
+    let token_id = parse_token_id(token_id)?;
+    let extra = hex::decode(data.trim().trim_start_matches("0x")).map_err(|_| "Error: Failed to decode the data field.".to_string())?;
+    let mut args = vec![abi_address(&from), abi_address(&to), abi_uint(token_id)];
+    let selector = if extra.is_empty() {
+        "42842e0e"
+    } else {
+        args.push(ethabi::Token::Bytes(extra));
+        "b88d4fde"
+    };
+    let mut out = hex::decode(selector).expect("Invalid selector");
+    out.extend(ethabi::encode(&args));
+    Ok(out)
+}
+
+// approve(spender, tokenId)
+pub fn encode_erc721_approve(spender: &str, token_id: &str) -> Result<Vec<u8>, String> {
+    let spender = parse_address(spender, "spender")?;
+    let token_id = parse_token_id(token_id)?;
+    let mut out = hex::decode("095ea7b3").expect("Invalid selector");
+    out.extend(ethabi::encode(&[abi_address(&spender), abi_uint(token_id)]));
+    Ok(out)
+}
+
+// setApprovalForAll(operator, approved)
+pub fn encode_set_approval_for_all(operator: &str, approved: bool) -> Result<Vec<u8>, String> {
+    let operator = parse_address(operator, "operator")?;
+    let mut out = hex::decode("a22cb465").expect("Invalid selector");
+    out.extend(ethabi::encode(&[abi_address(&operator), ethabi::Token::Bool(approved)]));
+    Ok(out)
+}
+
+// safeBatchTransferFrom(from, to, ids, amounts, data)
+pub fn encode_erc1155_batch_transfer(from: &str, to: &str, token_ids: &[String], amounts: &[String], data: &str) -> Result<Vec<u8>, String> {
+    let from = parse_address(from, "sender")?;
+    let to = parse_address(to, "recipient")?;
+    if token_ids.is_empty() || token_ids.len() != amounts.len() {
+        return Err("Error: Token IDs and amounts must be non-empty and the same length.".to_string());
+    }
+    let ids = token_ids.iter().map(|id| parse_token_id(id).map(abi_uint)).collect::<Result<Vec<_>, _>>()?;
+    let amounts = amounts.iter()
+        .map(|a| U256::from_dec_str(a.trim()).map(abi_uint).map_err(|_| "Error: Failed to parse amount.".to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let extra = hex::decode(data.trim().trim_start_matches("0x")).map_err(|_| "Error: Failed to decode the data field.".to_string())?;
+    let mut out = hex::decode("2eb2c2d6").expect("Invalid selector");
+    out.extend(ethabi::encode(&[abi_address(&from), abi_address(&to), ethabi::Token::Array(ids), ethabi::Token::Array(amounts), ethabi::Token::Bytes(extra)]));
+    Ok(out)
+}
+
+// balanceOfBatch(owners, ids)
+pub fn encode_erc1155_balance_of_batch(owners: &[String], token_ids: &[String]) -> Result<Vec<u8>, String> {
+    if token_ids.is_empty() || owners.len() != token_ids.len() {
+        return Err("Error: Owners and token IDs must be non-empty and the same length.".to_string());
+    }
+    let owners = owners.iter().map(|o| parse_address(o, "owner").map(|a| abi_address(&a))).collect::<Result<Vec<_>, _>>()?;
+    let ids = token_ids.iter().map(|id| parse_token_id(id).map(abi_uint)).collect::<Result<Vec<_>, _>>()?;
+    let mut out = hex::decode("4e1273f4").expect("Invalid selector");
+    out.extend(ethabi::encode(&[ethabi::Token::Array(owners), ethabi::Token::Array(ids)]));
+    Ok(out)
+}
+
+// Base cost plus roughly one storage update on each side per id
+fn erc1155_batch_gas_limit(ids: usize) -> u64 {
+    80_000 + 50_000 * ids as u64
+}
+
+// ERC-165 interface ids
+pub const ERC165_INTERFACE_ID: &str = "01ffc9a7";
+pub const ERC721_INTERFACE_ID: &str = "80ac58cd";
+pub const ERC721_METADATA_INTERFACE_ID: &str = "5b5e139f";
+pub const ERC721_ENUMERABLE_INTERFACE_ID: &str = "780e9d63";
+pub const ERC1155_INTERFACE_ID: &str = "d9b67a26";
+pub const ERC1155_METADATA_URI_INTERFACE_ID: &str = "0e89341c";
+// bytes32(uint256(keccak256("eip1967.proxy.implementation")) - 1) and the beacon equivalent
+pub const EIP1967_IMPLEMENTATION_SLOT: &str = "0x360894a13ba1a3210667c828492db98dca3e2076cc3735a920a3ca505d382bbc";
+pub const EIP1967_BEACON_SLOT: &str = "0xa3f0ad74e5423aebfd80d3ef4346578335a9a72aeaee59ff6cb3582b35133d50";
+// tokenOfOwnerByIndex calls per contract in nft_inventory
+#[cfg(feature = "rpc")]
+const NFT_INVENTORY_MAX_ENUMERATED: u64 = 2_000;
+
+// supportsInterface(bytes4) for a hex interface id such as "80ac58cd"
+pub fn encode_supports_interface(interface_id: &str) -> Vec<u8> {
+    let mut id = [0u8; 32];
+    if let Ok(bytes) = hex::decode(interface_id.trim_start_matches("0x")) {
+        let n = bytes.len().min(4);
+        id[..n].copy_from_slice(&bytes[..n]);
+    }
+    let mut out = hex::decode("01ffc9a7").expect("Invalid selector");
+    out.extend_from_slice(&id);
+    out
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ContractInterfaces {
+    pub erc165: bool,
+    pub erc721: bool,
+    pub erc721_metadata: bool,
+    pub erc721_enumerable: bool,
+    pub erc1155: bool,
+    pub erc1155_metadata_uri: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ProxyInfo {
+    pub kind: String, // eip1167, eip1967 or eip1967_beacon
+    pub implementation: Option<String>,
+    pub beacon: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ContractInfo {
+    pub address: String,
+    pub standard: String, // erc20, erc721, erc1155 or unknown
+    pub builder: String, // erc20_transfer, erc721_safe_transfer, erc1155_transfer or contract_call
+    pub interfaces: ContractInterfaces,
+    pub proxy: Option<ProxyInfo>,
+    pub name: Option<String>,
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    pub total_supply: Option<String>,
+}
+
+impl ContractInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|_| "Error: Failed to serialize contract info.".to_string())
+    }
+}
+
+// The implementation hard-coded in EIP-1167 minimal proxy bytecode
+pub fn eip1167_implementation(code: &[u8]) -> Option<String> {
+    let prefix = hex::decode("363d3d373d3d3d363d73").expect("Invalid bytecode");
+    let suffix = hex::decode("5af43d82803e903d91602b57fd5bf3").expect("Invalid bytecode");
+    if code.len() != prefix.len() + 20 + suffix.len() || !code.starts_with(&prefix) || !code.ends_with(&suffix) {
+        return None;
+    }
+    Some(format!("0x{}", hex::encode(&code[prefix.len()..prefix.len() + 20])))
+}
+
+// The address in a storage slot or return word, None when it's zero
+#[cfg(feature = "rpc")]
+fn slot_address(word: &[u8]) -> Option<String> {
+    if word.len() < 32 || word[12..32].iter().all(|b| *b == 0) {
+        return None;
+    }
+    Some(format!("0x{}", hex::encode(&word[12..32])))
+}
+
+// name()/symbol() as an ABI string or, for tokens like MKR, a zero-padded bytes32
+pub fn decode_string_or_bytes32(bytes: &[u8]) -> Option<String> {
+    decode_abi_string(&hex::encode(bytes)).filter(|s| !s.is_empty())
+}
+
+// Classifies a contract from the inspect_contract multicall results: seven supportsInterface calls (ERC165,
+// 0xffffffff, ERC721, metadata, enumerable, ERC1155, metadata URI) then decimals(), symbol(), name(), totalSupply()
+pub fn classify_contract(address: &str, results: &[Result<Vec<u8>, String>], proxy: Option<ProxyInfo>) -> ContractInfo {
+    let word = |i: usize| match results.get(i) {
+        Some(Ok(b)) if b.len() >= 32 => Some(&b[..32]),
+        _ => None,
+    };
+    let is_true = |i: usize| word(i).map(|w| U256::from_big_endian(w) == U256::one()).unwrap_or(false);
+    // ERC-165 compliance needs true for its own id and false for 0xffffffff
+    let erc165 = is_true(0) && word(1).map(|w| U256::from_big_endian(w).is_zero()).unwrap_or(false);
+    let interfaces = ContractInterfaces {
+        erc165,
+        erc721: erc165 && is_true(2),
+        erc721_metadata: erc165 && is_true(3),
+        erc721_enumerable: erc165 && is_true(4),
+        erc1155: erc165 && is_true(5),
+        erc1155_metadata_uri: erc165 && is_true(6),
+    };
+    let decimals = word(7).filter(|w| w[..31].iter().all(|b| *b == 0)).map(|w| w[31]);
+    let symbol = match results.get(8) { Some(Ok(b)) => decode_string_or_bytes32(b), _ => None };
+    let name = match results.get(9) { Some(Ok(b)) => decode_string_or_bytes32(b), _ => None };
+    let total_supply = word(10).map(|w| U256::from_big_endian(w).to_string());
+
+    let (standard, builder) = if interfaces.erc1155 {
+        ("erc1155", "erc1155_transfer")
+    } else if interfaces.erc721 {
+        ("erc721", "erc721_safe_transfer")
+    } else if decimals.is_some() && total_supply.is_some() {
+        ("erc20", "erc20_transfer")
+    } else {
+        ("unknown", "contract_call")
+    };
+    ContractInfo {
+        address: address.to_string(),
+        standard: standard.to_string(),
+        builder: builder.to_string(),
+        interfaces,
+        proxy,
+        name,
+        symbol,
+        decimals,
+        total_supply,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NftHolding {
+    pub contract_address: String,
+    pub standard: String, // erc721 or erc1155
+    pub token_id: String,
+    pub balance: String,
+    pub source: String, // enumerable or logs
+}
+
+// (contract, standard, token id) with a positive net amount received across the NFT history entries
+pub fn nft_holding_candidates(entries: &[HistoryEntry]) -> Vec<(String, String, U256)> {
+    let mut keys: Vec<(String, String, U256)> = Vec::new();
+    let mut totals: Vec<(U256, U256)> = Vec::new(); // (received, sent)
+    for e in entries.iter().filter(|e| e.kind == "erc721" || e.kind == "erc1155") {
+        let (Some(contract), Some(id)) = (&e.contract_address, e.token_id.as_deref().and_then(|id| U256::from_dec_str(id).ok())) else { continue };
+        let amount = U256::from_dec_str(&e.value).unwrap_or_default();
+        let key = (contract.to_lowercase(), e.kind.clone(), id);
+        let slot = match keys.iter().position(|k| *k == key) {
+            Some(i) => i,
+            None => {
+                keys.push(key);
+                totals.push((U256::zero(), U256::zero()));
+                keys.len() - 1
+            }
+        };
+        match e.direction.as_str() {
+            "received" => totals[slot].0 = totals[slot].0.saturating_add(amount),
+            "sent" => totals[slot].1 = totals[slot].1.saturating_add(amount),
+            _ => {}
+        }
+    }
+    keys.into_iter().zip(totals).filter(|(_, (received, sent))| received > sent).map(|(k, _)| k).collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Erc1155Balance {
+    pub owner: String,
+    pub token_id: String,
+    pub balance: String,
+}
+
+fn abi_address(address: &Address) -> ethabi::Token {
+    ethabi::Token::Address(ethabi::Address::from_slice(address.as_bytes()))
+}
+
+// ethabi carries its own ethereum-types version, so U256 goes across as big-endian bytes
+fn abi_uint(value: U256) -> ethabi::Token {
+    let mut word = [0u8; 32];
+    value.to_big_endian(&mut word);
+    ethabi::Token::Uint(ethabi::Uint::from_big_endian(&word))
+}
+
+fn parse_address(address: &str, what: &str) -> Result<Address, String> {
+    Address::from_str(address.trim()).map_err(|_| format!("Error: Failed to parse the {} address.", what))
+}
+
+fn parse_token_id(token_id: &str) -> Result<U256, String> {
+    U256::from_dec_str(token_id.trim()).map_err(|_| "Error: Failed to parse token ID.".to_string())
+}
+
+pub fn encode_erc1155_balance(owner: &str, token_id: U256) -> Vec<u8> {
+    let mut data = Vec::new();
+    // function selector for balanceOf(address,uint256)
+    data.extend(hex::decode("00fdd58e").expect("Invalid selector"));
+
+    // owner
+    let owner_clean = owner.trim_start_matches("0x");
+    let owner_bytes = hex::decode(owner_clean).expect("Invalid owner address");
+    let mut pad_owner = vec![0u8; 12];
+    pad_owner.extend(owner_bytes);
+    data.extend(pad_owner);
+
+    // tokenId
+    let mut id_bytes = [0u8; 32];
+    token_id.to_big_endian(&mut id_bytes);
+    data.extend_from_slice(&id_bytes);
+
+    data
+}
+
+
+pub fn encode_erc1155_transfer(from: &str, to: &str, token_id: U256, amount: U256) -> Vec<u8> {
+    let mut data = Vec::new();
+    // function selector for safeTransferFrom(address,address,uint256,uint256,bytes)
+    data.extend(hex::decode("f242432a").expect("Invalid selector"));
+
+    // from
+    let from_clean = from.trim_start_matches("0x");
+    let from_bytes = hex::decode(from_clean).expect("Invalid from address");
+    let mut pad_from = vec![0u8; 12];
+    pad_from.extend(from_bytes);
+    data.extend(pad_from);
+
+    // to
+    let to_clean = to.trim_start_matches("0x");
+    let to_bytes = hex::decode(to_clean).expect("Invalid to address");
+    let mut pad_to = vec![0u8; 12];
+    pad_to.extend(to_bytes);
+    data.extend(pad_to);
+
+    // tokenId
+    let mut id_bytes = [0u8; 32];
+    token_id.to_big_endian(&mut id_bytes);
+    data.extend(&id_bytes);
+
+    // amount
+    let mut amt_bytes = [0u8; 32];
+    amount.to_big_endian(&mut amt_bytes);
+    data.extend(&amt_bytes);
+
+    // offset to the dynamic `bytes data` (5 * 32 = 160)
+    let mut offset = [0u8; 32];
+    offset[31] = 160;
+    data.extend(&offset);
+
+    // length of `data` = 0
+    data.extend(&[0u8; 32]);
+
+    data
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EtherscanTx {
+    pub hash: String,
+    pub direction: String,
+    pub from_address: String,
+    pub to_address: String,
+    pub value_eth: f64, // the native amount, 0 for token transfers
+    pub value_wei: String, // wei, or the raw token amount for token transfers
+    pub block_number: String,
+    pub timestamp: String,
+    #[serde(default = "default_tx_kind")]
+    pub kind: String, // native, internal, erc20, erc721 or erc1155
+    #[serde(default)]
+    pub contract_address: Option<String>,
+    #[serde(default)]
+    pub token_symbol: Option<String>,
+    #[serde(default)]
+    pub token_decimals: Option<u8>,
+    #[serde(default)]
+    pub token_id: Option<String>,
+    #[serde(default)]
+    pub gas_used: String,
+    #[serde(default)]
+    pub fee_wei: String, // gas used * gas price of the whole transaction, paid by its sender
+    #[serde(default = "default_tx_status")]
+    pub status: String, // success or failed
+    #[serde(default)]
+    pub counterparty_label: Option<String>, // the address book label of the other side
+}
+
+fn default_tx_kind() -> String {
+    "native".to_string()
+}
+
+fn default_tx_status() -> String {
+    "success".to_string()
+}
+
+//History export for spreadsheets and accounting tools
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    Koinly,
+    CoinTracking,
+}
+
+impl ExportFormat {
+    pub fn parse(name: &str) -> Option<ExportFormat> {
+        match name.trim().to_lowercase().as_str() {
+            "csv" => Some(ExportFormat::Csv),
+            "koinly" => Some(ExportFormat::Koinly),
+            "cointracking" => Some(ExportFormat::CoinTracking),
+            _ => None,
+        }
+    }
+}
+
+// One history entry reduced to what the export layouts need
+struct ExportRow<'a> {
+    tx: &'a EtherscanTx,
+    date: String,
+    asset: String,
+    amount: String,
+    fee: Option<String>,
+    counterparty: &'a str,
+    note: String, // set when the amount couldn't be formatted
+}
+
+impl ExportRow<'_> {
+    fn is_fee_only(&self) -> bool {
+        self.fee.is_some() && (self.tx.status == "failed" || self.amount == "0")
+    }
+    // "erc20 to 0x.." with the note, for the description columns of the import layouts
+    fn description(&self) -> String {
+        let sent = self.tx.direction == "sent";
+        let mut text = format!("{} {} {}", self.tx.kind, if sent { "to" } else { "from" }, self.counterparty);
+        if !self.note.is_empty() {
+            text.push_str(&format!(" ({})", self.note));
+        }
+        text
+    }
+}
+
+// Token names and symbols come from the chain, so a cell that a spreadsheet would run as a formula gets a ' prefix
+fn csv_field(field: &str) -> String {
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", field)
+    } else {
+        field.to_string()
+    };
+    if field.contains(',') || field.contains('"') || field.contains('\n') || field.contains('\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+fn csv_line(fields: &[&str]) -> String {
+    fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",")
+}
+
+// Builds the export rows. Amounts use the token's decimals, a failed transaction moves nothing but
+// still pays its fee, and each fee is charged once per hash (on the native row when there is one).
+fn export_rows<'a>(entries: &'a [EtherscanTx], chain: &ChainInfo) -> Vec<ExportRow<'a>> {
+    let with_native_sent: std::collections::HashSet<&str> = entries.iter()
+        .filter(|tx| tx.kind == "native" && tx.direction == "sent")
+        .map(|tx| tx.hash.as_str())
+        .collect();
+    let mut charged = std::collections::HashSet::new();
+
+    entries.iter().map(|tx| {
+        let is_token = matches!(tx.kind.as_str(), "erc20" | "erc721" | "erc1155");
+        let asset = if is_token {
+            let symbol = tx.token_symbol.clone().filter(|s| !s.is_empty())
+                .or_else(|| tx.contract_address.clone())
+                .unwrap_or_else(|| "UNKNOWN".to_string());
+            match &tx.token_id {
+                Some(id) => format!("{}#{}", symbol, id),
+                None => symbol,
+            }
+        } else {
+            chain.native_symbol.clone()
+        };
+        // NFT amounts are counts, an ERC20 without decimals is left blank rather than exported as whole tokens
+        let decimals = match tx.kind.as_str() {
+            "erc20" => tx.token_decimals,
+            "erc721" | "erc1155" => Some(0),
+            _ => Some(chain.native_decimals),
+        };
+        let raw = U256::from_dec_str(&tx.value_wei).unwrap_or_default();
+        let (amount, note) = match decimals {
+            _ if tx.status == "failed" => ("0".to_string(), String::new()),
+            Some(d) => (format_units(raw, d), String::new()),
+            None => (String::new(), format!("unknown decimals, raw amount {}", raw)),
+        };
+
+        let fee_wei = U256::from_dec_str(&tx.fee_wei).unwrap_or_default();
+        let pays_fee = tx.direction == "sent" && !fee_wei.is_zero()
+            && (tx.kind == "native" || !with_native_sent.contains(tx.hash.as_str()))
+            && charged.insert(tx.hash.as_str());
+        let fee = if pays_fee { Some(format_units(fee_wei, chain.native_decimals)) } else { None };
+
+        ExportRow {
+            tx,
+            date: tx.timestamp.parse::<i64>().map(format_rfc3339).unwrap_or_default(),
+            asset,
+            amount,
+            fee,
+            counterparty: if tx.direction == "sent" { &tx.to_address } else { &tx.from_address },
+            note,
+        }
+    }).collect()
+}
+
+// Renders the history as CSV in the given layout
+pub fn export_history(entries: &[EtherscanTx], format: ExportFormat, chain: &ChainInfo) -> String {
+    let rows = export_rows(entries, chain);
+    let native = chain.native_symbol.as_str();
+    let mut lines = Vec::with_capacity(rows.len() + 1);
+    match format {
+        ExportFormat::Csv => {
+            lines.push("date,tx_hash,block_number,kind,direction,counterparty,asset,contract_address,token_id,amount,fee,fee_currency,status,note".to_string());
+            for r in &rows {
+                lines.push(csv_line(&[&r.date, &r.tx.hash, &r.tx.block_number, &r.tx.kind, &r.tx.direction, r.counterparty, &r.asset,
+                    r.tx.contract_address.as_deref().unwrap_or(""), r.tx.token_id.as_deref().unwrap_or(""), &r.amount,
+                    r.fee.as_deref().unwrap_or(""), if r.fee.is_some() { native } else { "" }, &r.tx.status, &r.note]));
+            }
+        }
+        ExportFormat::Koinly => {
+            lines.push("Date,Sent Amount,Sent Currency,Received Amount,Received Currency,Fee Amount,Fee Currency,Net Worth Amount,Net Worth Currency,Label,Description,TxHash".to_string());
+            for r in rows.iter().filter(|r| r.tx.status != "failed" || r.fee.is_some()) {
+                let sent = r.tx.direction == "sent";
+                let description = r.description();
+                // A failed or zero value transaction is only its fee, which Koinly takes as a sent "cost"
+                let line = match &r.fee {
+                    Some(fee) if r.is_fee_only() => csv_line(&[&r.date, fee, native, "", "", "", "", "", "", "cost", &description, &r.tx.hash]),
+                    fee => {
+                        let (sent_amount, sent_currency) = if sent { (r.amount.as_str(), r.asset.as_str()) } else { ("", "") };
+                        let (received_amount, received_currency) = if sent { ("", "") } else { (r.amount.as_str(), r.asset.as_str()) };
+                        csv_line(&[&r.date, sent_amount, sent_currency, received_amount, received_currency,
+                            fee.as_deref().unwrap_or(""), if fee.is_some() { native } else { "" }, "", "", "", &description, &r.tx.hash])
+                    }
+                };
+                lines.push(line);
+            }
+        }
+        ExportFormat::CoinTracking => {
+            lines.push("Type,Buy Amount,Buy Currency,Sell Amount,Sell Currency,Fee,Fee Currency,Exchange,Trade-Group,Comment,Date,Tx-ID".to_string());
+            for r in rows.iter().filter(|r| r.tx.status != "failed" || r.fee.is_some()) {
+                let sent = r.tx.direction == "sent";
+                let comment = r.description();
+                let line = match &r.fee {
+                    Some(fee) if r.is_fee_only() => csv_line(&["Other Fee", "", "", fee, native, "", "", &chain.name, "", &comment, &r.date, &r.tx.hash]),
+                    fee => {
+                        let kind = if sent { "Withdrawal" } else { "Deposit" };
+                        let (buy_amount, buy_currency) = if sent { ("", "") } else { (r.amount.as_str(), r.asset.as_str()) };
+                        let (sell_amount, sell_currency) = if sent { (r.amount.as_str(), r.asset.as_str()) } else { ("", "") };
+                        csv_line(&[kind, buy_amount, buy_currency, sell_amount, sell_currency,
+                            fee.as_deref().unwrap_or(""), if fee.is_some() { native } else { "" }, &chain.name, "", &comment, &r.date, &r.tx.hash])
+                    }
+                };
+                lines.push(line);
+            }
+        }
+    }
+    lines.join("\n") + "\n"
+}
+
+// Explorer account actions merged into the history, with the kind their rows get
+#[cfg(feature = "rpc")]
+const EXPLORER_HISTORY_ACTIONS: [(&str, &str); 5] = [
+    ("txlist", "native"),
+    ("txlistinternal", "internal"),
+    ("tokentx", "erc20"),
+    ("tokennfttx", "erc721"),
+    ("token1155tx", "erc1155"),
+];
+
+// Largest offset (rows per request) Etherscan style APIs accept
+#[cfg(feature = "rpc")]
+const EXPLORER_MAX_OFFSET: usize = 10_000;
+
+// One page per EXPLORER_HISTORY_ACTIONS entry between the blocks, newest first, with whether it came back full
+#[cfg(feature = "rpc")]
+async fn fetch_history_pages(base_url: &str, api_key: &str, chain: &ChainInfo, address: &str, start_block: u64, end_block: Option<u64>, offset: usize) -> Result<Vec<(Vec<EtherscanTx>, bool)>, String> {
+    let fetches = EXPLORER_HISTORY_ACTIONS.iter().map(|(action, _)| {
+        let mut url = format!("{}?chainid={}&module=account&action={}&address={}&sort=desc&page=1&offset={}&apikey={}",
+            base_url, chain.chain_id, action, address, offset, api_key);
+        if let Some(end) = end_block {
+            url.push_str(&format!("&startblock={}&endblock={}", start_block, end));
+        }
+        fetch_explorer_rows(url)
+    });
+    let results = futures::future::join_all(fetches).await;
+
+    let mut pages = Vec::new();
+    for ((_, kind), rows) in EXPLORER_HISTORY_ACTIONS.iter().zip(results) {
+        let rows = rows?;
+        let full = rows.len() >= offset;
+        pages.push((rows.iter().map(|row| etherscan_tx(row, kind, address, chain.native_decimals)).collect(), full));
+    }
+    Ok(pages)
+}
+
+// Rows of an Etherscan style account query, "No transactions found" is an empty list
+#[cfg(feature = "rpc")]
+async fn fetch_explorer_rows(url: String) -> Result<Vec<Value>, String> {
+    let response = match Client::new().get(&url).send().await {
+        Ok(r) if r.status().is_success() => r.text().await.unwrap_or_default(),
+        _ => return Err("Error: Failed to fetch transactions from API.".to_string()),
+    };
+    let json: Value = serde_json::from_str(&response).map_err(|_| "Error: Failed to deserialize.".to_string())?;
+    match (json.get("status").and_then(|s| s.as_str()), json.get("result")) {
+        (Some("1"), Some(Value::Array(rows))) => Ok(rows.clone()),
+        (_, Some(Value::Array(rows))) if rows.is_empty() => Ok(Vec::new()),
+        _ => Err("Error: API returned error status.".to_string()),
+    }
+}
+
+// One explorer row as an EtherscanTx of the given kind
+pub fn etherscan_tx(row: &Value, kind: &str, address: &str, native_decimals: u8) -> EtherscanTx {
+    let field = |name: &str| row.get(name).and_then(|v| v.as_str()).unwrap_or_default().to_string();
+    let from = row.get("from").and_then(|f| f.as_str()).unwrap_or("N/A");
+    let direction = if from.to_lowercase() == address.to_lowercase() { "sent" } else { "received" };
+    let is_token = matches!(kind, "erc20" | "erc721" | "erc1155");
+
+    let value_wei = match kind {
+        "erc721" => "1".to_string(),
+        "erc1155" => field("tokenValue"),
+        _ => field("value"),
+    };
+    let value_wei = if value_wei.is_empty() { "0".to_string() } else { value_wei };
+    let value_eth = if is_token {
+        0.0
+    } else {
+        U256::from_dec_str(&value_wei).ok()
+            .and_then(|v| format_units(v, native_decimals).parse().ok())
+            .unwrap_or(0.0)
+    };
+    let gas_used = field("gasUsed");
+    let fee_wei = match (U256::from_dec_str(&gas_used), U256::from_dec_str(&field("gasPrice"))) {
+        (Ok(used), Ok(price)) if kind != "internal" => (used * price).to_string(),
+        _ => "0".to_string(),
+    };
+    let optional = |name: &str| Some(field(name)).filter(|v| !v.is_empty());
+
+    EtherscanTx {
+        hash: row.get("hash").and_then(|h| h.as_str()).unwrap_or("N/A").to_string(),
+        direction: direction.to_string(),
+        from_address: from.to_string(),
+        to_address: row.get("to").and_then(|t| t.as_str()).unwrap_or("N/A").to_string(),
+        value_eth,
+        value_wei,
+        timestamp: row.get("timeStamp").and_then(|ts| ts.as_str()).unwrap_or("0").to_string(),
+        block_number: row.get("blockNumber").and_then(|bn| bn.as_str()).unwrap_or("0").to_string(),
+        kind: kind.to_string(),
+        contract_address: if is_token || kind == "native" { optional("contractAddress") } else { None },
+        token_symbol: if is_token { optional("tokenSymbol") } else { None },
+        token_decimals: if is_token { field("tokenDecimal").parse().ok() } else { None },
+        token_id: if kind == "erc721" || kind == "erc1155" { optional("tokenID") } else { None },
+        gas_used,
+        fee_wei,
+        status: if field("isError") == "1" { "failed" } else { "success" }.to_string(),
+        counterparty_label: None,
+    }
+}
+
+// Merges the per-action pages (entries newest first and whether the page was full) into one page.
+// A full page may stop part way through its oldest block, so only blocks above that are complete;
+// whole blocks are kept so the returned cursor (the next end block) never splits one.
+// Err(block) when every row of a full page sits in that one block, which then has to be fetched on its own.
+pub fn merge_history_pages(pages: Vec<(Vec<EtherscanTx>, bool)>, limit: usize) -> Result<(Vec<EtherscanTx>, Option<u64>), u64> {
+    let block = |tx: &EtherscanTx| tx.block_number.parse::<u64>().unwrap_or(0);
+    let complete_above = pages.iter()
+        .filter(|(entries, full)| *full && !entries.is_empty())
+        .filter_map(|(entries, _)| entries.last().map(block))
+        .max();
+    let mut entries: Vec<EtherscanTx> = pages.into_iter().flat_map(|(entries, _)| entries).collect();
+    entries.sort_by(|a, b| block(b).cmp(&block(a)).then_with(|| b.timestamp.cmp(&a.timestamp)));
+
+    let mut next_cursor = None;
+    if let Some(boundary) = complete_above {
+        if entries.iter().any(|tx| block(tx) > boundary) {
+            entries.retain(|tx| block(tx) > boundary);
+            next_cursor = Some(boundary);
+        } else {
+            // The full page may have cut this block short, stepping past it would lose the rest
+            return Err(boundary);
+        }
+    }
+    if entries.len() > limit {
+        let last_block = block(&entries[limit - 1]);
+        if entries.iter().any(|tx| block(tx) < last_block) {
+            entries.retain(|tx| block(tx) >= last_block);
+            next_cursor = last_block.checked_sub(1);
+        }
+    }
+    Ok((entries, next_cursor))
+}
+// Formats a 32 byte digest into the device payload "{hex-digest}:&{base64(digest||derivation)}"
+pub fn digest_payload(digest: &[u8; 32], account_derivation_path: &str) -> String {
+    let mut to_sign = digest.to_vec();
+    match extract_u16s(account_derivation_path) {
+        Ok((h1, h2)) => append_integers_as_bytes(&mut to_sign, h1, h2),
+        Err(_) => return "Error: Derivation path error.".to_string(),
+    }
+    format!("{}:&{}", hex::encode(digest), base64::encode(&to_sign))
+}
+
+// Parses a JSON number, decimal string or 0x hex string into a U256
+pub fn parse_u256_value(value: &Value) -> Option<U256> {
+    match value {
+        Value::Number(n) => n.as_u64().map(U256::from),
+        Value::String(s) => {
+            if let Some(h) = s.strip_prefix("0x") {
+                U256::from_str_radix(h, 16).ok()
+            } else {
+                U256::from_dec_str(s).ok()
+            }
+        }
+        Value::Bool(b) => Some(U256::from(*b as u8)),
+        _ => None,
+    }
+}
+
+// eth-sig-util's SignTypedDataVersion, only the object based versions go through typed_data_digest
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TypedDataVersion {
+    V3,
+    V4,
+}
+
+// keccak256(0x19 0x01 || domainSeparator || hashStruct(message)) following eth-sig-util's v3/v4 rules
+pub fn typed_data_digest(typed: &TypedData, version: TypedDataVersion) -> Result<[u8; 32], String> {
+    let mut input = vec![0x19, 0x01];
+    input.extend(typed.domain.separator());
+    if typed.primary_type != "EIP712Domain" {
+        let message = Value::Object(typed.message.clone().into_iter().collect());
+        input.extend(keccak256(encode_typed_struct(&typed.types, &typed.primary_type, &message, version)?));
+    }
+    Ok(keccak256(input))
+}
+
+// typeHash || enc(member) for each member. v3 leaves out members that are missing, v4 requires them
+// except for nested structs, which it hashes as a zero word when missing or null
+fn encode_typed_struct(types: &Types, ty: &str, data: &Value, version: TypedDataVersion) -> Result<Vec<u8>, String> {
+    let mut out = hash_type(ty, types)
+        .map_err(|_| format!("Error: Failed to encode EIP-712 type {}.", ty))?
+        .to_vec();
+    let fields = types.get(ty).ok_or(format!("Error: No EIP-712 type definition for {}.", ty))?;
+    for field in fields {
+        let value = data.get(&field.name);
+        if version == TypedDataVersion::V3 && value.is_none() {
+            continue;
+        }
+        out.extend(encode_typed_field(types, &field.name, &field.r#type, value, version)?);
+    }
+    Ok(out)
+}
+
+fn encode_typed_field(
+    types: &Types,
+    name: &str,
+    ty: &str,
+    value: Option<&Value>,
+    version: TypedDataVersion,
+) -> Result<[u8; 32], String> {
+    if types.contains_key(ty) {
+        return match (version, value) {
+            (TypedDataVersion::V4, None) | (TypedDataVersion::V4, Some(Value::Null)) => Ok([0u8; 32]),
+            // v3 encodes a null struct as one with every member left out
+            _ => Ok(keccak256(encode_typed_struct(types, ty, value.unwrap_or(&Value::Null), version)?)),
+        };
+    }
+    let value = value.ok_or(format!("Error: Missing EIP-712 value for {}.", name))?;
+    if let Some((inner, _)) = ty.strip_suffix(']').and_then(|t| t.rsplit_once('[')) {
+        if version == TypedDataVersion::V3 {
+            return Err("Error: Arrays are not supported by eth_signTypedData_v3.".to_string());
+        }
+        let items = value.as_array().ok_or(format!("Error: Expected an array for {}.", name))?;
+        let mut encoded = Vec::with_capacity(items.len() * 32);
+        for item in items {
+            encoded.extend(encode_typed_field(types, name, inner, Some(item), version)?);
+        }
+        return Ok(keccak256(encoded));
+    }
+    let token = encode_field(types, name, ty, value)
+        .map_err(|_| format!("Error: Failed to encode EIP-712 value for {}.", name))?;
+    let mut word = [0u8; 32];
+    word.copy_from_slice(&ethers_core::abi::encode(&[token]));
+    Ok(word)
+}
+
+// Legacy typed data hash: keccak256(keccak256(schema) || keccak256(solidityPack(values)))
+pub fn typed_data_v1_digest(fields: &[Value]) -> Result<[u8; 32], String> {
+    if fields.is_empty() {
+        return Err("Error: Typed data is empty.".to_string());
+    }
+    let mut schema = Vec::new();
+    let mut values = Vec::new();
+    for field in fields {
+        let ty = field["type"].as_str().ok_or("Error: Typed data field is missing a type.")?;
+        let name = field["name"].as_str().ok_or("Error: Typed data field is missing a name.")?;
+        schema.extend(format!("{} {}", ty, name).into_bytes());
+        values.extend(solidity_pack_value(ty, &field["value"])?);
+    }
+    let mut outer = keccak256(&schema).to_vec();
+    outer.extend(keccak256(&values));
+    Ok(keccak256(&outer))
+}
+
+// Tightly packs a single value the way ethereumjs-abi solidityPack does. Array elements are packed as if they
+// were 256 bits wide: numbers, bools and addresses take a 32 byte word, string, bytes and bytesN stay unpadded
+fn solidity_pack_value(ty: &str, value: &Value) -> Result<Vec<u8>, String> {
+    if let Some((inner, size)) = ty.strip_suffix(']').and_then(|t| t.rsplit_once('[')) {
+        let items = value.as_array().ok_or(format!("Error: Expected an array for {}.", ty))?;
+        if size.parse::<usize>().map(|n| items.len() > n).unwrap_or(false) {
+            return Err(format!("Error: Too many values for {}.", ty));
+        }
+        let elem = if inner.starts_with("uint") {
+            "uint256"
+        } else if inner.starts_with("int") {
+            "int256"
+        } else {
+            inner
+        };
+        let mut out = Vec::new();
+        for item in items {
+            let packed = solidity_pack_value(elem, item)?;
+            if elem == "bool" || elem == "address" {
+                out.extend(vec![0u8; 32 - packed.len()]);
+            }
+            out.extend(packed);
+        }
+        return Ok(out);
+    }
+    let err = || format!("Error: Failed to encode typed data value for {}.", ty);
+    match ty {
+        "string" => Ok(value.as_str().ok_or_else(err)?.as_bytes().to_vec()),
+        "bytes" => hex::decode(value.as_str().ok_or_else(err)?.trim_start_matches("0x")).map_err(|_| err()),
+        "bool" => Ok(vec![value.as_bool().ok_or_else(err)? as u8]),
+        "address" => {
+            let addr = Address::from_str(value.as_str().ok_or_else(err)?).map_err(|_| err())?;
+            Ok(addr.as_bytes().to_vec())
+        }
+        _ if ty.starts_with("uint") || ty.starts_with("int") => {
+            let signed = ty.starts_with("int");
+            let bits: usize = ty.trim_start_matches(|c: char| c.is_alphabetic()).parse().unwrap_or(256);
+            if bits == 0 || bits > 256 || !bits.is_multiple_of(8) {
+                return Err(err());
+            }
+            let (negative, magnitude) = match value.as_str().and_then(|s| s.strip_prefix('-')) {
+                Some(rest) => (true, rest.to_string()),
+                None => (false, value.as_str().map(|s| s.to_string()).unwrap_or_default()),
+            };
+            let num = if negative {
+                U256::from_dec_str(&magnitude).map_err(|_| err())?.overflowing_neg().0
+            } else if let Some(n) = value.as_i64().filter(|n| signed && *n < 0) {
+                U256::from(n.unsigned_abs()).overflowing_neg().0
+            } else {
+                parse_u256_value(value).ok_or_else(err)?
+            };
+            let mut word = [0u8; 32];
+            num.to_big_endian(&mut word);
+            Ok(word[32 - bits / 8..].to_vec())
+        }
+        _ if ty.starts_with("bytes") => {
+            let size: usize = ty[5..].parse().map_err(|_| err())?;
+            let mut raw = hex::decode(value.as_str().ok_or_else(err)?.trim_start_matches("0x")).map_err(|_| err())?;
+            if size == 0 || size > 32 || raw.len() > size {
+                return Err(err());
+            }
+            raw.resize(size, 0);
+            Ok(raw)
+        }
+        _ => Err(format!("Error: Unsupported typed data type {}.", ty)),
+    }
+}
+
+// Personal sign messages arrive either as 0x hex or as plain text
+fn message_text(message: &str) -> Option<String> {
+    match message.strip_prefix("0x").and_then(|h| hex::decode(h).ok()) {
+        Some(bytes) => String::from_utf8(bytes).ok(),
+        None => Some(message.to_string()),
+    }
+}
+
+// EIP-191 personal message: "\x19Ethereum Signed Message:\n" || len || message
+pub fn eip191_bytes(msg: &[u8]) -> Vec<u8> {
+    let mut out = format!("\x19Ethereum Signed Message:\n{}", msg.len()).into_bytes();
+    out.extend(msg);
+    out
+}
+
+// Decodes a 65 byte r||s||v signature given as 0x hex or base64 (as returned by the device)
+pub fn decode_signature_bytes(signature: &str) -> Result<Vec<u8>, String> {
+    let sig = signature.trim();
+    let bytes = match sig.strip_prefix("0x") {
+        Some(h) => hex::decode(h).map_err(|_| "Error: Failed to decode the signature.".to_string())?,
+        None => match hex::decode(sig) {
+            Ok(b) if sig.len() == 130 => b,
+            _ => base64::decode(sig).map_err(|_| "Error: Failed to decode the signature.".to_string())?,
+        },
+    };
+    if bytes.len() != 65 {
+        return Err("Error: Signature must be 65 bytes.".to_string());
+    }
+    Ok(bytes)
+}
+
+// Recovers the signing address (lowercase 0x hex) of a 32 byte digest, the signature can also be EIP-2098 compact
+pub fn recover_signer(digest: &[u8; 32], signature: &str) -> Result<String, String> {
+    let parsed = EvmSignature::parse(signature.to_string()).ok_or("Error: Failed to decode the signature.")?;
+    let sig = ethers_core::types::Signature::try_from(&parsed.bytes_with_v(parsed.v())[..])
+        .map_err(|_| "Error: Failed to decode the signature.".to_string())?;
+    let hash = ethers_core::types::H256::from_slice(digest);
+    match sig.recover(hash) {
+        Ok(addr) => Ok(format!("0x{}", hex::encode(addr.as_bytes()))),
+        Err(_) => Err("Error: Failed to recover the signer.".to_string()),
+    }
+}
+
+// Parses an RFC 3339 / ISO-8601 timestamp such as "2021-09-30T16:25:24.000Z" into unix seconds
+pub fn parse_rfc3339(ts: &str) -> Option<i64> {
+    let ts = ts.trim();
+    if ts.len() < 20 || !ts.is_char_boundary(19) {
+        return None;
+    }
+    let (date_time, zone) = ts.split_at(19);
+    let b = date_time.as_bytes();
+    if b[4] != b'-' || b[7] != b'-' || (b[10] != b'T' && b[10] != b't' && b[10] != b' ') || b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let num = |r: std::ops::Range<usize>| date_time.get(r)?.parse::<i64>().ok();
+    let (year, month, day) = (num(0..4)?, num(5..7)?, num(8..10)?);
+    let (hour, minute, second) = (num(11..13)?, num(14..16)?, num(17..19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    // Skip fractional seconds, then read the offset
+    let zone = match zone.strip_prefix('.') {
+        Some(rest) => rest.trim_start_matches(|c: char| c.is_ascii_digit()),
+        None => zone,
+    };
+    let offset = match zone {
+        "Z" | "z" => 0,
+        _ if zone.len() == 6 && zone.as_bytes()[3] == b':' => {
+            let sign = match &zone[..1] { "+" => 1, "-" => -1, _ => return None };
+            sign * (zone[1..3].parse::<i64>().ok()? * 3600 + zone[4..6].parse::<i64>().ok()? * 60)
+        }
+        _ => return None,
+    };
+    Some(days_from_civil(year, month, day) * 86_400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+// Days since 1970-01-01 for a proleptic Gregorian date (Howard Hinnant's algorithm)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Inverse of days_from_civil, days since 1970-01-01 to (year, month, day)
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+// Unix seconds as an ISO-8601 UTC timestamp such as "2024-01-01T00:00:00Z"
+pub fn format_rfc3339(ts: i64) -> String {
+    let (year, month, day) = civil_from_days(ts.div_euclid(86_400));
+    let secs = ts.rem_euclid(86_400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+// Reduces an origin or SIWE domain like "https://app.example.com/path" to "app.example.com"
+fn origin_authority(origin: &str) -> String {
+    let no_scheme = match origin.find("://") {
+        Some(i) => &origin[i + 3..],
+        None => origin,
+    };
+    no_scheme.split('/').next().unwrap_or("").to_lowercase()
+}
 
-        //let raw_hex = format!("0x{}", hex::encode(raw_tx));
-        // 👉 DEBUG: print (or even return) the fully signed RLP so you can inspect it
-        //println!("DEBUG signed_raw_tx: {}", raw_hex);
-        //return raw_hex;   // <— you can early‐return here for testing
-        // 6) broadcast
-        let client = Client::new();
-        let body = json!({
-            "jsonrpc": "2.0",
-            "method": "eth_sendRawTransaction",
-            "params": [ raw_hex ],
-            "id": 1
-        });
-        if let Ok(resp) = client.post(&self.infura_url).json(&body).send().await {
-            if let Ok(j) = resp.json::<serde_json::Value>().await {
-                if let Some(r) = j.get("result").and_then(|r| r.as_str()) {
-                    self.nonce = self.nonce + 1;
-                    return r.to_string();
-                }
-                if let Some(e) = j.get("error") {
-                    return format!("Error: {:?}", e);
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SiweMessage {
+    pub domain: String,
+    pub address: String,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    pub chain_id: u64,
+    pub nonce: String,
+    pub issued_at: String,
+    pub expiration_time: Option<String>,
+    pub not_before: Option<String>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SiweWarning {
+    pub code: String,
+    pub message: String,
+}
+
+impl SiweMessage {
+    const HEADER: &'static str = " wants you to sign in with your Ethereum account:";
+
+    pub fn parse(text: &str) -> Result<SiweMessage, String> {
+        let mut lines = text.lines();
+        let domain = match lines.next().and_then(|l| l.strip_suffix(Self::HEADER)) {
+            Some(d) if !d.is_empty() => d.to_string(),
+            _ => return Err("Error: Not a SIWE message.".to_string()),
+        };
+        let address = match lines.next() {
+            Some(a) if a.len() == 42 && a.starts_with("0x") && hex::decode(&a[2..]).is_ok() => a.to_string(),
+            _ => return Err("Error: SIWE message has an invalid address.".to_string()),
+        };
+
+        let mut siwe = SiweMessage {
+            domain,
+            address,
+            statement: None,
+            uri: String::new(),
+            version: String::new(),
+            chain_id: 0,
+            nonce: String::new(),
+            issued_at: String::new(),
+            expiration_time: None,
+            not_before: None,
+            request_id: None,
+            resources: Vec::new(),
+        };
+        let mut in_resources = false;
+        for line in lines {
+            if in_resources {
+                if let Some(r) = line.strip_prefix("- ") {
+                    siwe.resources.push(r.to_string());
+                    continue;
                 }
+                in_resources = false;
+            }
+            if let Some(v) = line.strip_prefix("URI: ") {
+                siwe.uri = v.to_string();
+            } else if let Some(v) = line.strip_prefix("Version: ") {
+                siwe.version = v.to_string();
+            } else if let Some(v) = line.strip_prefix("Chain ID: ") {
+                siwe.chain_id = v.trim().parse().map_err(|_| "Error: SIWE message has an invalid chain ID.".to_string())?;
+            } else if let Some(v) = line.strip_prefix("Nonce: ") {
+                siwe.nonce = v.to_string();
+            } else if let Some(v) = line.strip_prefix("Issued At: ") {
+                siwe.issued_at = v.to_string();
+            } else if let Some(v) = line.strip_prefix("Expiration Time: ") {
+                siwe.expiration_time = Some(v.to_string());
+            } else if let Some(v) = line.strip_prefix("Not Before: ") {
+                siwe.not_before = Some(v.to_string());
+            } else if let Some(v) = line.strip_prefix("Request ID: ") {
+                siwe.request_id = Some(v.to_string());
+            } else if line == "Resources:" {
+                in_resources = true;
+            } else if !line.is_empty() && siwe.uri.is_empty() {
+                // Anything between the address and the URI is the statement
+                siwe.statement = Some(match siwe.statement.take() {
+                    Some(s) => format!("{}\n{}", s, line),
+                    None => line.to_string(),
+                });
             }
         }
-        return "Error: Failed to broadcast transaction.".to_string()
+
+        if siwe.uri.is_empty() || siwe.version.is_empty() || siwe.nonce.is_empty() || siwe.issued_at.is_empty() || siwe.chain_id == 0 {
+            return Err("Error: SIWE message is missing a required field.".to_string());
+        }
+        Ok(siwe)
     }
-    pub async fn validate_contract(&mut self, contract_address: String) -> String {
-        let url = self.infura_url.clone();
-        let client = reqwest::Client::new();
 
-        // Batch JSON-RPC requests for decimals, symbol, and name.
-        let batch_request = json!([
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_call",
-                "params": [{
-                    "to": contract_address.clone(),
-                    "data": "0x313ce567" // decimals()
-                }, "latest"],
-                "id": 1
-            },
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_call",
-                "params": [{
-                    "to": contract_address.clone(),
-                    "data": "0x95d89b41" // symbol()
-                }, "latest"],
-                "id": 2
-            },
-            {
-                "jsonrpc": "2.0",
-                "method": "eth_call",
-                "params": [{
-                    "to": contract_address.clone(),
-                    "data": "0x06fdde03" // name()
-                }, "latest"],
-                "id": 3
+    pub fn warnings(&self, wallet_address: &str, chain_id: u64, origin: &str, now: u64) -> Vec<SiweWarning> {
+        let mut warnings = Vec::new();
+        let mut warn = |code: &str, message: String| warnings.push(SiweWarning { code: code.to_string(), message });
+
+        if origin_authority(&self.domain) != origin_authority(origin) {
+            warn("domain_mismatch", format!("Message domain {} does not match the requesting origin {}.", self.domain, origin));
+        }
+        if !self.address.eq_ignore_ascii_case(wallet_address) {
+            warn("address_mismatch", format!("Message is for {} but this wallet is {}.", self.address, wallet_address));
+        }
+        if self.chain_id != chain_id {
+            warn("wrong_chain", format!("Message is for chain {} but the wallet is on chain {}.", self.chain_id, chain_id));
+        }
+        if self.version != "1" {
+            warn("unsupported_version", format!("Unsupported SIWE version {}.", self.version));
+        }
+        if self.nonce.len() < 8 || !self.nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+            warn("invalid_nonce", "Nonce must be at least 8 alphanumeric characters.".to_string());
+        }
+        let now = now as i64;
+        match parse_rfc3339(&self.issued_at) {
+            Some(t) if t > now + 300 => warn("issued_in_future", format!("Message was issued in the future ({}).", self.issued_at)),
+            Some(_) => {}
+            None => warn("invalid_timestamp", format!("Invalid issued at time {}.", self.issued_at)),
+        }
+        if let Some(exp) = &self.expiration_time {
+            match parse_rfc3339(exp) {
+                Some(t) if t <= now => warn("expired", format!("Message expired at {}.", exp)),
+                Some(_) => {}
+                None => warn("invalid_timestamp", format!("Invalid expiration time {}.", exp)),
             }
-        ]);
+        }
+        if let Some(nbf) = &self.not_before {
+            match parse_rfc3339(nbf) {
+                Some(t) if t > now => warn("not_yet_valid", format!("Message is not valid before {}.", nbf)),
+                Some(_) => {}
+                None => warn("invalid_timestamp", format!("Invalid not before time {}.", nbf)),
+            }
+        }
+        warnings
+    }
+}
 
-        let response = match client
-            .post(&url)
-            .header(CONTENT_TYPE, "application/json")
-            .json(&batch_request)
-            .send()
-            .await
-        {
-            Ok(resp) => resp,
-            Err(_) => return "Error: Infura error during batch request.".to_string(),
-        };
+// Raw bytes of a personal sign message given as 0x hex or plain text
+#[cfg(feature = "rpc")]
+fn message_bytes(message: &str) -> Vec<u8> {
+    match message.strip_prefix("0x").and_then(|h| hex::decode(h).ok()) {
+        Some(bytes) => bytes,
+        None => message.as_bytes().to_vec(),
+    }
+}
+
+// Signatures for contract wallets can be any length, so only decode 0x hex or base64
+fn decode_hex_or_b64(input: &str) -> Option<Vec<u8>> {
+    let s = input.trim();
+    match s.strip_prefix("0x") {
+        Some(h) => hex::decode(h).ok(),
+        None => base64::decode(s).ok(),
+    }
+}
+
+// EIP-1271 isValidSignature(bytes32 hash, bytes signature)
+pub fn encode_is_valid_signature(digest: &[u8; 32], signature: &[u8]) -> Vec<u8> {
+    let mut data = hex::decode("1626ba7e").expect("Invalid selector");
+    data.extend_from_slice(digest);
+
+    // offset to the dynamic `bytes signature` (2 * 32 = 64)
+    let mut offset = [0u8; 32];
+    offset[31] = 64;
+    data.extend(&offset);
+
+    let mut len = [0u8; 32];
+    U256::from(signature.len()).to_big_endian(&mut len);
+    data.extend(&len);
+    data.extend_from_slice(signature);
+    data.resize(data.len() + (32 - signature.len() % 32) % 32, 0);
+
+    data
+}
+
+// Single JSON-RPC request, returns the "result" field or the error as an "Error: .." string
+#[cfg(feature = "rpc")]
+async fn rpc_call(url: &str, method: &str, params: Value) -> Result<Value, String> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": 1
+    });
+    let resp = match Client::new()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .json(&body)
+        .send()
+        .await
+    {
+        Ok(r) if r.status().is_success() => r,
+        _ => return Err("Error: Infura error.".to_string()),
+    };
+    let j: Value = match resp.json().await {
+        Ok(v) => v,
+        Err(_) => return Err("Error: JSON parse error.".to_string()),
+    };
+    if let Some(e) = j.get("error") {
+        return Err(format!("Error: {:?}", e));
+    }
+    match j.get("result") {
+        Some(r) => Ok(r.clone()),
+        None => Err("Error: Unexpected JSON format.".to_string()),
+    }
+}
+
+//Transaction builder helpers, shared by the builders that take explicit fields
+// fee rate 0 = slow, 1 = medium, 2 = fast
+pub fn fee_rate_gas_price(gas_price: U256, fee_rate: i32) -> U256 {
+    match fee_rate {
+        1 => gas_price * U256::from(15) / U256::from(10),
+        2 => gas_price * U256::from(20) / U256::from(10),
+        _ => gas_price,
+    }
+}
+
+// (max_priority_fee_per_gas, max_fee_per_gas) from the synced gas price and priority fee
+pub fn eip1559_fees(gas_price: &str, max_priority_fee_per_gas: &str, fee_rate: i32) -> (U256, U256) {
+    let mut pri = gas_price_from_string(max_priority_fee_per_gas);
+    if pri.is_zero() {
+        pri = U256::from(2_500_000_000u64);
+    }
+    let max_fee = fee_rate_gas_price(gas_price_from_string(gas_price), fee_rate) + pri;
+    (pri, max_fee)
+}
+
+// RLP encode [nonce, gasPrice, gasLimit, to, value, data, chain_id, 0, 0] and return "unsignedRlpHex:&base64(hash||derivation)".
+// A `to` of None is a contract creation.
+#[allow(clippy::too_many_arguments)]
+pub fn build_legacy_tx(nonce: U256, gas_price: U256, gas_limit: U256, to: Option<Address>, value: U256, data: &[u8], chain_id: u64, account_derivation_path: &str) -> String {
+    let mut stream = RlpStream::new_list(9);
+    stream.append(&nonce);
+    stream.append(&gas_price);
+    stream.append(&gas_limit);
+    match &to {
+        Some(a) => stream.append(a),
+        None => stream.append_empty_data(),
+    };
+    stream.append(&value);
+    stream.append(&data.to_vec());
+    stream.append(&chain_id);
+    stream.append(&0u8);
+    stream.append(&0u8);
+    let rlp_encoded = stream.out();
+
+    let tx_hash = keccak256(&rlp_encoded);
+    let mut total_bytes = tx_hash.to_vec();
+    match extract_u16s(account_derivation_path) {
+        Ok((first, second)) => append_integers_as_bytes(&mut total_bytes, first, second),
+        Err(_) => return "Error: Derivation path error.".to_string(),
+    }
+    format!("{}:&{}", hex::encode(rlp_encoded), base64::encode(&total_bytes))
+}
+
+// RLP encode [chain_id, nonce, maxPri, maxFee, gasLimit, to, value, data, []] and return "unsignedRlpHex:&base64(hash||derivation)".
+// A `to` of None is a contract creation.
+#[allow(clippy::too_many_arguments)]
+pub fn build_eip1559_tx(chain_id: u64, nonce: U256, max_priority_fee_per_gas: U256, max_fee_per_gas: U256, gas_limit: U256, to: Option<Address>, value: U256, data: &[u8], account_derivation_path: &str) -> String {
+    let mut stream = RlpStream::new_list(9);
+    stream.append(&U256::from(chain_id));
+    stream.append(&nonce);
+    stream.append(&max_priority_fee_per_gas);
+    stream.append(&max_fee_per_gas);
+    stream.append(&gas_limit);
+    match &to {
+        Some(a) => stream.append(a),
+        None => stream.append_empty_data(),
+    };
+    stream.append(&value);
+    stream.append(&data.to_vec());
+    stream.begin_list(0);
+    let rlp_payload = stream.out().to_vec();
+
+    // keccak256(0x02 || rlp_payload)
+    let mut typed = vec![0x02];
+    typed.extend(&rlp_payload);
+    let sign_hash = keccak256(&typed);
+    let mut to_sign = sign_hash.to_vec();
+    match extract_u16s(account_derivation_path) {
+        Ok((h1, h2)) => append_integers_as_bytes(&mut to_sign, h1, h2),
+        Err(_) => return "Error: Derivation path error.".to_string(),
+    }
+    format!("{}:&{}", hex::encode(rlp_payload), base64::encode(&to_sign))
+}
+
+// Function signatures the decoder recognises, keyed by selector
+const KNOWN_SELECTORS: [(&str, &str); 8] = [
+    ("a9059cbb", "transfer(address,uint256)"),
+    ("23b872dd", "transferFrom(address,address,uint256)"),
+    ("095ea7b3", "approve(address,uint256)"),
+    ("42842e0e", "safeTransferFrom(address,address,uint256)"),
+    ("b88d4fde", "safeTransferFrom(address,address,uint256,bytes)"),
+    ("a22cb465", "setApprovalForAll(address,bool)"),
+    ("f242432a", "safeTransferFrom(address,address,uint256,uint256,bytes)"),
+    ("2eb2c2d6", "safeBatchTransferFrom(address,address,uint256[],uint256[],bytes)"),
+];
+
+// The transaction type (0 or 2) and the RLP list of a payload. The crate's own EIP 1559 payloads have no 0x02
+// prefix, so without one type 2 is told apart by its field count: 12 when signed, or 9 with the access list
+// (a list) in field 8 where an unsigned legacy transaction has an empty s
+pub fn tx_payload_type(tx_bytes: &[u8]) -> (u8, &[u8]) {
+    if tx_bytes.first() == Some(&0x02) {
+        return (2, &tx_bytes[1..]);
+    }
+    let rlp = Rlp::new(tx_bytes);
+    match rlp.item_count() {
+        Ok(12) => (2, tx_bytes),
+        Ok(9) if rlp.at(8).map(|f| f.is_list()).unwrap_or(false) => (2, tx_bytes),
+        _ => (0, tx_bytes),
+    }
+}
+
+// Decodes a legacy or EIP 1559 transaction built by this crate, unsigned or signed. When the base64 half of the
+// payload is given the derivation path is read from it and the hash is checked against the transaction.
+pub fn decode_unsigned_tx(payload: &str) -> Result<Value, String> {
+    let (tx_hex, device_b64) = match payload.trim().split_once(':') {
+        Some((tx, rest)) => (tx, Some(rest.trim_start_matches('&'))),
+        None => (payload.trim(), None),
+    };
+    let tx_bytes = hex::decode(tx_hex.trim_start_matches("0x"))
+        .map_err(|_| "Error: Failed to decode the unsigned transaction.".to_string())?;
+    let (tx_type, rlp_bytes) = tx_payload_type(&tx_bytes);
+    let is_eip1559 = tx_type == 2;
+    let rlp = Rlp::new(rlp_bytes);
+    if !rlp.is_list() {
+        return Err("Error: Failed to decode the unsigned transaction.".to_string());
+    }
+    let u256_at = |idx: usize, name: &str| {
+        rlp.at(idx).and_then(|f| f.as_val::<U256>()).map_err(|_| format!("Error: Failed to decode the {}.", name))
+    };
+    let bytes_at = |idx: usize, name: &str| {
+        rlp.at(idx).and_then(|f| f.data().map(|d| d.to_vec())).map_err(|_| format!("Error: Failed to decode the {}.", name))
+    };
+
+    let mut decoded = if is_eip1559 {
+        json!({
+            "type": 2,
+            "chain_id": u256_at(0, "chain ID")?.low_u64(),
+            "nonce": u256_at(1, "nonce")?.to_string(),
+            "max_priority_fee_per_gas": u256_at(2, "max priority fee per gas")?.to_string(),
+            "max_fee_per_gas": u256_at(3, "max fee per gas")?.to_string(),
+            "gas_limit": u256_at(4, "gas limit")?.to_string(),
+        })
+    } else {
+        // Signed legacy transactions carry the EIP 155 v in place of the chain id
+        let v = u256_at(6, "chain ID")?.low_u64();
+        let signed = !u256_at(7, "signature")?.is_zero();
+        json!({
+            "type": 0,
+            "chain_id": if signed && v >= 35 { (v - 35) / 2 } else if signed { 0 } else { v },
+            "nonce": u256_at(0, "nonce")?.to_string(),
+            "gas_price": u256_at(1, "gas price")?.to_string(),
+            "gas_limit": u256_at(2, "gas limit")?.to_string(),
+        })
+    };
+    let offset = if is_eip1559 { 5 } else { 3 };
+    let to = bytes_at(offset, "output")?;
+    let data = bytes_at(offset + 2, "data field")?;
+    decoded["to"] = if to.is_empty() { Value::Null } else { json!(format!("0x{}", hex::encode(&to))) };
+    decoded["value"] = json!(u256_at(offset + 1, "value")?.to_string());
+    decoded["data"] = json!(format!("0x{}", hex::encode(&data)));
+    decoded["call"] = decode_calldata(&data).unwrap_or(Value::Null);
+
+    let signed = if is_eip1559 { rlp.item_count().unwrap_or(0) == 12 } else { !u256_at(7, "signature")?.is_zero() };
+    // What the device signs: keccak256(rlp) for legacy, keccak256(0x02 || rlp) for type 2
+    let mut typed = if is_eip1559 { vec![0x02] } else { Vec::new() };
+    typed.extend_from_slice(rlp_bytes);
+    decoded["signed"] = json!(signed);
+    if signed {
+        decoded["hash"] = json!(format!("0x{}", hex::encode(keccak256(&typed))));
+        return Ok(decoded);
+    }
+    let sign_hash = keccak256(&typed);
+    decoded["sign_hash"] = json!(format!("0x{}", hex::encode(sign_hash)));
+    if let Some(b64) = device_b64 {
+        let device_bytes = base64::decode(b64).map_err(|_| "Error: Failed to decode the payload hash.".to_string())?;
+        if device_bytes.len() != 36 {
+            return Err("Error: Failed to decode the payload hash.".to_string());
+        }
+        let first = u16::from_le_bytes([device_bytes[32], device_bytes[33]]);
+        let second = u16::from_le_bytes([device_bytes[34], device_bytes[35]]);
+        decoded["derivation_path"] = json!(format!("m/{}/{}", first, second));
+        decoded["hash_matches"] = json!(device_bytes[..32] == sign_hash[..]);
+    }
+    Ok(decoded)
+}
 
-        if !response.status().is_success() {
-            return "Error: Infura error during batch request.".to_string();
+// Decodes calldata for one of the KNOWN_SELECTORS into {"selector","function","args"}
+pub fn decode_calldata(data: &[u8]) -> Option<Value> {
+    use ethabi::Token;
+    if data.len() < 4 { return None; }
+    let selector = hex::encode(&data[..4]);
+    let (_, signature) = KNOWN_SELECTORS.iter().find(|(sel, _)| *sel == selector)?;
+    let arg_types = signature.split_once('(')?.1.trim_end_matches(')');
+    let params = arg_types.split(',')
+        .map(ethabi::param_type::Reader::read)
+        .collect::<Result<Vec<_>, _>>().ok()?;
+    let tokens = ethabi::decode(&params, &data[4..]).ok()?;
+
+    fn token_json(token: Token) -> Value {
+        match token {
+            Token::Address(a) => json!(format!("0x{}", hex::encode(a.as_bytes()))),
+            Token::Uint(v) | Token::Int(v) => json!(v.to_string()),
+            Token::Bool(b) => json!(b),
+            Token::Bytes(b) | Token::FixedBytes(b) => json!(format!("0x{}", hex::encode(b))),
+            Token::String(s) => json!(s),
+            Token::Array(items) | Token::FixedArray(items) | Token::Tuple(items) => Value::Array(items.into_iter().map(token_json).collect()),
         }
+    }
+    Some(json!({
+        "selector": format!("0x{}", selector),
+        "function": signature,
+        "args": tokens.into_iter().map(token_json).collect::<Vec<_>>(),
+    }))
+}
+
+// Init code + constructor args, value and gas limit (decimal strings) for a deployment
+fn parse_deployment(bytecode: &str, constructor_args: &str, value: &str, gas_limit: &str) -> Result<(Vec<u8>, U256, U256), String> {
+    let mut init_code = hex::decode(bytecode.trim().trim_start_matches("0x"))
+        .map_err(|_| "Error: Failed to decode the bytecode.".to_string())?;
+    if init_code.is_empty() {
+        return Err("Error: Bytecode is empty.".to_string());
+    }
+    let args = hex::decode(constructor_args.trim().trim_start_matches("0x"))
+        .map_err(|_| "Error: Failed to decode the constructor arguments.".to_string())?;
+    init_code.extend(args);
+    let value_u256 = if value.trim().is_empty() {
+        U256::zero()
+    } else {
+        U256::from_dec_str(value).map_err(|_| "Error: Failed to parse value.".to_string())?
+    };
+    let gas_limit_u256 = U256::from_dec_str(gas_limit).map_err(|_| "Error: Failed to parse the gas limit.".to_string())?;
+    Ok((init_code, value_u256, gas_limit_u256))
+}
+
+// Recipient, value and gas limit (decimal strings) and calldata (hex) for a contract call
+fn parse_contract_call(to: &str, value: &str, data: &str, gas_limit: &str) -> Result<(Address, U256, Vec<u8>, U256), String> {
+    let to_addr = Address::from_str(to).map_err(|_| "Error: Failed to parse the recipient address.".to_string())?;
+    let value_u256 = if value.trim().is_empty() {
+        U256::zero()
+    } else {
+        U256::from_dec_str(value).map_err(|_| "Error: Failed to parse value.".to_string())?
+    };
+    let data_bytes = hex::decode(data.trim().trim_start_matches("0x"))
+        .map_err(|_| "Error: Failed to decode the data field.".to_string())?;
+    let gas_limit_u256 = U256::from_dec_str(gas_limit).map_err(|_| "Error: Failed to parse the gas limit.".to_string())?;
+    Ok((to_addr, value_u256, data_bytes, gas_limit_u256))
+}
 
-        let body = response.text().await.unwrap();
-        let responses: Value = match serde_json::from_str(&body) {
-            Ok(val) => val,
-            Err(_) => return "Error: JSON parse error during batch request.".to_string(),
+// ABI encodes values (as strings) for solidity types such as "address", "uint256[]" or "(uint256,bool)"
+pub fn encode_abi_args(types: &[String], values: &[String]) -> Result<Vec<u8>, String> {
+    use ethabi::token::{LenientTokenizer, Tokenizer};
+    if types.len() != values.len() {
+        return Err("Error: Number of types and values do not match.".to_string());
+    }
+    let mut tokens = Vec::with_capacity(types.len());
+    for (ty, value) in types.iter().zip(values) {
+        let param = ethabi::param_type::Reader::read(ty).map_err(|_| format!("Error: Unsupported ABI type {}.", ty))?;
+        // The tokenizer expects addresses and bytes without the 0x prefix
+        let cleaned = if (ty.contains("address") || ty.contains("bytes")) && !ty.contains("string") {
+            value.replace("0x", "")
+        } else {
+            value.to_string()
         };
+        let token = LenientTokenizer::tokenize(&param, &cleaned).map_err(|_| format!("Error: Failed to encode {} as {}.", value, ty))?;
+        tokens.push(token);
+    }
+    Ok(ethabi::encode(&tokens))
+}
+
+// CREATE address: keccak256(rlp([sender, nonce]))[12..]
+pub fn create_address(sender: &str, nonce: u64) -> String {
+    let sender_addr = match Address::from_str(sender) {
+        Ok(a) => a,
+        Err(_) => return "Error: Failed to parse the sender address.".to_string(),
+    };
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&sender_addr);
+    stream.append(&nonce);
+    let hash = keccak256(stream.out());
+    format!("0x{}", hex::encode(&hash[12..]))
+}
+
+// CREATE2 address: keccak256(0xff || factory || salt || keccak256(init_code))[12..]
+pub fn create2_address(factory: &str, salt: &str, init_code_hash: &[u8; 32]) -> String {
+    let factory_addr = match Address::from_str(factory) {
+        Ok(a) => a,
+        Err(_) => return "Error: Failed to parse the factory address.".to_string(),
+    };
+    let salt_bytes = match hex::decode(salt.trim_start_matches("0x")) {
+        Ok(s) if s.len() <= 32 => s,
+        _ => return "Error: Salt must be at most 32 bytes of hex.".to_string(),
+    };
+    let mut buf = vec![0xff];
+    buf.extend(factory_addr.as_bytes());
+    buf.extend(vec![0u8; 32 - salt_bytes.len()]);
+    buf.extend(salt_bytes);
+    buf.extend(init_code_hash);
+    let hash = keccak256(&buf);
+    format!("0x{}", hex::encode(&hash[12..]))
+}
+
+//Multicall3, deployed at the same address on most EVM chains
+pub const MULTICALL3_ADDRESS: &str = "0xcA11bde05977b3631167028862bE2a173976CA11";
+// Calls per aggregate3 request, keeps the eth_call under provider gas and size caps
+#[cfg(feature = "rpc")]
+const MULTICALL_CHUNK_SIZE: usize = 500;
+
+// Runs (target, calldata) eth_calls through Multicall3 aggregate3 with allowFailure set,
+// falling back to a JSON-RPC batch when no multicall address is set or the call fails.
+// Each call gets its own Ok(return data) or Err("Error: ..").
+#[cfg(feature = "rpc")]
+async fn multicall(url: &str, multicall_address: &str, calls: &[(String, Vec<u8>)]) -> Result<Vec<Result<Vec<u8>, String>>, String> {
+    if calls.is_empty() {
+        return Ok(Vec::new());
+    }
+    if !multicall_address.is_empty() {
+        if let Ok(results) = aggregate3(url, multicall_address, calls).await {
+            return Ok(results);
+        }
+    }
+    batch_eth_call(url, calls).await
+}
+
+#[cfg(feature = "rpc")]
+async fn aggregate3(url: &str, multicall_address: &str, calls: &[(String, Vec<u8>)]) -> Result<Vec<Result<Vec<u8>, String>>, String> {
+    // Pin every chunk to the same block so the results are consistent
+    let block = rpc_call(url, "eth_blockNumber", json!([])).await?;
+    let mut out = Vec::with_capacity(calls.len());
+    for chunk in calls.chunks(MULTICALL_CHUNK_SIZE) {
+        let data = encode_aggregate3(chunk)?;
+        let result = rpc_call(url, "eth_call", json!([{ "to": multicall_address, "data": format!("0x{}", hex::encode(data)) }, block])).await?;
+        let bytes = hex::decode(result.as_str().unwrap_or("").trim_start_matches("0x"))
+            .map_err(|_| "Error: Unexpected JSON format.".to_string())?;
+        // An empty result means there is no Multicall3 contract on this chain
+        if bytes.is_empty() {
+            return Err("Error: Multicall3 is not deployed.".to_string());
+        }
+        let decoded = decode_aggregate3(&bytes)?;
+        if decoded.len() != chunk.len() {
+            return Err("Error: Unexpected multicall result length.".to_string());
+        }
+        out.extend(decoded);
+    }
+    Ok(out)
+}
+
+// aggregate3((address target, bool allowFailure, bytes callData)[])
+pub fn encode_aggregate3(calls: &[(String, Vec<u8>)]) -> Result<Vec<u8>, String> {
+    use ethabi::Token;
+    let mut tuples = Vec::with_capacity(calls.len());
+    for (target, data) in calls {
+        let addr = Address::from_str(target).map_err(|_| format!("Error: Failed to parse the contract address {}.", target))?;
+        tuples.push(Token::Tuple(vec![
+            Token::Address(ethabi::Address::from_slice(addr.as_bytes())),
+            Token::Bool(true),
+            Token::Bytes(data.clone()),
+        ]));
+    }
+    let mut data = hex::decode("82ad56cb").expect("Invalid selector");
+    data.extend(ethabi::encode(&[Token::Array(tuples)]));
+    Ok(data)
+}
+
+// Returns (bool success, bytes returnData)[] as per-call results
+pub fn decode_aggregate3(output: &[u8]) -> Result<Vec<Result<Vec<u8>, String>>, String> {
+    use ethabi::{ParamType, Token};
+    let kind = ParamType::Array(Box::new(ParamType::Tuple(vec![ParamType::Bool, ParamType::Bytes])));
+    let tokens = ethabi::decode(&[kind], output).map_err(|_| "Error: Failed to decode multicall result.".to_string())?;
+    let items = match tokens.into_iter().next() {
+        Some(Token::Array(items)) => items,
+        _ => return Err("Error: Failed to decode multicall result.".to_string()),
+    };
+    Ok(items.into_iter().map(|item| match item {
+        Token::Tuple(mut fields) if fields.len() == 2 => match (fields.remove(0), fields.remove(0)) {
+            (Token::Bool(true), Token::Bytes(data)) => Ok(data),
+            _ => Err("Error: Call reverted.".to_string()),
+        },
+        _ => Err("Error: Failed to decode multicall result.".to_string()),
+    }).collect())
+}
+
+// Fallback for chains without Multicall3, one JSON-RPC batch of eth_calls
+#[cfg(feature = "rpc")]
+async fn batch_eth_call(url: &str, calls: &[(String, Vec<u8>)]) -> Result<Vec<Result<Vec<u8>, String>>, String> {
+    let requests: Vec<(&str, Value)> = calls.iter()
+        .map(|(to, data)| ("eth_call", json!([{ "to": to, "data": format!("0x{}", hex::encode(data)) }, "latest"])))
+        .collect();
+    let results = batch_rpc(url, &requests).await?;
+    Ok(results.into_iter().map(|r| {
+        let result = r?;
+        hex::decode(result.as_str().unwrap_or("").trim_start_matches("0x")).map_err(|_| "Error: Unexpected JSON format.".to_string())
+    }).collect())
+}
+
+// JSON-RPC batch of (method, params), the responses are matched back to the requests by id
+#[cfg(feature = "rpc")]
+async fn batch_rpc(url: &str, requests: &[(&str, Value)]) -> Result<Vec<Result<Value, String>>, String> {
+    if requests.is_empty() {
+        return Ok(Vec::new());
+    }
+    let batch: Vec<Value> = requests.iter().enumerate().map(|(i, (method, params))| json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": i + 1
+    })).collect();
+    let resp = match Client::new()
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .json(&batch)
+        .send()
+        .await
+    {
+        Ok(r) if r.status().is_success() => r,
+        _ => return Err("Error: Infura error.".to_string()),
+    };
+    let parsed: Vec<Value> = match resp.json().await {
+        Ok(v) => v,
+        Err(_) => return Err("Error: JSON parse error.".to_string()),
+    };
 
-        let responses_array = match responses.as_array() {
-            Some(arr) => arr,
-            None => return "Error: Unexpected JSON format in batch response.".to_string(),
+    let mut out: Vec<Result<Value, String>> = vec![Err("Error: Missing response.".to_string()); requests.len()];
+    for item in parsed {
+        let idx = match item.get("id").and_then(|v| v.as_u64()) {
+            Some(id) if id >= 1 && (id as usize) <= requests.len() => id as usize - 1,
+            _ => continue,
+        };
+        out[idx] = match (item.get("result"), item.get("error")) {
+            (_, Some(e)) => Err(format!("Error: {:?}", e)),
+            (Some(r), None) => Ok(r.clone()),
+            _ => Err("Error: Unexpected JSON format.".to_string()),
         };
+    }
+    Ok(out)
+}
 
-        // Initialize empty strings for each response.
-        let mut decimals_hex = "";
-        let mut symbol_hex = "";
-        let mut name_hex = "";
+//Chain registry, keyed by chain id
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChainInfo {
+    pub chain_id: u64,
+    pub name: String,
+    pub native_symbol: String,
+    pub native_decimals: u8,
+    pub eip1559: bool,
+    pub multicall_address: Option<String>,
+    pub explorer_url: String,
+    pub explorer_api_url: String,
+    // 0 = legacy, 2 = EIP 1559
+    pub default_tx_type: u8,
+}
 
-        // Iterate through responses, matching each by its id.
-        for resp in responses_array {
-            if let Some(id) = resp.get("id").and_then(|v| v.as_i64()) {
-                match id {
-                    1 => {
-                        decimals_hex = resp.get("result").and_then(|r| r.as_str()).unwrap_or("");
-                    },
-                    2 => {
-                        symbol_hex = resp.get("result").and_then(|r| r.as_str()).unwrap_or("");
-                    },
-                    3 => {
-                        name_hex = resp.get("result").and_then(|r| r.as_str()).unwrap_or("");
-                    },
-                    _ => {},
-                }
-            }
+impl ChainInfo {
+    // Used for chain ids that aren't in the registry, assumes an ETH-like chain with legacy transactions
+    pub fn unknown(chain_id: u64) -> ChainInfo {
+        ChainInfo {
+            chain_id,
+            name: format!("Chain {}", chain_id),
+            native_symbol: "ETH".to_string(),
+            native_decimals: 18,
+            eip1559: false,
+            multicall_address: Some(MULTICALL3_ADDRESS.to_string()),
+            explorer_url: String::new(),
+            explorer_api_url: String::new(),
+            default_tx_type: 0,
         }
+    }
+}
 
-        // Decode decimals using hex_to_vec directly.
-        let decimals = match hex_to_vec(decimals_hex.trim_start_matches("0x")) {
-            Some(mut bytes) => {
-                // Remove any leading zeros.
-                while !bytes.is_empty() && bytes[0] == 0 {
-                    bytes.remove(0);
-                }
-                let value = if bytes.is_empty() {
-                    0u64
-                } else {
-                    bytes.into_iter().fold(0u64, |acc, b| acc * 256 + b as u64)
-                };
-                if value <= u8::MAX as u64 {
-                    value as u8
+const ETHERSCAN_V2_API: &str = "https://api.etherscan.io/v2/api";
+
+fn builtin_chains() -> Vec<ChainInfo> {
+    // (chain_id, name, symbol, eip1559, explorer)
+    let chains: [(u64, &str, &str, bool, &str); 12] = [
+        (1, "Ethereum", "ETH", true, "https://etherscan.io"),
+        (10, "OP Mainnet", "ETH", true, "https://optimistic.etherscan.io"),
+        (56, "BNB Smart Chain", "BNB", false, "https://bscscan.com"),
+        (100, "Gnosis", "XDAI", true, "https://gnosisscan.io"),
+        (137, "Polygon", "POL", true, "https://polygonscan.com"),
+        (324, "zkSync Era", "ETH", true, "https://era.zksync.network"),
+        (8453, "Base", "ETH", true, "https://basescan.org"),
+        (42161, "Arbitrum One", "ETH", true, "https://arbiscan.io"),
+        (43114, "Avalanche C-Chain", "AVAX", true, "https://snowtrace.io"),
+        (59144, "Linea", "ETH", true, "https://lineascan.build"),
+        (11155111, "Sepolia", "ETH", true, "https://sepolia.etherscan.io"),
+        (17000, "Holesky", "ETH", true, "https://holesky.etherscan.io"),
+    ];
+    chains.iter().map(|(chain_id, name, symbol, eip1559, explorer)| ChainInfo {
+        chain_id: *chain_id,
+        name: name.to_string(),
+        native_symbol: symbol.to_string(),
+        native_decimals: 18,
+        eip1559: *eip1559,
+        // zkSync Era has its own Multicall3 deployment
+        multicall_address: Some(if *chain_id == 324 { "0xF9cda624FBC7e059355ce98a31693d299FACd963" } else { MULTICALL3_ADDRESS }.to_string()),
+        explorer_url: explorer.to_string(),
+        explorer_api_url: ETHERSCAN_V2_API.to_string(),
+        default_tx_type: if *eip1559 { 2 } else { 0 },
+    }).collect()
+}
+
+// Process wide so chains registered on one thread are seen from every other (tokio workers, host callbacks)
+static CUSTOM_CHAINS: RwLock<Vec<ChainInfo>> = RwLock::new(Vec::new());
+
+fn custom_chains() -> Vec<ChainInfo> {
+    CUSTOM_CHAINS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Adds a chain to the registry, replacing any existing entry with the same chain id
+pub fn register_chain(info: ChainInfo) {
+    let mut chains = CUSTOM_CHAINS.write().unwrap_or_else(|e| e.into_inner());
+    chains.retain(|existing| existing.chain_id != info.chain_id);
+    chains.push(info);
+}
+
+// Registered chains take priority over the built-in ones
+pub fn chain_info(chain_id: u64) -> ChainInfo {
+    CUSTOM_CHAINS.read().unwrap_or_else(|e| e.into_inner()).iter().find(|info| info.chain_id == chain_id).cloned()
+        .or_else(|| builtin_chains().into_iter().find(|info| info.chain_id == chain_id))
+        .unwrap_or_else(|| ChainInfo::unknown(chain_id))
+}
+
+pub fn all_chains() -> Vec<ChainInfo> {
+    let mut chains = custom_chains();
+    for builtin in builtin_chains() {
+        if !chains.iter().any(|c| c.chain_id == builtin.chain_id) {
+            chains.push(builtin);
+        }
+    }
+    chains.sort_by_key(|c| c.chain_id);
+    chains
+}
+
+// Derives the EVM address for a relative derivation path such as "m/0/0" from a zpub/xpub
+pub fn derive_address(xpub_str: &str, account_derivation_path: &str) -> Result<String, String> {
+    let xpub_tmp_str = convert_to_xpub(xpub_str.to_string());
+    let xpub = Xpub::from_str(&xpub_tmp_str).map_err(|_| "Error: zPub derivation error.".to_string())?;
+    let derivation_path = DerivationPath::from_str(account_derivation_path).map_err(|_| "Error: Derivation path error.".to_string())?;
+    let derived_xpub = xpub.derive_pub(&bitcoin::secp256k1::Secp256k1::new(), &derivation_path)
+        .map_err(|_| "Error: zPub derivation error.".to_string())?;
+    let uncompressed = PublicKey::new_uncompressed(derived_xpub.public_key).to_bytes();
+    // Hash the 64 byte public key (without the 0x04 prefix) with Keccak-256
+    let output = keccak256(&uncompressed[1..]);
+    Ok(format!("0x{}", hex::encode(&output[12..])))
+}
+
+// discover_accounts caps the gap limit and queries addresses in batches of two requests each
+#[cfg(feature = "rpc")]
+const DISCOVERY_MAX_GAP_LIMIT: u32 = 1_000;
+#[cfg(feature = "rpc")]
+const DISCOVERY_BATCH_SIZE: u32 = 50;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DiscoveredAccount {
+    pub derivation_path: String,
+    pub address: String,
+    pub nonce: u64,
+    pub balance: String,
+}
+
+// Gap scan behind discover_accounts, `activity` returns (nonce, balance) for each address of a batch
+#[cfg(feature = "rpc")]
+async fn scan_accounts<F, Fut>(xpub: &str, gap_limit: u32, max_branches: u32, mut activity: F) -> Result<Vec<DiscoveredAccount>, String>
+where
+    F: FnMut(Vec<String>) -> Fut,
+    Fut: std::future::Future<Output = Result<Vec<(U256, U256)>, String>>,
+{
+    let gap_limit = if gap_limit == 0 { 20 } else { gap_limit.min(DISCOVERY_MAX_GAP_LIMIT) };
+    let mut used: Vec<DiscoveredAccount> = Vec::new();
+    for branch in 0..max_branches.max(1) {
+        let found_before = used.len();
+        let mut next_index: u32 = 0;
+        let mut unused_in_a_row: u32 = 0;
+        while unused_in_a_row < gap_limit && next_index <= u16::MAX as u32 {
+            // Query the rest of the gap window, at most DISCOVERY_BATCH_SIZE addresses per batch
+            let window = (gap_limit - unused_in_a_row).min(DISCOVERY_BATCH_SIZE);
+            let window_end = next_index.saturating_add(window).min(u16::MAX as u32 + 1);
+            let mut accounts = Vec::new();
+            for index in next_index..window_end {
+                let path = format!("m/{}/{}", branch, index);
+                accounts.push((path.clone(), derive_address(xpub, &path)?));
+            }
+            let results = activity(accounts.iter().map(|(_, a)| a.clone()).collect()).await?;
+            if results.len() != accounts.len() {
+                return Err("Error: Failed to query account activity.".to_string());
+            }
+            for ((path, address), (nonce, balance)) in accounts.into_iter().zip(results) {
+                if nonce.is_zero() && balance.is_zero() {
+                    unused_in_a_row += 1;
+                    if unused_in_a_row >= gap_limit { break; }
                 } else {
-                    return "Error: Decimals value out of range.".to_string();
+                    unused_in_a_row = 0;
+                    used.push(DiscoveredAccount {
+                        derivation_path: path,
+                        address,
+                        nonce: nonce.low_u64(),
+                        balance: balance.to_string(),
+                    });
                 }
-            },
-            None => return "Error: Failed to decode decimals.".to_string(),
-        };
-
-        let symbol = match decode_abi_string(symbol_hex) {
-            Some(s) => s,
-            None => return "Error: Failed to decode symbol.".to_string(),
-        };
+            }
+            next_index = window_end;
+        }
+        if used.len() == found_before { break; }
+    }
+    Ok(used)
+}
 
-        let name = match decode_abi_string(name_hex) {
-            Some(n) => n,
-            None => return "Error: Failed to decode name.".to_string(),
-        };
+//Portfolio, one xpub across several accounts and chains
+#[cfg(feature = "rpc")]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+pub struct Portfolio {
+    xpub: String,
+    account_derivation_paths: Vec<String>,
+    // (chain_id, rpc url)
+    chains: Vec<(u64, String)>,
+    // (chain_id, ERC20 contract address)
+    tokens: Vec<(u64, String)>,
+}
 
-        // Assemble the contract data into a JSON object and return it as a string.
-        let contract_data = json!({
-             "address": contract_address,
-             "decimals": decimals,
-             "symbol": symbol,
-             "name": name,
-        });
-        return contract_data.to_string();
+#[cfg(feature = "rpc")]
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl Portfolio {
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn new(xpub: String) -> Portfolio {
+        Portfolio {
+            xpub,
+            account_derivation_paths: Vec::new(),
+            chains: Vec::new(),
+            tokens: Vec::new(),
+        }
     }
-    pub fn erc20_transfer(&self, contract_address: String, recipient: String, token_amount: &str, fee_rate: i32) -> String {
-        // Use a higher gas limit for token transfers.
-        let gas_limit: u64 = 160000;
-        let token_amount_u256 = U256::from_dec_str(token_amount).unwrap_or(U256::zero());
-        // Encode the ERC20 transfer data.
-        let data = encode_transfer(&recipient, token_amount_u256);
-        let new_gas_price;
-        let self_gas = gas_price_from_string(&self.gas_price);
-         match fee_rate{
-            0 => new_gas_price = &self_gas * U256::from(10) / U256::from(10),
-            1 => new_gas_price = self_gas * U256::from(15) / U256::from(10),
-            2 => new_gas_price = &self_gas * U256::from(20) / U256::from(10) ,
-            _ => new_gas_price = self_gas,
+    //Derivation paths use the same m/x/y format as Wallet
+    pub fn add_account(&mut self, account_derivation_path: String) -> String {
+        if extract_u16s(&account_derivation_path).is_err() {
+            return "Error: Derivation path error.".to_string();
+        }
+        if !self.account_derivation_paths.contains(&account_derivation_path) {
+            self.account_derivation_paths.push(account_derivation_path);
         }
-
-        let mut stream = RlpStream::new_list(9);
-        stream.append(&U256::from(self.nonce));
-        stream.append(&new_gas_price);
-        stream.append(&U256::from(gas_limit));
-        let contract_addr = Address::from_str(&contract_address).unwrap_or(Address::zero());
-        stream.append(&contract_addr);
-        // For token transfers, ETH value is zero.
-        stream.append(&U256::zero());
-        stream.append(&data);
-        stream.append(&self.chain_id);
-        stream.append(&0u8);
-        stream.append(&0u8);
-        let rlp_encoded = stream.out();
-        let mut hasher = Keccak::v256();
-        let mut tx_hash = [0u8; 32];
-        hasher.update(&rlp_encoded);
-        hasher.finalize(&mut tx_hash);
-        let mut total_bytes : Vec<u8> = Vec::new();
-        total_bytes.extend_from_slice(&tx_hash);
-        match extract_u16s(&self.account_derivation_path) {
-            Ok((first, second)) => append_integers_as_bytes(&mut total_bytes,first,second),
-            Err(_) => return "Error: Derivation path error.".to_string(),
+        "Account added.".to_string()
+    }
+    pub fn add_chain(&mut self, chain_id: u64, rpc_url: String) {
+        self.chains.retain(|(id, _)| *id != chain_id);
+        self.chains.push((chain_id, rpc_url));
+    }
+    pub fn add_token(&mut self, chain_id: u64, contract_address: String) -> String {
+        if Address::from_str(&contract_address).is_err() {
+            return "Error: Failed to parse the contract address.".to_string();
         }
-        let unsigned_tx = hex::encode(rlp_encoded);
-        let final_str = unsigned_tx + ":&" + &base64::encode(&total_bytes);
-        return final_str;
+        let exists = self.tokens.iter().any(|(id, c)| *id == chain_id && c.eq_ignore_ascii_case(&contract_address));
+        if !exists {
+            self.tokens.push((chain_id, contract_address));
+        }
+        "Token added.".to_string()
     }
-    //This function now always accepts and returns a list of balances for a list of contracts
-    pub async fn erc20_balance(&self, contract_addresses: Vec<String>) -> Vec<String> {
-        // Clean and pad the wallet address
-        let wallet_addr_clean = self.address.trim_start_matches("0x");
-        let padded_wallet_addr = format!("{:0>64}", wallet_addr_clean);
+    //Syncs every chain concurrently and returns a PortfolioSnapshot as JSON.
+    //A chain or account that fails to sync is listed in "errors" and the rest are still returned.
+    pub async fn sync(&self) -> String {
+        let mut snapshot = PortfolioSnapshot { balances: Vec::new(), account_totals: Vec::new(), chain_totals: Vec::new(), errors: Vec::new() };
+        let mut accounts = Vec::with_capacity(self.account_derivation_paths.len());
+        for path in &self.account_derivation_paths {
+            match derive_address(&self.xpub, path) {
+                Ok(address) => accounts.push((path.clone(), address)),
+                Err(e) => snapshot.errors.push(format!("Account {}: {}", path, e)),
+            }
+        }
 
-        // Build the call data using the ERC20 balanceOf selector (0x70a08231)
-        let call_data = format!("0x70a08231{}", padded_wallet_addr);
+        let syncs = self.chains.iter().map(|(chain_id, url)| {
+            let tokens: Vec<String> = self.tokens.iter().filter(|(id, _)| id == chain_id).map(|(_, c)| c.clone()).collect();
+            let accounts = accounts.clone();
+            async move { (*chain_id, sync_portfolio_chain(*chain_id, url, &accounts, &tokens).await) }
+        });
+        let results = futures::future::join_all(syncs).await;
 
-        // Build batched JSON-RPC requests, one per contract address
-        let mut batch_requests = Vec::new();
-        for (i, contract_address) in contract_addresses.iter().enumerate() {
-            let req = json!({
-                "jsonrpc": "2.0",
-                "method": "eth_call",
-                "params": [
-                    {
-                        "to": contract_address,
-                        "data": call_data
-                    },
-                    "latest"
-                ],
-                "id": i + 1  // Assign a unique id for each request
-            });
-            batch_requests.push(req);
+        for (chain_id, result) in results {
+            match result {
+                Ok(balances) => snapshot.balances.extend(balances),
+                Err(e) => snapshot.errors.push(format!("Chain {}: {}", chain_id, e)),
+            }
         }
+        snapshot.compute_totals();
+        serde_json::to_string(&snapshot).unwrap_or_else(|_| "Error: Failed to serialize portfolio.".to_string())
+    }
+}
 
-        // Send the batched request to Infura
-        let client = reqwest::Client::new();
-        let response = match client
-            .post(&self.infura_url)
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&batch_requests)
-            .send()
-            .await
-        {
-            Ok(resp) => resp,
-            Err(_) => return vec!["Error: Infura error.".to_string()],
+// Native balances in one JSON-RPC batch, token metadata and balances in one multicall
+#[cfg(feature = "rpc")]
+async fn sync_portfolio_chain(chain_id: u64, url: &str, accounts: &[(String, String)], tokens: &[String]) -> Result<Vec<AccountBalance>, String> {
+    let chain = chain_info(chain_id);
+    let requests: Vec<(&str, Value)> = accounts.iter().map(|(_, address)| ("eth_getBalance", json!([address, "latest"]))).collect();
+    let native = batch_rpc(url, &requests).await?;
+
+    let mut calls = Vec::new();
+    for token in tokens {
+        calls.push((token.clone(), hex::decode("313ce567").unwrap())); // decimals()
+        calls.push((token.clone(), hex::decode("95d89b41").unwrap())); // symbol()
+        calls.push((token.clone(), hex::decode("06fdde03").unwrap())); // name()
+        for (_, address) in accounts {
+            let data = hex::decode(format!("70a08231{:0>64}", address.trim_start_matches("0x"))).map_err(|_| "Error: Address error.".to_string())?;
+            calls.push((token.clone(), data));
+        }
+    }
+    let token_results = multicall(url, &chain.multicall_address.clone().unwrap_or_default(), &calls).await?;
+    let per_token = 3 + accounts.len();
+
+    let mut balances = Vec::with_capacity(accounts.len());
+    for (i, (path, address)) in accounts.iter().enumerate() {
+        let native_wei = native[i].as_ref().map_err(|e| e.clone())?
+            .as_str()
+            .and_then(|h| U256::from_str_radix(h.trim_start_matches("0x"), 16).ok())
+            .ok_or_else(|| "Error: Balance parse error.".to_string())?;
+        let mut account = AccountBalance {
+            derivation_path: path.clone(),
+            address: address.clone(),
+            chain_id,
+            native: AssetBalance::new(chain_id, None, &chain.native_symbol, &chain.name, chain.native_decimals, native_wei),
+            tokens: Vec::new(),
         };
-
-        if response.status().is_success() {
-            let body = match response.text().await {
-                Ok(text) => text,
-                Err(_) => return vec!["Error: Infura error.".to_string()],
+        for (t, token) in tokens.iter().enumerate() {
+            let r = &token_results[t * per_token..(t + 1) * per_token];
+            // Tokens whose metadata can't be read aren't ERC20s and are skipped
+            let metadata = match (&r[0], &r[1], &r[2]) {
+                (Ok(d), Ok(s), Ok(n)) if d.len() >= 32 && d[..31].iter().all(|b| *b == 0) => decode_abi_string(&hex::encode(s))
+                    .zip(decode_abi_string(&hex::encode(n)))
+                    .map(|(symbol, name)| (d[31], symbol, name)),
+                _ => None,
+            };
+            let (decimals, symbol, name) = match metadata {
+                Some(m) => m,
+                None => continue,
+            };
+            let balance = match &r[3 + i] {
+                Ok(b) if b.len() >= 32 => U256::from_big_endian(&b[..32]),
+                _ => continue,
             };
+            account.tokens.push(AssetBalance::new(chain_id, Some(token.clone()), &symbol, &name, decimals, balance));
+        }
+        balances.push(account);
+    }
+    Ok(balances)
+}
 
-            // Parse the response as an array of JSON objects
-            let parsed: Vec<serde_json::Value> = match serde_json::from_str(&body) {
-                Ok(val) => val,
-                Err(_) => return vec!["Error: JSON parse error.".to_string()],
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AssetBalance {
+    pub chain_id: u64,
+    // None for the native currency
+    pub contract_address: Option<String>,
+    pub symbol: String,
+    pub name: String,
+    pub decimals: u8,
+    pub balance: String,
+    pub balance_formatted: String,
+}
+
+#[cfg(feature = "rpc")]
+impl AssetBalance {
+    fn new(chain_id: u64, contract_address: Option<String>, symbol: &str, name: &str, decimals: u8, balance: U256) -> AssetBalance {
+        AssetBalance {
+            chain_id,
+            contract_address,
+            symbol: symbol.to_string(),
+            name: name.to_string(),
+            decimals,
+            balance: balance.to_string(),
+            balance_formatted: format_units(balance, decimals),
+        }
+    }
+    fn same_asset(&self, other: &AssetBalance) -> bool {
+        self.chain_id == other.chain_id
+            && self.contract_address.as_deref().map(|c| c.to_lowercase()) == other.contract_address.as_deref().map(|c| c.to_lowercase())
+    }
+    fn add(&mut self, other: &AssetBalance) {
+        let total = gas_price_from_string(&self.balance).saturating_add(gas_price_from_string(&other.balance));
+        self.balance = total.to_string();
+        self.balance_formatted = format_units(total, self.decimals);
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountBalance {
+    pub derivation_path: String,
+    pub address: String,
+    pub chain_id: u64,
+    pub native: AssetBalance,
+    pub tokens: Vec<AssetBalance>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AccountTotals {
+    pub derivation_path: String,
+    pub address: String,
+    // One entry per chain and token the account holds
+    pub assets: Vec<AssetBalance>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChainTotals {
+    pub chain_id: u64,
+    pub name: String,
+    pub native: AssetBalance,
+    pub tokens: Vec<AssetBalance>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortfolioSnapshot {
+    pub balances: Vec<AccountBalance>,
+    pub account_totals: Vec<AccountTotals>,
+    pub chain_totals: Vec<ChainTotals>,
+    pub errors: Vec<String>,
+}
+
+#[cfg(feature = "rpc")]
+impl PortfolioSnapshot {
+    fn compute_totals(&mut self) {
+        self.account_totals.clear();
+        self.chain_totals.clear();
+        for balance in &self.balances {
+            // Per account, every asset across chains
+            let idx = match self.account_totals.iter().position(|a| a.derivation_path == balance.derivation_path) {
+                Some(i) => i,
+                None => {
+                    self.account_totals.push(AccountTotals { derivation_path: balance.derivation_path.clone(), address: balance.address.clone(), assets: Vec::new() });
+                    self.account_totals.len() - 1
+                }
+            };
+            let assets = &mut self.account_totals[idx].assets;
+            assets.push(balance.native.clone());
+            assets.extend(balance.tokens.iter().cloned());
+
+            // Per chain, each asset summed over the accounts
+            let idx = match self.chain_totals.iter().position(|c| c.chain_id == balance.chain_id) {
+                Some(i) => {
+                    self.chain_totals[i].native.add(&balance.native);
+                    i
+                }
+                None => {
+                    self.chain_totals.push(ChainTotals {
+                        chain_id: balance.chain_id,
+                        name: chain_info(balance.chain_id).name,
+                        native: balance.native.clone(),
+                        tokens: Vec::new(),
+                    });
+                    self.chain_totals.len() - 1
+                }
             };
+            for token in &balance.tokens {
+                let totals = &mut self.chain_totals[idx].tokens;
+                match totals.iter_mut().find(|t| t.same_asset(token)) {
+                    Some(t) => t.add(token),
+                    None => totals.push(token.clone()),
+                }
+            }
+        }
+    }
+}
 
-            let mut balances = Vec::new();
-            // Process each response object in the batch
-            for resp_item in parsed {
-                let result_str = match resp_item.get("result").and_then(|r| r.as_str()) {
-                    Some(r) => r,
-                    None => return vec!["Error: Unexpected JSON format.".to_string()],
-                };
+// Formats a raw integer amount with the given decimals, e.g. 1500000 with 6 decimals is "1.5"
+pub fn format_units(amount: U256, decimals: u8) -> String {
+    let digits = amount.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+    let padded = format!("{:0>width$}", digits, width = decimals + 1);
+    let (int_part, frac_part) = padded.split_at(padded.len() - decimals);
+    let frac = frac_part.trim_end_matches('0');
+    if frac.is_empty() {
+        int_part.to_string()
+    } else {
+        format!("{}.{}", int_part, frac)
+    }
+}
 
-                let balance_u256 = match U256::from_str_radix(result_str.trim_start_matches("0x"), 16) {
-                    Ok(val) => val,
-                    Err(_) => return vec!["Error: Balance parse error.".to_string()],
-                };
+//Persisted wallet state, bump WALLET_STATE_VERSION when the layout changes
+pub const WALLET_STATE_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct KnownToken {
+    pub address: String,
+    pub decimals: u8,
+    pub symbol: String,
+    pub name: String,
+}
+
+// A token from an imported token list, mismatches are filled in by verify_token_lists
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ListedToken {
+    pub list: String,
+    pub chain_id: u64,
+    pub address: String,
+    pub decimals: u8,
+    pub symbol: String,
+    pub name: String,
+    pub logo_uri: Option<String>,
+    #[serde(default)]
+    pub mismatches: Vec<String>,
+}
 
-                balances.push(balance_u256.to_string());
+pub struct TokenList {
+    pub name: String,
+    pub tokens: Vec<ListedToken>,
+}
+
+// A known or listed token, `sources` is "user" (validate_contract) and/or list names and `flags` holds
+// decimals_mismatch, symbol_mismatch, name_mismatch or not_erc20 when the sources or the chain disagree
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RegistryToken {
+    pub address: String,
+    pub decimals: u8,
+    pub symbol: String,
+    pub name: String,
+    pub logo_uri: Option<String>,
+    pub sources: Vec<String>,
+    pub flags: Vec<String>,
+}
+
+// Parses a Uniswap Token List, keeping the tokens for chain_id. Returns the list, the invalid entries
+// as {"index","error"} and the number of tokens for other chains
+pub fn parse_token_list(list_json: &str, chain_id: u64) -> Result<(TokenList, Vec<Value>, usize), String> {
+    let list: Value = serde_json::from_str(list_json).map_err(|_| "Error: The token list is not valid JSON.".to_string())?;
+    let name = match list["name"].as_str().map(str::trim) {
+        Some(n) if !n.is_empty() => n.to_string(),
+        _ => return Err("Error: The token list has no name.".to_string()),
+    };
+    let entries = list["tokens"].as_array().ok_or_else(|| "Error: The token list has no tokens.".to_string())?;
+    let mut tokens: Vec<ListedToken> = Vec::new();
+    let mut invalid = Vec::new();
+    let mut other_chains = 0;
+    for (index, entry) in entries.iter().enumerate() {
+        match parse_listed_token(entry, &name) {
+            Ok(token) if token.chain_id != chain_id => other_chains += 1,
+            Ok(token) if tokens.iter().any(|t| t.address.eq_ignore_ascii_case(&token.address)) => {
+                invalid.push(json!({ "index": index, "error": "Error: Duplicate token address." }));
             }
-            // Prepend "Success" as the first element
-            let mut result_vec = vec!["Success".to_string()];
-            result_vec.extend(balances);
-            result_vec
-        } else {
-            vec!["Error: Infura error.".to_string()]
+            Ok(token) => tokens.push(token),
+            Err(e) => invalid.push(json!({ "index": index, "error": e })),
         }
     }
-    pub async fn broadcast(&mut self, unsigned_tx: String, tx_signature : String) -> String {
-        let unsigned_tx_hex = unsigned_tx.trim_start_matches("0x");
-        let unsigned_tx_bytes = match hex::decode(unsigned_tx_hex){
-            Ok(bytes) => bytes,
-            Err(_) => return "Error: Failed to decode the unsigned transaction.".to_string(),
-        };
+    Ok((TokenList { name, tokens }, invalid, other_chains))
+}
 
-        // Decode the unsigned transaction RLP.
-        // This unsigned tx is expected to have 9 fields:
-        // [nonce, gasPrice, gasLimit, to, value, data, v, r, s]
-        // In the unsigned tx, the v, r, s fields are placeholders (usually 0x80).
-        let rlp_unsigned = Rlp::new(&unsigned_tx_bytes);
-        let base_bytes = match base64::decode(&tx_signature){
-            Ok(bytes) => bytes,
-            Err(_) => return "Error: Failed to decode the transaction signature.".to_string()
-        };
+// One token list entry, checked against the schema's chainId, address, decimals, symbol and name rules
+fn parse_listed_token(entry: &Value, list: &str) -> Result<ListedToken, String> {
+    let chain_id = entry["chainId"].as_u64().filter(|c| *c >= 1).ok_or_else(|| "Error: Invalid chainId.".to_string())?;
+    let address = entry["address"].as_str().unwrap_or_default();
+    parse_address(address, "token")?;
+    let decimals = entry["decimals"].as_u64().filter(|d| *d <= 255).ok_or_else(|| "Error: Invalid decimals.".to_string())? as u8;
+    let symbol = entry["symbol"].as_str().unwrap_or_default();
+    if symbol.is_empty() || symbol.chars().count() > 20 || symbol.chars().any(char::is_whitespace) {
+        return Err("Error: Invalid symbol.".to_string());
+    }
+    let name = entry["name"].as_str().unwrap_or_default();
+    if name.trim().is_empty() || name.chars().count() > 60 {
+        return Err("Error: Invalid name.".to_string());
+    }
+    Ok(ListedToken {
+        list: list.to_string(),
+        chain_id,
+        address: address.trim().to_string(),
+        decimals,
+        symbol: symbol.to_string(),
+        name: name.to_string(),
+        logo_uri: entry["logoURI"].as_str().map(str::to_string),
+        mismatches: Vec::new(),
+    })
+}
 
-        let nonce = match rlp_unsigned.at(0) {
-            Ok(field) => match field.as_val::<U256>() {
-                Ok(val) => val,
-                Err(_) => return "Error: Failed to decode the nonce.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the nonce.".to_string(),
-        };
+// How a list entry differs from the on-chain metadata, None means the call failed
+pub fn token_mismatches(token: &ListedToken, decimals: Option<u8>, symbol: Option<&str>, name: Option<&str>) -> Vec<String> {
+    let mut out = Vec::new();
+    if decimals.is_none() {
+        out.push("not_erc20".to_string());
+        return out;
+    }
+    if decimals != Some(token.decimals) {
+        out.push("decimals_mismatch".to_string());
+    }
+    if symbol.is_some_and(|s| s.trim() != token.symbol) {
+        out.push("symbol_mismatch".to_string());
+    }
+    if name.is_some_and(|n| n.trim() != token.name) {
+        out.push("name_mismatch".to_string());
+    }
+    out
+}
 
-        let gas_price = match rlp_unsigned.at(1) {
-            Ok(field) => match field.as_val::<U256>() {
-                Ok(val) => val,
-                Err(_) =>return "Error: Failed to decode the gas price.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the gas price.".to_string(),
-        };
+// Merges tokens added through validate_contract (on-chain metadata, preferred) with list tokens
+pub fn merge_token_registry(known: &[KnownToken], listed: &[ListedToken]) -> Vec<RegistryToken> {
+    let mut registry: Vec<RegistryToken> = known.iter().map(|t| RegistryToken {
+        address: t.address.clone(),
+        decimals: t.decimals,
+        symbol: t.symbol.clone(),
+        name: t.name.clone(),
+        logo_uri: None,
+        sources: vec!["user".to_string()],
+        flags: Vec::new(),
+    }).collect();
+    for token in listed {
+        let flag = |flags: &mut Vec<String>, f: &str| if !flags.iter().any(|x| x == f) { flags.push(f.to_string()) };
+        match registry.iter_mut().find(|r| r.address.eq_ignore_ascii_case(&token.address)) {
+            Some(entry) => {
+                if entry.decimals != token.decimals { flag(&mut entry.flags, "decimals_mismatch"); }
+                if entry.symbol != token.symbol { flag(&mut entry.flags, "symbol_mismatch"); }
+                if entry.name != token.name { flag(&mut entry.flags, "name_mismatch"); }
+                for m in &token.mismatches { flag(&mut entry.flags, m); }
+                if entry.logo_uri.is_none() { entry.logo_uri = token.logo_uri.clone(); }
+                if !entry.sources.contains(&token.list) { entry.sources.push(token.list.clone()); }
+            }
+            None => registry.push(RegistryToken {
+                address: token.address.clone(),
+                decimals: token.decimals,
+                symbol: token.symbol.clone(),
+                name: token.name.clone(),
+                logo_uri: token.logo_uri.clone(),
+                sources: vec![token.list.clone()],
+                flags: token.mismatches.clone(),
+            }),
+        }
+    }
+    registry
+}
 
-        let gas_limit = match rlp_unsigned.at(2) {
-            Ok(field) => match field.as_val::<U256>() {
-                Ok(val) => val,
-                Err(_) => return "Error: Failed to decode the gas limit.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the gas limit.".to_string(),
-        };
+//Spam and scam heuristics, scores run from 0 to 100
+pub const RISK_MEDIUM: u32 = 30;
+pub const RISK_HIGH: u32 = 70;
+// Hex characters after 0x that must match at each end for two addresses to count as look-alikes
+pub const LOOKALIKE_PREFIX_LEN: usize = 4;
+pub const LOOKALIKE_SUFFIX_LEN: usize = 4;
+
+// Canonical contracts of symbols scam tokens like to copy, per chain
+const WELL_KNOWN_TOKENS: &[(u64, &str, &str)] = &[
+    (1, "USDC", "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"),
+    (1, "USDT", "0xdac17f958d2ee523a2206206994597c13d831ec7"),
+    (1, "DAI", "0x6b175474e89094c44da98b954eedeac495271d0f"),
+    (1, "WETH", "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2"),
+    (1, "WBTC", "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599"),
+];
 
-        let to = match rlp_unsigned.at(3) {
-            Ok(field) => match field.data() {
-                Ok(data) => data.to_vec(),
-                Err(_) => return "Error: Failed to decode the output.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the output.".to_string(),
-        };
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenRisk {
+    pub address: String,
+    pub symbol: String,
+    pub score: u32,
+    pub level: String, // low, medium or high
+    pub reasons: Vec<String>,
+    pub impersonates: Option<String>, // the genuine contract, when known
+}
 
-        let value = match rlp_unsigned.at(4) {
-            Ok(field) => match field.as_val::<U256>() {
-                Ok(val) => val,
-                Err(_) => return "Error: Failed to decode the value.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the value.".to_string(),
-        };
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferRisk {
+    pub hash: String,
+    pub kind: String,
+    pub contract_address: Option<String>,
+    pub counterparty: String,
+    pub score: u32,
+    pub level: String,
+    pub reasons: Vec<String>,
+    pub lookalike_of: Option<String>,
+}
 
-        let data_field = match rlp_unsigned.at(5) {
-            Ok(field) => match field.data() {
-                Ok(data) => data.to_vec(),
-                Err(_) => return "Error: Failed to decode the data field.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the data field.".to_string(),
-        };
+pub fn risk_level(score: u32) -> String {
+    match score {
+        s if s >= RISK_HIGH => "high",
+        s if s >= RISK_MEDIUM => "medium",
+        _ => "low",
+    }.to_string()
+}
 
-        let chain_id = match rlp_unsigned.at(6) {
-            Ok(field) => match field.as_val::<U256>() {
-                Ok(val) => val,
-                Err(_) => return "Error: Failed to decode the chain ID.".to_string(),
-            },
-            Err(_) => return "Error: Failed to decode the chain ID.".to_string(),
-        };
+// True when the two addresses differ but share the first and last characters, as address poisoning relies on
+pub fn is_lookalike_address(a: &str, b: &str) -> bool {
+    let a = a.trim().trim_start_matches("0x").to_lowercase();
+    let b = b.trim().trim_start_matches("0x").to_lowercase();
+    if a.len() != 40 || b.len() != 40 || a == b {
+        return false;
+    }
+    a[..LOOKALIKE_PREFIX_LEN] == b[..LOOKALIKE_PREFIX_LEN] && a[40 - LOOKALIKE_SUFFIX_LEN..] == b[40 - LOOKALIKE_SUFFIX_LEN..]
+}
 
-        let r_sig = &base_bytes[0..32];
-        let s_sig = &base_bytes[32..64];
-        let v_sig = base_bytes[64];
-        
-        let recovery_id = if v_sig > 1 { v_sig - 27 } else { v_sig };
-        let v_eip155 = chain_id.low_u64() * 2 + 35 + recovery_id as u64;
-        let mut stream = RlpStream::new_list(9);
-        stream.append(&nonce);
-        stream.append(&gas_price);
-        stream.append(&gas_limit);
-        stream.append(&to);
-        stream.append(&value);
-        stream.append(&data_field);
-        stream.append(&v_eip155);
-        stream.append(&r_sig);
-        stream.append(&s_sig);
+// Links and "claim" bait in a token name or symbol
+fn contains_url(text: &str) -> bool {
+    let lower = text.to_lowercase();
+    ["http", "www.", "://", "t.me/", ".com", ".io", ".org", ".net", ".xyz", ".app", ".site", ".top", ".gift", ".ly", "claim", "visit"]
+        .iter()
+        .any(|p| lower.contains(p))
+}
 
-        let signed_tx_bytes = stream.out().to_vec();
-        let signed_tx_hex = format!("0x{}", hex::encode(&signed_tx_bytes));
-        
-        let client = Client::new();
-        let req_body = json!({
-            "jsonrpc": "2.0",
-            "method": "eth_sendRawTransaction",
-            "params": [signed_tx_hex],
-            "id": 1
-        });
-        let resp = client.post(&self.infura_url).json(&req_body).send().await;
-        if let Ok(response) = resp {
-            if let Ok(resp_json) = response.json::<serde_json::Value>().await {
-                if let Some(result) = resp_json.get("result").and_then(|r| r.as_str()) {
-                    self.nonce = self.nonce + 1;
-                    return result.to_string();
-                } else if let Some(error) = resp_json.get("error") {
-                    return format!("Error: {:?}", error);
-                }
+// Scores a token from its address, symbol and name against the registry and the well-known tokens
+pub fn assess_token(address: &str, symbol: &str, name: &str, chain_id: u64, registry: &[RegistryToken]) -> TokenRisk {
+    let mut score = 0;
+    let mut reasons = Vec::new();
+    let mut impersonates = None;
+    let listed = registry.iter().find(|t| t.address.eq_ignore_ascii_case(address.trim()));
+
+    if contains_url(name) {
+        score += 70;
+        reasons.push("url_in_name".to_string());
+    }
+    if contains_url(symbol) {
+        score += 70;
+        reasons.push("url_in_symbol".to_string());
+    }
+    if !symbol.is_ascii() || !name.is_ascii() {
+        // Look-alike characters such as a Cyrillic "ѕ" in "USDC"
+        score += 40;
+        reasons.push("non_ascii_characters".to_string());
+    }
+    let normalized = symbol.trim().to_uppercase();
+    if listed.is_none() && !normalized.is_empty() {
+        let genuine = WELL_KNOWN_TOKENS.iter()
+            .find(|(chain, sym, _)| *chain == chain_id && *sym == normalized)
+            .map(|(_, _, addr)| addr.to_string())
+            .or_else(|| registry.iter().find(|t| t.flags.is_empty() && t.symbol.trim().to_uppercase() == normalized).map(|t| t.address.clone()));
+        if let Some(genuine) = genuine.filter(|g| !g.eq_ignore_ascii_case(address.trim())) {
+            score += 80;
+            reasons.push("impersonation".to_string());
+            impersonates = Some(genuine);
+        } else if normalized == chain_info(chain_id).native_symbol.to_uppercase() {
+            score += 80;
+            reasons.push("impersonation".to_string());
+        }
+    }
+    match listed {
+        Some(t) if !t.flags.is_empty() => {
+            score += 30;
+            reasons.push("registry_mismatch".to_string());
+        }
+        None if !registry.is_empty() => {
+            score += 10;
+            reasons.push("not_in_registry".to_string());
+        }
+        _ => {}
+    }
+    let score = score.min(100);
+    TokenRisk {
+        address: address.trim().to_string(),
+        symbol: symbol.to_string(),
+        score,
+        level: risk_level(score),
+        reasons,
+        impersonates,
+    }
+}
+
+// Recipients of successful outgoing transfers with a value, the addresses a poisoner wants to copy
+pub fn paid_counterparties(entries: &[EtherscanTx]) -> Vec<String> {
+    let mut paid: Vec<String> = Vec::new();
+    for e in entries.iter().filter(|e| e.direction == "sent" && e.status == "success") {
+        if U256::from_dec_str(&e.value_wei).unwrap_or_default().is_zero() {
+            continue;
+        }
+        if !paid.iter().any(|p| p.eq_ignore_ascii_case(&e.to_address)) {
+            paid.push(e.to_address.clone());
+        }
+    }
+    paid
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecipientCheck {
+    pub recipient: String,
+    pub score: u32,
+    pub level: String,
+    // lookalike, first_time_recipient, contact_other_chain, token_contract_recipient, contract_recipient or own_address
+    pub warnings: Vec<String>,
+    pub lookalike_of: Option<String>,
+    pub contact: Option<String>, // the address book label, for trusted destinations
+    pub is_contract: Option<bool>, // None until eth_getCode has been checked
+    pub token: Option<String>, // the symbol when the recipient is a token contract
+}
+
+// Offline part of check_recipient, is_contract is left for add_contract_warning. Address book entries for this
+// chain count as trusted destinations
+pub fn assess_recipient(recipient: &str, paid: &[String], contacts: &[AddressBookEntry], registry: &[RegistryToken], chain_id: u64, own_address: &str) -> RecipientCheck {
+    let recipient = recipient.trim();
+    let mut score = 0;
+    let mut warnings = Vec::new();
+    if recipient.eq_ignore_ascii_case(own_address.trim()) {
+        warnings.push("own_address".to_string());
+    }
+    let contact = lookup_contact(contacts, recipient, chain_id);
+    let trusted: Vec<&str> = paid.iter().map(|p| p.as_str())
+        .chain(contacts.iter().filter(|c| c.on_chain(chain_id)).map(|c| c.address.as_str()))
+        .collect();
+    let known = trusted.iter().any(|t| t.eq_ignore_ascii_case(recipient));
+    let lookalike_of = if known { None } else { trusted.iter().find(|t| is_lookalike_address(t, recipient)).map(|t| t.to_string()) };
+    if lookalike_of.is_some() {
+        score += 80;
+        warnings.push("lookalike".to_string());
+    }
+    if !known {
+        score += 20;
+        warnings.push("first_time_recipient".to_string());
+        if contacts.iter().any(|c| c.address.eq_ignore_ascii_case(recipient)) {
+            // Saved for another chain, a contract or exchange deposit address may not exist here
+            warnings.push("contact_other_chain".to_string());
+        }
+    }
+    let token = registry.iter()
+        .find(|t| t.address.eq_ignore_ascii_case(recipient))
+        .map(|t| t.symbol.clone())
+        .or_else(|| WELL_KNOWN_TOKENS.iter()
+            .find(|(chain, _, addr)| *chain == chain_id && addr.eq_ignore_ascii_case(recipient))
+            .map(|(_, sym, _)| sym.to_string()));
+    if token.is_some() {
+        // Tokens sent to a token contract are almost always lost
+        score += 80;
+        warnings.push("token_contract_recipient".to_string());
+    }
+    let score = score.min(100);
+    RecipientCheck {
+        recipient: recipient.to_string(),
+        score,
+        level: risk_level(score),
+        warnings,
+        lookalike_of,
+        contact: contact.map(|c| c.label.clone()),
+        is_contract: None,
+        token,
+    }
+}
+
+// Records the eth_getCode result, contracts that aren't already flagged as tokens get a contract_recipient warning
+pub fn add_contract_warning(check: &mut RecipientCheck, is_contract: bool) {
+    check.is_contract = Some(is_contract);
+    if is_contract && check.token.is_none() {
+        check.score = (check.score + 20).min(100);
+        check.level = risk_level(check.score);
+        check.warnings.push("contract_recipient".to_string());
+    }
+}
+
+// Below a thousandth of a unit
+fn is_dust(raw: U256, decimals: u8) -> bool {
+    !raw.is_zero() && raw < U256::exp10(decimals.saturating_sub(3) as usize)
+}
+
+// Scores history entries: zero-value transfers out of the wallet (transferFrom spoofs), tokens that score as spam
+// and dust or zero-value transfers from look-alikes of addresses the wallet has really paid
+pub fn assess_transfers(entries: &[EtherscanTx], chain_id: u64, registry: &[RegistryToken]) -> Vec<TransferRisk> {
+    let parse = |v: &str| U256::from_dec_str(v).unwrap_or_default();
+    let paid = paid_counterparties(entries);
+    let native_decimals = chain_info(chain_id).native_decimals;
+
+    entries.iter().map(|e| {
+        let mut score = 0;
+        let mut reasons = Vec::new();
+        let value = parse(&e.value_wei);
+        let fungible = e.kind == "erc20" || e.kind == "native" || e.kind == "internal";
+        let counterparty = if e.direction == "sent" { e.to_address.clone() } else { e.from_address.clone() };
+
+        if e.kind == "erc20" && value.is_zero() {
+            if e.direction == "sent" {
+                score += 80;
+                reasons.push("zero_value_transfer_from_wallet".to_string());
+            } else {
+                score += 30;
+                reasons.push("zero_value_transfer".to_string());
             }
         }
-        return "Error: Failed to broadcast transaction.".to_string();
+        if let Some(contract) = e.contract_address.as_deref().filter(|_| e.kind == "erc20") {
+            let token = assess_token(contract, e.token_symbol.as_deref().unwrap_or_default(), "", chain_id, registry);
+            if token.score >= RISK_MEDIUM {
+                score += token.score;
+                reasons.extend(token.reasons.into_iter().map(|r| format!("token_{}", r)));
+            }
+        }
+        let lookalike_of = paid.iter().find(|p| is_lookalike_address(p, &counterparty)).cloned();
+        if lookalike_of.is_some() {
+            score += 60;
+            reasons.push("lookalike_counterparty".to_string());
+        }
+        if fungible && e.direction != "sent" && is_dust(value, e.token_decimals.unwrap_or(native_decimals)) {
+            score += if lookalike_of.is_some() { 20 } else { 10 };
+            reasons.push("dust".to_string());
+        }
+        let score = score.min(100);
+        TransferRisk {
+            hash: e.hash.clone(),
+            kind: e.kind.clone(),
+            contract_address: e.contract_address.clone(),
+            counterparty,
+            score,
+            level: risk_level(score),
+            reasons,
+            lookalike_of,
+        }
+    }).collect()
+}
+
+//Address book
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AddressBookEntry {
+    pub label: String,
+    pub address: String,
+    #[serde(default)]
+    pub ens: Option<String>,
+    #[serde(default)]
+    pub chains: Vec<u64>, // empty means every chain
+    #[serde(default)]
+    pub notes: Option<String>,
+}
+
+impl AddressBookEntry {
+    pub fn on_chain(&self, chain_id: u64) -> bool {
+        self.chains.is_empty() || self.chains.contains(&chain_id)
     }
-    pub fn construct_signed_tx(&self, unsigned_tx: String, tx_signature: String) -> String {
-        // 1. strip 0x and hex-decode
-        let unsigned_hex = unsigned_tx.trim_start_matches("0x");
-        let mut tx_bytes = match hex::decode(unsigned_hex) {
-            Ok(b) => b,
-            Err(_) => return "Error: Failed to decode the unsigned transaction.".to_string(),
-        };
+}
 
-        // 2. detect & strip EIP-1559 prefix
-        let is_eip1559 = tx_bytes.get(0).map(|b| *b == 0x02).unwrap_or(false);
-        if is_eip1559 {
-            // drop the 0x02 tag
-            tx_bytes = tx_bytes.split_off(1);
-        }
+// Validates and trims a contact, empty ens and notes become None
+pub fn address_book_entry(label: &str, address: &str, ens: &str, chains: Vec<u64>, notes: &str) -> Result<AddressBookEntry, String> {
+    if label.trim().is_empty() {
+        return Err("Error: The contact has no label.".to_string());
+    }
+    parse_address(address, "contact")?;
+    let ens = ens.trim().to_lowercase();
+    if !ens.is_empty() && (!ens.contains('.') || ens.starts_with('.') || ens.ends_with('.') || ens.contains(char::is_whitespace)) {
+        return Err("Error: Invalid ENS name.".to_string());
+    }
+    Ok(AddressBookEntry {
+        label: label.trim().to_string(),
+        address: address.trim().to_string(),
+        ens: Some(ens).filter(|e| !e.is_empty()),
+        chains,
+        notes: Some(notes.trim().to_string()).filter(|n| !n.is_empty()),
+    })
+}
 
-        // 3. RLP-decode
-        let rlp = Rlp::new(&tx_bytes);
+pub fn lookup_contact<'a>(contacts: &'a [AddressBookEntry], address: &str, chain_id: u64) -> Option<&'a AddressBookEntry> {
+    contacts.iter().find(|c| c.on_chain(chain_id) && c.address.eq_ignore_ascii_case(address.trim()))
+}
 
-        // 4. helpers for per-field error handling
-        let get_u256 = |idx: usize, msg: &str| {
-            rlp.at(idx)
-                .and_then(|f| f.as_val::<U256>())
-                .map_err(|_| msg.to_string())
-        };
-        let get_bytes = |idx: usize, msg: &str| {
-            rlp.at(idx)
-                .and_then(|f| f.data())
-                .map(|d| d.to_vec())
-                .map_err(|_| msg.to_string())
-        };
+// Fills counterparty_label from the address book
+pub fn label_history(entries: &mut [EtherscanTx], contacts: &[AddressBookEntry], chain_id: u64) {
+    for e in entries.iter_mut() {
+        let counterparty = if e.direction == "sent" { &e.to_address } else { &e.from_address };
+        e.counterparty_label = lookup_contact(contacts, counterparty, chain_id).map(|c| c.label.clone());
+    }
+}
 
-        // 5. pull out fields in the correct order
-        let (chain_id, nonce, max_priority_fee, max_fee, gas_limit, to, value, data_field) =
-            if is_eip1559 {
-                // type-2 fields: [chainId, nonce, maxPriorityFeePerGas,
-                //                   maxFeePerGas, gasLimit, to, value, data, accessList…]
-                let chain_id = match get_u256(0, "Error: Failed to decode the chain ID.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let nonce = match get_u256(1, "Error: Failed to decode the nonce.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let max_priority_fee = match get_u256(
-                    2,
-                    "Error: Failed to decode the max priority fee per gas.",
-                ) {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let max_fee = match get_u256(3, "Error: Failed to decode the max fee per gas.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let gas_limit = match get_u256(4, "Error: Failed to decode the gas limit.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let to = match get_bytes(5, "Error: Failed to decode the output.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let value = match get_u256(6, "Error: Failed to decode the value.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let data_field = match get_bytes(7, "Error: Failed to decode the data field.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                (chain_id, nonce, max_priority_fee, max_fee, gas_limit, to, value, data_field)
-            } else {
-                // legacy fields: [nonce, gasPrice, gasLimit, to, value, data, chainId…]
-                let nonce = match get_u256(0, "Error: Failed to decode the nonce.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let gas_price = match get_u256(1, "Error: Failed to decode the gas price.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let gas_limit = match get_u256(2, "Error: Failed to decode the gas limit.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let to = match get_bytes(3, "Error: Failed to decode the output.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let value = match get_u256(4, "Error: Failed to decode the value.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let data_field = match get_bytes(5, "Error: Failed to decode the data field.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                let chain_id = match get_u256(6, "Error: Failed to decode the chain ID.") {
-                    Ok(v) => v,
-                    Err(e) => return e,
-                };
-                // for legacy, both priority & max fees are simply gasPrice
-                (chain_id, nonce, gas_price, gas_price, gas_limit, to, value, data_field)
-            };
+// Broadcast transactions kept in the wallet state for local history, oldest dropped first
+pub const SENT_TXS_MAX: usize = 500;
 
-        // 6. decode the signature
-        let sig_bytes = match base64::decode(&tx_signature) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to decode the transaction signature.".to_string(),
-        };
-        if sig_bytes.len() < 65 {
-            return "Error: Failed to decode the transaction signature.".to_string();
-        }
-        let r_sig = &sig_bytes[0..32];
-        let s_sig = &sig_bytes[32..64];
-        let v_raw = sig_bytes[64];
-        let recovery_id = if v_raw > 1 { v_raw - 27 } else { v_raw };
-        let v_calc = chain_id.low_u64() * 2 + 35 + recovery_id as u64;
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PendingTx {
+    pub hash: String,
+    pub nonce: u64,
+    pub raw_tx: String,
+}
 
-        if is_eip1559 {
-            U256::from(recovery_id as u64)
-        } else {
-            U256::from(chain_id.low_u64() * 2 + 35 + recovery_id as u64)
-        };
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WalletState {
+    pub version: u32,
+    pub xpub: String,
+    pub account_derivation_path: String,
+    pub infura_url: String,
+    pub chain_id: u64,
+    pub address: String,
+    pub nonce: u64,
+    pub balance: String,
+    pub gas_price: String,
+    pub max_priority_fee_per_gas: String,
+    #[serde(default)]
+    pub multicall_address: Option<String>, // None follows the chain registry
+    #[serde(default)]
+    pub known_tokens: Vec<KnownToken>,
+    #[serde(default)]
+    pub listed_tokens: Vec<ListedToken>,
+    #[serde(default)]
+    pub address_book: Vec<AddressBookEntry>,
+    #[serde(default)]
+    pub pending_txs: Vec<PendingTx>,
+    #[serde(default)]
+    pub sent_txs: Vec<PendingTx>, // every broadcast, kept after confirmation for get_log_history
+    #[serde(default = "default_ipfs_gateway")]
+    pub ipfs_gateway: String,
+    #[serde(default = "default_arweave_gateway")]
+    pub arweave_gateway: String,
+}
 
-        // 7. rebuild the signed RLP
-        let mut stream = if is_eip1559 {
-            RlpStream::new_list(12)
-        } else {
-            RlpStream::new_list(9)
-        };
+fn default_ipfs_gateway() -> String {
+    DEFAULT_IPFS_GATEWAY.to_string()
+}
 
-        if is_eip1559 {
-            stream.append(&chain_id);
-            stream.append(&nonce);
-            stream.append(&max_priority_fee);
-            stream.append(&max_fee);
-            stream.append(&gas_limit);
-            stream.append(&to);
-            stream.append(&value);
-            stream.append(&data_field);
-            stream.begin_list(0); // empty accessList
-            stream.append_raw(&[recovery_id], 1);
-            stream.append(&r_sig);
-            stream.append(&s_sig);
-        } else {
-            stream.append(&nonce);
-            stream.append(&max_priority_fee); // gasPrice
-            stream.append(&gas_limit);
-            stream.append(&to);
-            stream.append(&value);
-            stream.append(&data_field);
-            stream.append(&v_calc);
-            stream.append(&r_sig);
-            stream.append(&s_sig);
-        }
+fn default_arweave_gateway() -> String {
+    DEFAULT_ARWEAVE_GATEWAY.to_string()
+}
 
-        let mut signed_bytes = stream.out().to_vec();
-        if is_eip1559 {
-            // re-prefix with 0x02
-            let mut pref = vec![0x02];
-            pref.append(&mut signed_bytes);
-            signed_bytes = pref;
+impl Wallet {
+    // A broadcast transaction is pending until sync sees its nonce confirmed, the sent list keeps it for history
+    #[cfg(feature = "rpc")]
+    fn record_sent(&mut self, tx: PendingTx) {
+        self.pending_txs.push(tx.clone());
+        self.sent_txs.push(tx);
+        if self.sent_txs.len() > SENT_TXS_MAX {
+            let excess = self.sent_txs.len() - SENT_TXS_MAX;
+            self.sent_txs.drain(..excess);
         }
-
-        format!("0x{}", hex::encode(&signed_bytes))
     }
-    pub fn hex_to_b64(&self, tx_hash : String) -> String{
-        let hex_str = tx_hash.strip_prefix("0x").unwrap_or(&tx_hash);
-        let bytes = match hex::decode(hex_str){
-            Ok(hex) => hex,
-            Err(_) => return "Error: Invalid hex string.".to_string(),
-        };
-        return base64::encode(&bytes);
+    // The set_multicall_address override, otherwise the registry entry at call time so chains registered after
+    // Wallet::new are picked up
+    fn multicall_address(&self) -> String {
+        self.multicall_override.clone()
+            .or_else(|| chain_info(self.chain_id).multicall_address)
+            .unwrap_or_default()
     }
-    pub fn get_nonce(&self) -> u64{
-        return self.nonce;
+    // Addresses the wallet has paid: successful outgoing history entries with a value and pending transactions
+    #[cfg(feature = "rpc")]
+    fn paid_counterparties(&self, history: &[EtherscanTx]) -> Vec<String> {
+        let mut paid = paid_counterparties(history);
+        for tx in &self.pending_txs {
+            // Only well-formed signed transactions, anything else would put a bogus address in the trusted set
+            let Ok(decoded) = decode_unsigned_tx(&tx.raw_tx) else { continue };
+            if decoded["signed"] != true { continue; }
+            // ERC20 transfers pay the first argument rather than the token contract
+            let to = if decoded["call"]["function"].as_str().is_some_and(|f| f.starts_with("transfer(")) {
+                decoded["call"]["args"][0].as_str()
+            } else {
+                decoded["to"].as_str()
+            };
+            if let Some(to) = to.filter(|to| !paid.iter().any(|p| p.eq_ignore_ascii_case(to))) {
+                paid.push(to.to_string());
+            }
+        }
+        paid
     }
-    pub fn get_chain_id(&self) -> u64{
-        return self.chain_id;
+    pub fn state(&self) -> WalletState {
+        WalletState {
+            version: WALLET_STATE_VERSION,
+            xpub: self.xpub.clone(),
+            account_derivation_path: self.account_derivation_path.clone(),
+            infura_url: self.infura_url.clone(),
+            chain_id: self.chain_id,
+            address: self.address.clone(),
+            nonce: self.nonce,
+            balance: self.balance.clone(),
+            gas_price: self.gas_price.clone(),
+            max_priority_fee_per_gas: self.max_priority_fee_per_gas.clone(),
+            multicall_address: self.multicall_override.clone(),
+            known_tokens: self.known_tokens.clone(),
+            listed_tokens: self.listed_tokens.clone(),
+            address_book: self.address_book.clone(),
+            pending_txs: self.pending_txs.clone(),
+            sent_txs: self.sent_txs.clone(),
+            ipfs_gateway: self.ipfs_gateway.clone(),
+            arweave_gateway: self.arweave_gateway.clone(),
+        }
     }
-    pub fn address(&mut self) -> String{
-    	let xpub_tmp_str = &convert_to_xpub(self.xpub.clone()); //Xpub 1
-        let xpub = match Xpub::from_str(&xpub_tmp_str){
-            Ok(xpub) => xpub,
-            Err(_) => return "Error: zPub derivation error.".to_string(),
-        };
-        let derivation_path = DerivationPath::from_str(&self.account_derivation_path).unwrap();
-        let derived_xpub = match xpub.derive_pub(&bitcoin::secp256k1::Secp256k1::new(), &derivation_path){
-            Ok(derived_xpub) => derived_xpub,
-            Err(_) => return "Error: zPub derivation error.".to_string(),
-        };
-        let public_key = PublicKey::new_uncompressed(
-            derived_xpub.public_key
-        );
-        let uncompressed = public_key.to_bytes();
-	    let public_bytes = &uncompressed[1..];
-	    // Hash with Keccak-256
-	    let mut hasher = Keccak::v256();
-	    let mut output = [0u8; 32];
-	    hasher.update(public_bytes);
-	    hasher.finalize(&mut output);
-	    let address = &output[12..];
-	    self.address = format!("0x{}", hex::encode(address));
-    	return format!("0x{}", hex::encode(address));
+}
+
+// Decimal wei string to U256, an empty value is an error so offline builders never fall back to defaults
+fn parse_wei(value: &str, what: &str) -> Result<U256, String> {
+    U256::from_dec_str(value.trim()).map_err(|_| format!("Error: Failed to parse the {}.", what))
+}
+
+// An empty address means contract creation
+fn parse_optional_address(to: &str) -> Result<Option<Address>, String> {
+    if to.trim().is_empty() {
+        return Ok(None);
     }
-    pub fn balance(&self) -> String {
-        self.eth_balance.to_string()
+    Address::from_str(to.trim()).map(Some).map_err(|_| "Error: Failed to parse the recipient address.".to_string())
+}
+
+// Hex calldata for transfer(address,uint256)
+fn erc20_transfer_calldata(recipient: &str, token_amount: &str) -> Result<String, String> {
+    if Address::from_str(recipient).is_err() {
+        return Err("Error: Failed to parse the recipient address.".to_string());
     }
-    //fee rate, 0 = slow, 1 = medium, 2 = fast
-    pub fn estimate_fee(&self, fee_rate : i32, gas_limit : i32) -> String{
-        let mut new_gas_price;
-        let self_gas = gas_price_from_string(&self.gas_price);
-        match fee_rate{
-            0 => new_gas_price = &self_gas * U256::from(10) / U256::from(10),
-            1 => new_gas_price = &self_gas * U256::from(15) / U256::from(10),
-            2 => new_gas_price = &self_gas * U256::from(20) / U256::from(10),
-            _ => new_gas_price = self_gas,
+    let amount = parse_wei(token_amount, "token amount")?;
+    Ok(hex::encode(encode_transfer(recipient, amount)))
+}
+
+// Hex calldata for ERC721 transferFrom(address,address,uint256)
+fn erc721_transfer_calldata(from: &str, to: &str, token_id: &str) -> Result<String, String> {
+    parse_address(to, "recipient")?;
+    let token_id = parse_token_id(token_id)?;
+    Ok(hex::encode(encode_erc721_transfer(from, to, token_id)))
+}
+
+// Hex calldata for ERC1155 safeTransferFrom(address,address,uint256,uint256,bytes) with empty data
+fn erc1155_transfer_calldata(from: &str, to: &str, token_id: &str, amount: &str) -> Result<String, String> {
+    parse_address(to, "recipient")?;
+    let token_id = parse_token_id(token_id)?;
+    let amount = parse_wei(amount, "amount")?;
+    Ok(hex::encode(encode_erc1155_transfer(from, to, token_id, amount)))
+}
+
+//On-chain transfer history from event logs
+pub const TRANSFER_TOPIC: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+pub const TRANSFER_SINGLE_TOPIC: &str = "0xc3d58168c5ae7397731d063d5bbf3d657854427343f4c083240f7aacaa2d0f62";
+pub const TRANSFER_BATCH_TOPIC: &str = "0x4a39dc06d4c0dbc64b70af90fd698a233a518aa5d07e595d983b8c0526c8f7fb";
+// eth_getLogs block ranges start here, halve when the node refuses a range and double after a quiet one
+#[cfg(feature = "rpc")]
+const LOG_CHUNK_START: u64 = 2_000;
+#[cfg(feature = "rpc")]
+const LOG_CHUNK_MAX: u64 = 100_000;
+// Ranges scanned per page before handing back a cursor, so quiet addresses don't scan the whole chain in one call
+#[cfg(feature = "rpc")]
+const LOG_MAX_REQUESTS_PER_PAGE: usize = 50;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HistoryEntry {
+    pub hash: String,
+    pub block_number: Option<u64>,
+    pub log_index: Option<u64>,
+    pub timestamp: Option<u64>,
+    pub kind: String, // erc20, erc721, erc1155 or transaction (locally broadcast)
+    pub direction: String, // sent, received or self
+    pub from_address: String,
+    pub to_address: String,
+    pub contract_address: Option<String>,
+    pub token_id: Option<String>,
+    pub value: String, // wei or the token's smallest unit
+    pub status: String, // confirmed, failed or pending
+}
+
+#[cfg(feature = "rpc")]
+fn topic_address(address: &str) -> String {
+    format!("0x{:0>64}", address.trim_start_matches("0x").to_lowercase())
+}
+
+fn address_from_topic(topic: &str) -> String {
+    let t = topic.trim_start_matches("0x");
+    format!("0x{}", &t[t.len().saturating_sub(40)..]).to_lowercase()
+}
+
+fn history_direction(from: &str, to: &str, address: &str) -> String {
+    match (from.eq_ignore_ascii_case(address), to.eq_ignore_ascii_case(address)) {
+        (true, true) => "self",
+        (true, false) => "sent",
+        _ => "received",
+    }.to_string()
+}
+
+// Turns a Transfer/TransferSingle/TransferBatch log into history entries, a batch gives one entry per id
+pub fn parse_transfer_log(log: &Value, address: &str) -> Vec<HistoryEntry> {
+    let topics: Vec<&str> = log.get("topics").and_then(|t| t.as_array())
+        .map(|t| t.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    let data = log.get("data").and_then(|d| d.as_str())
+        .and_then(|d| hex::decode(d.trim_start_matches("0x")).ok())
+        .unwrap_or_default();
+    let hex_field = |name: &str| log.get(name).and_then(|v| v.as_str())
+        .and_then(|h| u64::from_str_radix(h.trim_start_matches("0x"), 16).ok());
+    let entry = |kind: &str, from: String, to: String, token_id: Option<String>, value: String| HistoryEntry {
+        hash: log.get("transactionHash").and_then(|h| h.as_str()).unwrap_or_default().to_string(),
+        block_number: hex_field("blockNumber"),
+        log_index: hex_field("logIndex"),
+        timestamp: None,
+        kind: kind.to_string(),
+        direction: history_direction(&from, &to, address),
+        from_address: from,
+        to_address: to,
+        contract_address: log.get("address").and_then(|a| a.as_str()).map(|a| a.to_lowercase()),
+        token_id,
+        value,
+        status: "confirmed".to_string(),
+    };
+    if log.get("removed").and_then(|r| r.as_bool()) == Some(true) || topics.is_empty() {
+        return Vec::new();
+    }
+
+    let topic0 = topics[0].to_lowercase();
+    if topic0 == TRANSFER_TOPIC && topics.len() == 4 {
+        let id = U256::from_str_radix(topics[3].trim_start_matches("0x"), 16).unwrap_or_default();
+        vec![entry("erc721", address_from_topic(topics[1]), address_from_topic(topics[2]), Some(id.to_string()), "1".to_string())]
+    } else if topic0 == TRANSFER_TOPIC && topics.len() == 3 && data.len() >= 32 {
+        vec![entry("erc20", address_from_topic(topics[1]), address_from_topic(topics[2]), None, U256::from_big_endian(&data[..32]).to_string())]
+    } else if topic0 == TRANSFER_SINGLE_TOPIC && topics.len() == 4 && data.len() >= 64 {
+        vec![entry("erc1155", address_from_topic(topics[2]), address_from_topic(topics[3]),
+            Some(U256::from_big_endian(&data[..32]).to_string()), U256::from_big_endian(&data[32..64]).to_string())]
+    } else if topic0 == TRANSFER_BATCH_TOPIC && topics.len() == 4 {
+        use ethabi::{ParamType, Token};
+        let kinds = [ParamType::Array(Box::new(ParamType::Uint(256))), ParamType::Array(Box::new(ParamType::Uint(256)))];
+        match ethabi::decode(&kinds, &data).ok().as_deref() {
+            Some([Token::Array(ids), Token::Array(values)]) => ids.iter().zip(values.iter()).filter_map(|pair| match pair {
+                (Token::Uint(id), Token::Uint(value)) => Some(entry("erc1155", address_from_topic(topics[2]), address_from_topic(topics[3]),
+                    Some(id.to_string()), value.to_string())),
+                _ => None,
+            }).collect(),
+            _ => Vec::new(),
+        }
+    } else {
+        Vec::new()
+    }
+}
+
+// Newest first, locally broadcast transactions that haven't been mined come before everything
+pub fn sort_history(entries: &mut [HistoryEntry]) {
+    entries.sort_by(|a, b| {
+        let key = |e: &HistoryEntry| (e.block_number.map_or(u64::MAX, |n| n), e.log_index.map_or(u64::MAX, |n| n));
+        key(b).cmp(&key(a))
+    });
+}
+
+// The four eth_getLogs filters covering transfers from and to the address, limited to `contracts` unless empty
+#[cfg(feature = "rpc")]
+async fn fetch_transfer_logs(url: &str, address: &str, contracts: &[String], from_block: u64, to_block: u64) -> Result<Result<Vec<Value>, String>, String> {
+    let addr = topic_address(address);
+    let range = |topics: Value| {
+        let mut filter = json!({
+            "fromBlock": format!("0x{:x}", from_block),
+            "toBlock": format!("0x{:x}", to_block),
+            "topics": topics,
+        });
+        if !contracts.is_empty() {
+            filter["address"] = json!(contracts);
+        }
+        json!([filter])
+    };
+    let nft_topics = json!([TRANSFER_SINGLE_TOPIC, TRANSFER_BATCH_TOPIC]);
+    let requests = [
+        ("eth_getLogs", range(json!([TRANSFER_TOPIC, addr]))),
+        ("eth_getLogs", range(json!([TRANSFER_TOPIC, Value::Null, addr]))),
+        ("eth_getLogs", range(json!([nft_topics, Value::Null, addr]))),
+        ("eth_getLogs", range(json!([nft_topics, Value::Null, Value::Null, addr]))),
+    ];
+    let mut logs = Vec::new();
+    for result in batch_rpc(url, &requests).await? {
+        match result {
+            Ok(Value::Array(items)) => logs.extend(items),
+            Ok(_) => return Ok(Err("Error: Unexpected JSON format.".to_string())),
+            // Usually "block range too large" or "too many results", the caller retries with a smaller range
+            Err(e) => return Ok(Err(e)),
         }
-        new_gas_price = new_gas_price * U256::from(gas_limit);
-        return format!("{}", wei_to_eth(new_gas_price));
     }
-    pub fn nonce(&self) -> u64 {
-        self.nonce
+    Ok(Ok(logs))
+}
+
+// Scans block ranges from start_block down to from_block until `limit` entries are found.
+// Whole blocks are kept so the returned cursor (the next block to scan) never splits one.
+#[cfg(feature = "rpc")]
+async fn scan_transfer_logs(url: &str, address: &str, contracts: &[String], start_block: u64, from_block: u64, limit: usize) -> Result<(Vec<HistoryEntry>, Option<u64>), String> {
+    let mut entries: Vec<HistoryEntry> = Vec::new();
+    let mut seen = std::collections::HashSet::new();
+    let mut chunk = LOG_CHUNK_START;
+    let mut high = Some(start_block).filter(|b| *b >= from_block);
+    let mut requests = 0;
+
+    while let Some(top) = high {
+        if entries.len() >= limit || requests >= LOG_MAX_REQUESTS_PER_PAGE { break; }
+        requests += 1;
+        let low = top.saturating_sub(chunk - 1).max(from_block);
+        match fetch_transfer_logs(url, address, contracts, low, top).await? {
+            Ok(logs) => {
+                let found = logs.len();
+                for log in logs {
+                    // A self transfer matches both the "from" and "to" filters
+                    let key = (log.get("transactionHash").cloned(), log.get("logIndex").cloned());
+                    if seen.insert(key) {
+                        entries.extend(parse_transfer_log(&log, address));
+                    }
+                }
+                high = if low > from_block { Some(low - 1) } else { None };
+                if found < 100 { chunk = (chunk * 2).min(LOG_CHUNK_MAX); }
+            }
+            Err(e) if chunk == 1 => return Err(e),
+            Err(_) => chunk = (chunk / 2).max(1),
+        }
     }
-    pub async fn get_tx_history(&mut self, base_url: &str, api_key: &str, limit: u32) -> String {
-        let address = self.address();
-        if address.starts_with("Error") { return address; }
 
-        let url = format!("{}?chainid={}&module=account&action=txlist&address={}&sort=desc&offset={}&apikey={}",
-            base_url, self.chain_id, address, if limit == 0 { 10 } else { limit }, api_key);
+    sort_history(&mut entries);
+    if entries.len() > limit {
+        let last_block = entries[limit - 1].block_number.unwrap_or(from_block);
+        entries.retain(|e| e.block_number.unwrap_or(0) >= last_block);
+        high = if last_block > from_block { Some(last_block - 1) } else { None };
+    }
+    Ok((entries, high))
+}
 
-        let response = match reqwest::Client::new().get(&url).send().await {
-            Ok(r) if r.status().is_success() => r.text().await.unwrap_or_default(),
-            _ => return "Error: Failed to fetch transactions from API.".to_string(),
-        };
+// Locally broadcast transactions with their receipt status
+#[cfg(feature = "rpc")]
+async fn local_history_entries(url: &str, address: &str, pending_txs: &[PendingTx]) -> Result<Vec<HistoryEntry>, String> {
+    let requests: Vec<(&str, Value)> = pending_txs.iter()
+        .map(|tx| ("eth_getTransactionReceipt", json!([tx.hash])))
+        .collect();
+    let receipts = batch_rpc(url, &requests).await?;
+    Ok(pending_txs.iter().zip(receipts).map(|(tx, receipt)| {
+        let decoded = decode_unsigned_tx(&tx.raw_tx).unwrap_or(Value::Null);
+        let receipt = receipt.ok().filter(|r| !r.is_null());
+        let hex_field = |name: &str| receipt.as_ref().and_then(|r| r.get(name)).and_then(|v| v.as_str())
+            .and_then(|h| u64::from_str_radix(h.trim_start_matches("0x"), 16).ok());
+        let to = decoded.get("to").and_then(|t| t.as_str()).unwrap_or_default().to_string();
+        HistoryEntry {
+            hash: tx.hash.clone(),
+            block_number: hex_field("blockNumber"),
+            log_index: None,
+            timestamp: None,
+            kind: "transaction".to_string(),
+            direction: history_direction(address, &to, address),
+            from_address: address.to_lowercase(),
+            to_address: to,
+            contract_address: None,
+            token_id: None,
+            value: decoded.get("value").and_then(|v| v.as_str()).unwrap_or("0").to_string(),
+            status: match hex_field("status") {
+                None => "pending",
+                Some(1) => "confirmed",
+                Some(_) => "failed",
+            }.to_string(),
+        }
+    }).collect())
+}
 
-        let json: Value = match serde_json::from_str(&response) {
-            Ok(val) => val,
-            Err(_) => return "Error: Failed to deserialize.".to_string(),
-        };
+// Fills in timestamps with one eth_getBlockByNumber per distinct block, failures leave them empty
+#[cfg(feature = "rpc")]
+async fn add_block_timestamps(url: &str, entries: &mut [HistoryEntry]) {
+    let mut blocks: Vec<u64> = entries.iter().filter_map(|e| e.block_number).collect();
+    blocks.sort_unstable();
+    blocks.dedup();
+    let requests: Vec<(&str, Value)> = blocks.iter()
+        .map(|b| ("eth_getBlockByNumber", json!([format!("0x{:x}", b), false])))
+        .collect();
+    let results = match batch_rpc(url, &requests).await {
+        Ok(r) => r,
+        Err(_) => return,
+    };
+    let timestamps: std::collections::HashMap<u64, u64> = blocks.into_iter().zip(results).filter_map(|(block, r)| {
+        let ts = r.ok()?.get("timestamp")?.as_str()
+            .and_then(|h| u64::from_str_radix(h.trim_start_matches("0x"), 16).ok())?;
+        Some((block, ts))
+    }).collect();
+    for entry in entries.iter_mut() {
+        entry.timestamp = entry.block_number.and_then(|b| timestamps.get(&b).copied());
+    }
+}
 
-        if json.get("status").and_then(|s| s.as_str()) != Some("1") {
-            return "Error: API returned error status.".to_string();
-        }
+//NFT metadata
+pub const DEFAULT_IPFS_GATEWAY: &str = "https://ipfs.io/ipfs/";
+pub const DEFAULT_ARWEAVE_GATEWAY: &str = "https://arweave.net/";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct NftMetadata {
+    pub contract_address: String,
+    pub token_id: String,
+    pub token_uri: String, // as returned by the contract
+    pub metadata_url: Option<String>, // the URL fetched, None for data: URIs
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>, // ipfs:// and ar:// rewritten through the gateway
+    pub animation_url: Option<String>,
+    pub external_url: Option<String>,
+    pub attributes: Vec<NftAttribute>,
+}
 
-        let txs = match json.get("result").and_then(|r| r.as_array()) {
-            Some(arr) => arr,
-            None => return "[]".to_string(),
-        };
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NftAttribute {
+    pub trait_type: Option<String>,
+    pub value: Value,
+    pub display_type: Option<String>,
+}
 
-        let tx_history: Vec<EtherscanTx> = txs.iter().map(|tx| {
-            let from = tx.get("from").and_then(|f| f.as_str()).unwrap_or("N/A");
-            let value_wei = tx.get("value").and_then(|v| v.as_str()).unwrap_or("0");
-            let value_eth = U256::from_dec_str(value_wei).map(wei_to_eth).unwrap_or(0.0);
-            let direction = if from.to_lowercase() == address.to_lowercase() { "sent" } else { "received" };
-            
-            EtherscanTx {
-                hash: tx.get("hash").and_then(|h| h.as_str()).unwrap_or("N/A").to_string(),
-                direction: direction.to_string(),
-                from_address: from.to_string(),
-                to_address: tx.get("to").and_then(|t| t.as_str()).unwrap_or("N/A").to_string(),
-                value_eth,
-                value_wei: value_wei.to_string(),
-                timestamp: tx.get("timeStamp").and_then(|ts| ts.as_str()).unwrap_or("0").to_string(),
-                block_number: tx.get("blockNumber").and_then(|bn| bn.as_str()).unwrap_or("0").to_string(),
-            }
-        }).collect();
+// Rewrites ipfs:// and ar:// URIs through the gateways, anything else is returned unchanged
+pub fn gateway_url(uri: &str, ipfs_gateway: &str, arweave_gateway: &str) -> String {
+    let uri = uri.trim();
+    let join = |gateway: &str, path: &str| format!("{}/{}", gateway.trim_end_matches('/'), path.trim_start_matches('/'));
+    if let Some(path) = uri.strip_prefix("ipfs://") {
+        join(ipfs_gateway, path.strip_prefix("ipfs/").unwrap_or(path))
+    } else if let Some(path) = uri.strip_prefix("ar://") {
+        join(arweave_gateway, path)
+    } else {
+        uri.to_string()
+    }
+}
+
+// Substitutes the ERC1155 {id} placeholder (64 lowercase hex digits) and applies the gateways
+pub fn resolve_token_uri(uri: &str, token_id: U256, ipfs_gateway: &str, arweave_gateway: &str) -> String {
+    let mut id_bytes = [0u8; 32];
+    token_id.to_big_endian(&mut id_bytes);
+    gateway_url(&uri.replace("{id}", &hex::encode(id_bytes)), ipfs_gateway, arweave_gateway)
+}
 
-        serde_json::to_string(&tx_history).unwrap_or_else(|_| "Error: Failed to serialize transaction history.".to_string())
+// The payload of a data: URI, base64 or percent-encoded
+pub fn decode_data_uri(uri: &str) -> Option<Vec<u8>> {
+    let (meta, payload) = uri.trim().strip_prefix("data:")?.split_once(',')?;
+    if meta.ends_with(";base64") {
+        return base64::decode(payload.trim()).ok();
     }
-    //NFT methods ERC721 + ERC1155
-    pub fn erc721_transfer(&self,contract_address: String,to: String, token_id: &str,fee_rate: i32,) -> String {
-        let gas_limit: u64 = 100_000;
-        let token_id_u256 = match U256::from_dec_str(token_id) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to parse token ID.".to_string(),
-        };
+    let bytes = payload.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1..i + 3)) {
+            (b'%', Some(h)) => match u8::from_str_radix(std::str::from_utf8(h).unwrap_or(""), 16) {
+                Ok(b) => {
+                    out.push(b);
+                    i += 3;
+                    continue;
+                }
+                Err(_) => out.push(b'%'),
+            },
+            (b, _) => out.push(b),
+        }
+        i += 1;
+    }
+    Some(out)
+}
 
-        let data = encode_erc721_transfer(&self.address, &to, token_id_u256);
+// Reads the common metadata fields, image URLs are rewritten through the gateways
+pub fn parse_nft_metadata(json: &Value, ipfs_gateway: &str, arweave_gateway: &str) -> NftMetadata {
+    let text = |name: &str| json.get(name).and_then(|v| v.as_str()).map(|v| v.to_string());
+    let url = |name: &str| text(name).map(|u| gateway_url(&u, ipfs_gateway, arweave_gateway));
+    let attributes = json.get("attributes").or_else(|| json.get("traits"))
+        .and_then(|a| a.as_array())
+        .map(|items| items.iter().filter_map(|a| Some(NftAttribute {
+            trait_type: a.get("trait_type").and_then(|t| t.as_str()).map(|t| t.to_string()),
+            value: a.get("value")?.clone(),
+            display_type: a.get("display_type").and_then(|t| t.as_str()).map(|t| t.to_string()),
+        })).collect())
+        .unwrap_or_default();
+    NftMetadata {
+        name: text("name"),
+        description: text("description"),
+        image: url("image").or_else(|| url("image_url")),
+        animation_url: url("animation_url"),
+        external_url: text("external_url"),
+        attributes,
+        ..NftMetadata::default()
+    }
+}
 
-        // gas price adjustment
-        let self_gas = gas_price_from_string(&self.gas_price);
-        let new_gas_price = match fee_rate {
-            1 => self_gas * U256::from(15) / U256::from(10),
-            2 => self_gas * U256::from(20) / U256::from(10),
-            _ => self_gas,
+// Resolves the token URI and fetches its JSON, data: URIs are decoded inline
+#[cfg(feature = "rpc")]
+async fn fetch_nft_metadata(token_uri: &str, token_id: U256, ipfs_gateway: &str, arweave_gateway: &str) -> Result<NftMetadata, String> {
+    let resolved = resolve_token_uri(token_uri, token_id, ipfs_gateway, arweave_gateway);
+    let (body, metadata_url) = if resolved.starts_with("data:") {
+        (decode_data_uri(&resolved).ok_or_else(|| "Error: Failed to decode the data URI.".to_string())?, None)
+    } else if resolved.starts_with("https://") || resolved.starts_with("http://") {
+        let body = match Client::new().get(&resolved).send().await {
+            Ok(r) if r.status().is_success() => r.bytes().await.map_err(|_| "Error: Failed to fetch the metadata.".to_string())?,
+            _ => return Err("Error: Failed to fetch the metadata.".to_string()),
         };
+        (body.to_vec(), Some(resolved))
+    } else {
+        return Err(format!("Error: Unsupported token URI {}.", token_uri));
+    };
+    let json: Value = serde_json::from_slice(&body).map_err(|_| "Error: The metadata is not valid JSON.".to_string())?;
+    let mut metadata = parse_nft_metadata(&json, ipfs_gateway, arweave_gateway);
+    metadata.token_id = token_id.to_string();
+    metadata.token_uri = token_uri.to_string();
+    metadata.metadata_url = metadata_url;
+    Ok(metadata)
+}
 
-        // RLP‐encode: [nonce, gasPrice, gasLimit, to=contract, value=0, data, chain_id, 0, 0]
-        let mut stream = RlpStream::new_list(9);
-        stream.append(&U256::from(self.nonce));
-        stream.append(&new_gas_price);
-        stream.append(&U256::from(gas_limit));
-        let caddr = Address::from_str(&contract_address).unwrap_or(Address::zero());
-        stream.append(&caddr);
-        stream.append(&U256::zero());
-        stream.append(&data);
-        stream.append(&self.chain_id);
-        stream.append(&0u8);
-        stream.append(&0u8);
-        let rlp = stream.out();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPIENT: &str = "0x3535353535353535353535353535353535353535";
+    // BIP32 test vector 1 master xpub
+    const XPUB: &str = "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ESFjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8";
+
+    #[test]
+    fn decode_legacy_round_trip() {
+        let data = encode_transfer(RECIPIENT, U256::from(1_500_000u64));
+        let to = Address::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap();
+        let payload = build_legacy_tx(U256::from(7), U256::from(20_000_000_000u64), U256::from(60_000), Some(to), U256::from(5), &data, 137, "m/1/2");
+        let decoded = decode_unsigned_tx(&payload).unwrap();
+        assert_eq!(decoded["type"], 0);
+        assert_eq!(decoded["chain_id"], 137);
+        assert_eq!(decoded["nonce"], "7");
+        assert_eq!(decoded["gas_price"], "20000000000");
+        assert_eq!(decoded["gas_limit"], "60000");
+        assert_eq!(decoded["to"], "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
+        assert_eq!(decoded["value"], "5");
+        assert_eq!(decoded["data"], format!("0x{}", hex::encode(&data)));
+        assert_eq!(decoded["call"]["function"], "transfer(address,uint256)");
+        assert_eq!(decoded["call"]["args"], json!([RECIPIENT, "1500000"]));
+        assert_eq!(decoded["signed"], false);
+        assert_eq!(decoded["derivation_path"], "m/1/2");
+        assert_eq!(decoded["hash_matches"], true);
+    }
 
-        // Keccak + derivation‐bytes
-        let mut h = Keccak::v256();
-        let mut tx_hash = [0u8; 32];
-        h.update(&rlp);
-        h.finalize(&mut tx_hash);
-        let mut blob = tx_hash.to_vec();
-        if let Ok((h1, h2)) = extract_u16s(&self.account_derivation_path) {
-            append_integers_as_bytes(&mut blob, h1, h2);
-        } else {
-            return "Error: Derivation path error.".to_string();
-        }
+    #[test]
+    fn decode_eip1559_round_trip() {
+        let to = Address::from_str(RECIPIENT).unwrap();
+        let payload = build_eip1559_tx(5, U256::from(3), U256::from(1_500_000_000u64), U256::from(30_000_000_000u64), U256::from(21_000), Some(to), U256::from(10u64.pow(18)), &[], "m/0/0");
+        let decoded = decode_unsigned_tx(&payload).unwrap();
+        assert_eq!(decoded["type"], 2);
+        assert_eq!(decoded["chain_id"], 5);
+        assert_eq!(decoded["nonce"], "3");
+        assert_eq!(decoded["max_priority_fee_per_gas"], "1500000000");
+        assert_eq!(decoded["max_fee_per_gas"], "30000000000");
+        assert_eq!(decoded["gas_limit"], "21000");
+        assert_eq!(decoded["to"], RECIPIENT);
+        assert_eq!(decoded["value"], "1000000000000000000");
+        assert_eq!(decoded["data"], "0x");
+        assert_eq!(decoded["call"], Value::Null);
+        assert_eq!(decoded["signed"], false);
+        assert_eq!(decoded["derivation_path"], "m/0/0");
+        assert_eq!(decoded["hash_matches"], true);
+
+        // The 0x02 prefixed form decodes the same way
+        let prefixed = format!("02{}", payload);
+        assert_eq!(decode_unsigned_tx(&prefixed).unwrap(), decoded);
+    }
 
-        format!("{}:&{}", hex::encode(rlp), base64::encode(&blob))
+    #[test]
+    fn decode_contract_creation() {
+        let payload = build_eip1559_tx(1, U256::zero(), U256::one(), U256::from(2), U256::from(100_000), None, U256::zero(), &[0x60, 0x00], "m/0/0");
+        let decoded = decode_unsigned_tx(&payload).unwrap();
+        assert_eq!(decoded["type"], 2);
+        assert_eq!(decoded["to"], Value::Null);
+        assert_eq!(decoded["data"], "0x6000");
+        assert_eq!(decoded["hash_matches"], true);
     }
 
-    // Batch-query ERC-721 `balanceOf(owner)` for each contract.
-    pub async fn erc721_balance(&self,contract_addresses: Vec<String>,) -> Vec<String> {
-        let owner_clean = self.address.trim_start_matches("0x");
-        let padded_owner = format!("{:0>64}", owner_clean);
+    #[test]
+    fn decode_signed_transactions() {
+        let wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 5);
+        let signature = base64::encode([[1u8; 32], [2u8; 32]].concat().into_iter().chain([1u8]).collect::<Vec<u8>>());
+        let to = Address::from_str(RECIPIENT).unwrap();
+
+        let unsigned = build_eip1559_tx(5, U256::from(3), U256::one(), U256::from(2), U256::from(21_000), Some(to), U256::from(9), &[], "m/0/0");
+        let signed = wallet.construct_signed_tx(unsigned.split(':').next().unwrap().to_string(), signature.clone());
+        assert!(signed.starts_with("0x02"));
+        let decoded = decode_unsigned_tx(&signed).unwrap();
+        assert_eq!(decoded["type"], 2);
+        assert_eq!(decoded["signed"], true);
+        assert_eq!(decoded["chain_id"], 5);
+        assert_eq!(decoded["to"], RECIPIENT);
+        assert_eq!(decoded["value"], "9");
+
+        let unsigned = build_legacy_tx(U256::from(3), U256::from(2), U256::from(21_000), Some(to), U256::from(9), &[], 5, "m/0/0");
+        let signed = wallet.construct_signed_tx(unsigned.split(':').next().unwrap().to_string(), signature);
+        let decoded = decode_unsigned_tx(&signed).unwrap();
+        assert_eq!(decoded["type"], 0);
+        assert_eq!(decoded["signed"], true);
+        assert_eq!(decoded["chain_id"], 5);
+        assert_eq!(decoded["to"], RECIPIENT);
+        assert_eq!(decoded["value"], "9");
+    }
 
-        let mut batch = Vec::new();
-        for (i, contract) in contract_addresses.iter().enumerate() {
-            let call_data = format!("0x70a08231{}", padded_owner);
-            batch.push(json!({
-                "jsonrpc":"2.0",
-                "method":"eth_call",
-                "params":[{ "to": contract, "data": call_data }, "latest"],
-                "id": i + 1
-            }));
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn pending_transactions_are_paid_counterparties() {
+        let mut wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        let signature = base64::encode([[1u8; 32], [2u8; 32]].concat().into_iter().chain([0u8]).collect::<Vec<u8>>());
+        let token = Address::from_str("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48").unwrap();
+        let other = "0x4444444444444444444444444444444444444444";
+        let unsigned = [
+            build_eip1559_tx(1, U256::zero(), U256::one(), U256::from(2), U256::from(21_000), Some(Address::from_str(other).unwrap()), U256::one(), &[], "m/0/0"),
+            build_eip1559_tx(1, U256::one(), U256::one(), U256::from(2), U256::from(60_000), Some(token), U256::zero(), &encode_transfer(RECIPIENT, U256::one()), "m/0/0"),
+        ];
+        for (nonce, tx) in unsigned.iter().enumerate() {
+            let raw_tx = wallet.construct_signed_tx(tx.split(':').next().unwrap().to_string(), signature.clone());
+            wallet.pending_txs.push(PendingTx { hash: String::new(), nonce: nonce as u64, raw_tx });
         }
+        wallet.pending_txs.push(PendingTx { hash: String::new(), nonce: 2, raw_tx: unsigned[0].clone() });
+        assert_eq!(wallet.paid_counterparties(&[]), vec![other.to_string(), RECIPIENT.to_string()]);
+    }
 
-        let client = reqwest::Client::new();
-        let resp = match client
-            .post(&self.infura_url)
-            .header(CONTENT_TYPE, "application/json")
-            .json(&batch)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(_) => return vec!["Error: Infura error.".to_string()],
-        };
+    #[test]
+    fn registered_chains_are_shared_across_threads() {
+        let mut wallet = Wallet::new(XPUB.to_string(), "m/0/0".to_string(), String::new(), 990_001);
+        assert_eq!(wallet.get_multicall_address(), MULTICALL3_ADDRESS);
+        std::thread::spawn(|| {
+            let mut info = ChainInfo::unknown(990_001);
+            info.multicall_address = Some("0x1111111111111111111111111111111111111111".to_string());
+            info.default_tx_type = 2;
+            register_chain(info);
+        }).join().unwrap();
+        assert_eq!(chain_info(990_001).default_tx_type, 2);
+        assert_eq!(wallet.get_multicall_address(), "0x1111111111111111111111111111111111111111");
+        // Unsynced the sender is unknown, afterwards the calldata carries it as the from word
+        assert_eq!(wallet.erc721_transfer_eip1559(RECIPIENT.to_string(), RECIPIENT.to_string(), "1", 0), "Error: Failed to parse the sender address.");
+        assert_eq!(wallet.erc1155_transfer_auto(RECIPIENT.to_string(), RECIPIENT.to_string(), "1", "1", 0), "Error: Failed to parse the sender address.");
+        let from = wallet.address();
+        let decoded = decode_unsigned_tx(&wallet.erc721_transfer_auto(RECIPIENT.to_string(), RECIPIENT.to_string(), "1", 0)).unwrap();
+        assert_eq!(decoded["type"], 2);
+        assert_eq!(decoded["to"], RECIPIENT);
+        assert_eq!(decoded["data"], format!("0x{}", hex::encode(encode_erc721_transfer(&from, RECIPIENT, U256::one()))));
+        assert_eq!(decoded["call"]["function"], "transferFrom(address,address,uint256)");
+        let decoded = decode_unsigned_tx(&wallet.erc1155_transfer_auto(RECIPIENT.to_string(), RECIPIENT.to_string(), "1", "2", 0)).unwrap();
+        assert_eq!(decoded["data"], format!("0x{}", hex::encode(encode_erc1155_transfer(&from, RECIPIENT, U256::one(), U256::from(2)))));
+    }
 
-        if !resp.status().is_success() {
-            return vec!["Error: Infura error.".to_string()];
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn sent_transactions_outlive_pending() {
+        let mut wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        for nonce in 0..(SENT_TXS_MAX as u64 + 2) {
+            wallet.record_sent(PendingTx { hash: format!("0x{:x}", nonce), nonce, raw_tx: String::new() });
         }
+        // What sync does once the nonces are confirmed
+        wallet.pending_txs.retain(|tx| tx.nonce >= SENT_TXS_MAX as u64 + 2);
+        let restored = Wallet::from_json(wallet.to_json()).unwrap();
+        assert!(restored.pending_txs.is_empty());
+        assert_eq!(restored.sent_txs.len(), SENT_TXS_MAX);
+        assert_eq!(restored.sent_txs[0].nonce, 2);
+    }
 
-        let body = match resp.text().await {
-            Ok(t) => t,
-            Err(_) => return vec!["Error: Infura error.".to_string()],
-        };
-        let parsed: Vec<Value> = match serde_json::from_str(&body) {
-            Ok(v) => v,
-            Err(_) => return vec!["Error: JSON parse error.".to_string()],
-        };
+    fn history_tx(hash: &str, block: u64) -> EtherscanTx {
+        serde_json::from_value(json!({
+            "hash": hash, "direction": "received", "from_address": RECIPIENT, "to_address": RECIPIENT,
+            "value_eth": 0.0, "value_wei": "1", "block_number": block.to_string(), "timestamp": block.to_string(),
+        })).unwrap()
+    }
 
-        let mut out = vec!["Success".to_string()];
-        for item in parsed {
-            if let Some(r) = item.get("result").and_then(|v| v.as_str()) {
-                let bal = U256::from_str_radix(r.trim_start_matches("0x"), 16)
-                    .unwrap_or(U256::zero());
-                out.push(bal.to_string());
-            } else {
-                return vec!["Error: Unexpected JSON format.".to_string()];
-            }
-        }
-        out
+    #[test]
+    fn merge_history_pages_keeps_whole_blocks() {
+        // The full native page stops part way through block 90, so only blocks above it are complete
+        let native = vec![history_tx("a", 100), history_tx("b", 95), history_tx("c", 90)];
+        let tokens = vec![history_tx("d", 97), history_tx("e", 80)];
+        let (entries, cursor) = merge_history_pages(vec![(native, true), (tokens, false)], 10).unwrap();
+        assert_eq!(entries.iter().map(|e| e.hash.as_str()).collect::<Vec<_>>(), ["a", "d", "b"]);
+        assert_eq!(cursor, Some(90));
+
+        // Every row of a full page in one block: that block has to be fetched on its own
+        let native = vec![history_tx("a", 100), history_tx("b", 100)];
+        assert_eq!(merge_history_pages(vec![(native, true), (vec![history_tx("c", 50)], false)], 2).unwrap_err(), 100);
     }
 
-    // Query ERC-721 `ownerOf(tokenId)`.
-    pub async fn erc721_owner_of(&self,contract_address: String,token_id: String,) -> String {
-        let id = match U256::from_dec_str(&token_id) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to parse token ID.".to_string(),
-        };
-        let mut id_bytes = [0u8; 32];
-        id.to_big_endian(&mut id_bytes);
+    fn mail_typed_data(to: Value) -> TypedData {
+        let message = json!({
+            "from": { "name": "Cow", "wallet": "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826" },
+            "to": to,
+            "contents": "Hello, Bob!"
+        });
+        serde_json::from_value(json!({
+            "types": {
+                "EIP712Domain": [
+                    { "name": "name", "type": "string" },
+                    { "name": "version", "type": "string" },
+                    { "name": "chainId", "type": "uint256" },
+                    { "name": "verifyingContract", "type": "address" }
+                ],
+                "Person": [
+                    { "name": "name", "type": "string" },
+                    { "name": "wallet", "type": "address" }
+                ],
+                "Mail": [
+                    { "name": "from", "type": "Person" },
+                    { "name": "to", "type": "Person" },
+                    { "name": "contents", "type": "string" }
+                ]
+            },
+            "primaryType": "Mail",
+            "domain": {
+                "name": "Ether Mail",
+                "version": "1",
+                "chainId": 1,
+                "verifyingContract": "0xCcCCccccCCCCcCCCCCCcCcCccCcCCCcCcccccccC"
+            },
+            "message": message
+        }))
+        .unwrap()
+    }
 
-        // build call_data = 0x6352211e + padded tokenId
-        let call_data = {
-            let mut d = Vec::new();
-            d.extend(hex::decode("6352211e").unwrap());
-            d.extend(&id_bytes);
-            format!("0x{}", hex::encode(d))
-        };
+    // keccak256(0x1901 || domainSeparator || keccak256(parts)) with the Mail domain from EIP-712
+    fn mail_digest(parts: &[&str]) -> [u8; 32] {
+        let encoded: Vec<u8> = parts.iter().flat_map(|p| hex::decode(p).unwrap()).collect();
+        let mut input = hex::decode("1901f2cee375fa42b42143804025fc449deafd50cc031ca257e0b194a650a912090f").unwrap();
+        input.extend(keccak256(encoded));
+        keccak256(input)
+    }
 
-        let req = json!({
-            "jsonrpc":"2.0",
-            "method":"eth_call",
-            "params":[{ "to": contract_address, "data": call_data }, "latest"],
-            "id": 1
-        });
-        let client = reqwest::Client::new();
-        let resp = match client
-            .post(&self.infura_url)
-            .header(CONTENT_TYPE, "application/json")
-            .json(&req)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(_) => return "Error: Infura error.".to_string(),
-        };
-        if !resp.status().is_success() {
-            return "Error: Infura error.".to_string();
-        }
-        let j: Value = match resp.json().await {
-            Ok(v) => v,
-            Err(_) => return "Error: JSON parse error.".to_string(),
-        };
-        let result = j.get("result").and_then(|v| v.as_str()).unwrap_or("");
-        let bytes = hex::decode(result.trim_start_matches("0x")).unwrap_or_default();
-        if bytes.len() < 20 {
-            return "Error: Unexpected result length.".to_string();
-        }
-        let addr = &bytes[bytes.len() - 20..];
-        format!("0x{}", hex::encode(addr))
+    const MAIL_TYPE_HASH: &str = "a0cedeb2dc280ba39b857546d74f5549c3a1d7bdc2dd96bf881f76108e23dac2";
+    const PERSON_TYPE_HASH: &str = "b9d8c78acf9b987311de6c7b45bb6a9c8e1bf361fa7fd3467a2163f994c79500";
+    const COW_HASH: &str = "fc71e5fa27ff56c350aa531bc129ebdf613b772b6604664f5d8dbe21b85eb0c8";
+    const CONTENTS_HASH: &str = "b5aadf3154a261abdd9086fc627b61efca26ae5702701d05cd2305f7c52a2fc8";
+
+    #[test]
+    fn typed_data_mail_example() {
+        let typed = mail_typed_data(json!({ "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" }));
+        let expected = "be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2";
+        assert_eq!(hex::encode(typed_data_digest(&typed, TypedDataVersion::V4).unwrap()), expected);
+        assert_eq!(hex::encode(typed_data_digest(&typed, TypedDataVersion::V3).unwrap()), expected);
     }
 
-    // Query ERC-1155 `balanceOf(owner, tokenId)`.
-    pub async fn erc1155_balance_of(&self,contract_address: String,owner: String,token_id: String,) -> String {
-        let id = match U256::from_dec_str(&token_id) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to parse token ID.".to_string(),
-        };
-        let mut id_bytes = [0u8; 32];
-        id.to_big_endian(&mut id_bytes);
+    #[test]
+    fn typed_data_null_nested_struct() {
+        // v4 hashes a null struct as a zero word, v3 encodes it as a Person with no members
+        let typed = mail_typed_data(Value::Null);
+        let zero = "00".repeat(32);
+        let empty_person = hex::encode(keccak256(hex::decode(PERSON_TYPE_HASH).unwrap()));
+        let v4 = typed_data_digest(&typed, TypedDataVersion::V4).unwrap();
+        let v3 = typed_data_digest(&typed, TypedDataVersion::V3).unwrap();
+        assert_eq!(v4, mail_digest(&[MAIL_TYPE_HASH, COW_HASH, &zero, CONTENTS_HASH]));
+        assert_eq!(v3, mail_digest(&[MAIL_TYPE_HASH, COW_HASH, &empty_person, CONTENTS_HASH]));
+    }
 
-        let call_data = {
-            let mut d = Vec::new();
-            d.extend(hex::decode("00fdd58e").unwrap());  // balanceOf selector
-            let owner_clean = owner.trim_start_matches("0x");
-            let owner_bytes = hex::decode(owner_clean).unwrap_or_default();
-            let mut pad_o = vec![0u8; 12];
-            pad_o.extend(owner_bytes);
-            d.extend(pad_o);
-            d.extend(&id_bytes);
-            format!("0x{}", hex::encode(d))
-        };
+    #[test]
+    fn typed_data_missing_members() {
+        // v4 hashes a missing struct as a zero word, v3 leaves the member out
+        let mut typed = mail_typed_data(Value::Null);
+        typed.message.remove("to");
+        let zero = "00".repeat(32);
+        assert_eq!(typed_data_digest(&typed, TypedDataVersion::V4).unwrap(), mail_digest(&[MAIL_TYPE_HASH, COW_HASH, &zero, CONTENTS_HASH]));
+        assert_eq!(typed_data_digest(&typed, TypedDataVersion::V3).unwrap(), mail_digest(&[MAIL_TYPE_HASH, COW_HASH, CONTENTS_HASH]));
+
+        // v4 still requires the atomic members
+        typed.message.remove("contents");
+        assert!(typed_data_digest(&typed, TypedDataVersion::V4).is_err());
+        assert_eq!(typed_data_digest(&typed, TypedDataVersion::V3).unwrap(), mail_digest(&[MAIL_TYPE_HASH, COW_HASH]));
+    }
 
-        let req = json!({
-            "jsonrpc":"2.0",
-            "method":"eth_call",
-            "params":[{ "to": contract_address, "data": call_data }, "latest"],
-            "id": 1
-        });
-        let client = reqwest::Client::new();
-        let resp = match client
-            .post(&self.infura_url)
-            .header(CONTENT_TYPE, "application/json")
-            .json(&req)
-            .send()
-            .await
-        {
-            Ok(r) => r,
-            Err(_) => return "Error: Infura error.".to_string(),
-        };
-        if !resp.status().is_success() {
-            return "Error: Infura error.".to_string();
-        }
-        let j: Value = match resp.json().await {
-            Ok(v) => v,
-            Err(_) => return "Error: JSON parse error.".to_string(),
-        };
-        let result = j.get("result").and_then(|v| v.as_str()).unwrap_or("");
-        let bal = U256::from_str_radix(result.trim_start_matches("0x"), 16)
-            .unwrap_or(U256::zero());
-        bal.to_string()
+    #[test]
+    fn typed_data_v3_rejects_arrays() {
+        let mut typed = mail_typed_data(Value::Null);
+        typed.types.get_mut("Mail").unwrap()[1].r#type = "Person[]".to_string();
+        typed.message.insert("to".to_string(), json!([{ "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" }]));
+        assert!(typed_data_digest(&typed, TypedDataVersion::V4).is_ok());
+        assert_eq!(
+            typed_data_digest(&typed, TypedDataVersion::V3).unwrap_err(),
+            "Error: Arrays are not supported by eth_signTypedData_v3."
+        );
     }
 
-    // Send an ERC-1155 `safeTransferFrom(self.address, to, tokenId, amount, bytes)`.
-    pub fn erc1155_transfer(&self,contract_address: String,to: String,token_id: &str,amount: &str,fee_rate: i32,) -> String {
-        let gas_limit: u64 = 200_000;
-        let tid = match U256::from_dec_str(token_id) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to parse token ID.".to_string(),
-        };
-        let amt = match U256::from_dec_str(amount) {
-            Ok(v) => v,
-            Err(_) => return "Error: Failed to parse amount.".to_string(),
-        };
+    #[test]
+    fn deploy_needs_a_synced_sender() {
+        let wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        let expected = "Error: Sender address and nonce are unknown, sync the wallet first.";
+        assert_eq!(wallet.deploy_contract("0x6000".to_string(), String::new(), "0", "100000", 0), expected);
+        assert_eq!(wallet.deploy_contract_eip1559("0x6000".to_string(), String::new(), "0", "100000", 0), expected);
+    }
 
-        let data = encode_erc1155_transfer(&self.address, &to, tid, amt);
+    #[test]
+    fn offline_nft_transfers() {
+        let wallet = Wallet::new(XPUB.to_string(), "m/0/0".to_string(), String::new(), 1);
+        let from = derive_address(XPUB, "m/0/0").unwrap();
+        let contract = "0x1111111111111111111111111111111111111111";
+
+        let payload = wallet.erc721_transfer_offline(contract.to_string(), RECIPIENT.to_string(), "7", 4, "2000000000", "120000", 137);
+        let decoded = decode_unsigned_tx(&payload).unwrap();
+        assert_eq!(decoded["type"], 0);
+        assert_eq!(decoded["chain_id"], 137);
+        assert_eq!(decoded["nonce"], "4");
+        assert_eq!(decoded["to"], contract);
+        assert_eq!(decoded["data"], format!("0x{}", hex::encode(encode_erc721_transfer(&from, RECIPIENT, U256::from(7)))));
+
+        let payload = wallet.erc1155_transfer_eip1559_offline(contract.to_string(), RECIPIENT.to_string(), "7", "3", 5, "1", "2", "200000", 1);
+        let decoded = decode_unsigned_tx(&payload).unwrap();
+        assert_eq!(decoded["type"], 2);
+        assert_eq!(decoded["nonce"], "5");
+        assert_eq!(decoded["data"], format!("0x{}", hex::encode(encode_erc1155_transfer(&from, RECIPIENT, U256::from(7), U256::from(3)))));
+
+        assert_eq!(
+            wallet.erc1155_transfer_offline(contract.to_string(), "0x12".to_string(), "7", "3", 5, "1", "200000", 1),
+            "Error: Failed to parse the recipient address."
+        );
+    }
 
-        let self_gas = gas_price_from_string(&self.gas_price);
-        let new_gas_price = match fee_rate {
-            1 => self_gas * U256::from(15) / U256::from(10),
-            2 => self_gas * U256::from(20) / U256::from(10),
-            _ => self_gas,
-        };
+    // Mail example from EIP-712 signed with keccak256("cow")
+    const COW_ADDRESS: &str = "0xCD2a3d9F938E13CD947Ec05AbC7FE734Df8DD826";
+    const MAIL_R: &str = "4355c47d63924e8a72e509b65029052eb6c299d53a04e167c5775fd466751c9d";
+    const MAIL_S: &str = "07299936d304c153f6443dfa05f40ff007d72911b6f72307f996231605b91562";
 
-        let mut stream = RlpStream::new_list(9);
-        stream.append(&U256::from(self.nonce));
-        stream.append(&new_gas_price);
-        stream.append(&U256::from(gas_limit));
-        let caddr = Address::from_str(&contract_address).unwrap_or(Address::zero());
-        stream.append(&caddr);
-        stream.append(&U256::zero());
-        stream.append(&data);
-        stream.append(&self.chain_id);
-        stream.append(&0u8);
-        stream.append(&0u8);
-        let rlp = stream.out();
+    #[test]
+    fn eip191_personal_message_hash() {
+        assert_eq!(
+            hex::encode(keccak256(eip191_bytes(b"Hello World"))),
+            "a1de988600a42c4b4ab089b619297c17d53cffae5d5120d82d8a92d0bb3b78f2"
+        );
+    }
+
+    #[test]
+    fn recover_mail_signer() {
+        let digest: [u8; 32] = hex::decode("be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2").unwrap().try_into().unwrap();
+        let full = format!("0x{}{}1c", MAIL_R, MAIL_S);
+        // EIP-2098 puts yParity (1 for v = 28) in the top bit of s
+        let compact = format!("0x{}8{}", MAIL_R, &MAIL_S[1..]);
+        assert_eq!(EvmSignature::parse(full.clone()).unwrap().to_compact(), compact);
+
+        let expected = COW_ADDRESS.to_lowercase();
+        assert_eq!(recover_signer(&digest, &full).unwrap(), expected);
+        assert_eq!(recover_signer(&digest, &compact).unwrap(), expected);
+        let b64 = base64::encode(hex::decode(full.trim_start_matches("0x")).unwrap());
+        assert_eq!(recover_signer(&digest, &b64).unwrap(), expected);
+        assert!(recover_signer(&digest, "0x1234").is_err());
+    }
 
-        let mut h = Keccak::v256();
-        let mut tx_hash = [0u8; 32];
-        h.update(&rlp);
-        h.finalize(&mut tx_hash);
-        let mut blob = tx_hash.to_vec();
-        if let Ok((h1, h2)) = extract_u16s(&self.account_derivation_path) {
-            append_integers_as_bytes(&mut blob, h1, h2);
-        } else {
-            return "Error: Derivation path error.".to_string();
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn verify_typed_data_signatures() {
+        // A matching ecrecover returns before any RPC call, so no node is needed
+        let wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        let typed = mail_typed_data(json!({ "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" }));
+        let json = serde_json::to_string(&typed).unwrap();
+        for signature in [format!("0x{}{}1c", MAIL_R, MAIL_S), format!("0x{}8{}", MAIL_R, &MAIL_S[1..])] {
+            let v4: Value = serde_json::from_str(&futures::executor::block_on(wallet.verify_typed_data(json.clone(), signature.clone(), COW_ADDRESS.to_string()))).unwrap();
+            assert_eq!(v4["valid"], true);
+            assert_eq!(v4["method"], "ecrecover");
+            let v3: Value = serde_json::from_str(&futures::executor::block_on(wallet.verify_typed_data_v3(json.clone(), signature, COW_ADDRESS.to_string()))).unwrap();
+            assert_eq!(v3["valid"], true);
         }
+    }
 
-        format!("{}:&{}", hex::encode(rlp), base64::encode(&blob))
+    #[test]
+    fn typed_data_v1_vector() {
+        // keccak256(keccak256("string messageuint32 valueint8 delta") || keccak256(solidityPack(values)))
+        let fields = vec![
+            json!({ "type": "string", "name": "message", "value": "Hi, Alice!" }),
+            json!({ "type": "uint32", "name": "value", "value": 42 }),
+            json!({ "type": "int8", "name": "delta", "value": -1 }),
+        ];
+        let mut expected = keccak256(b"string messageuint32 valueint8 delta").to_vec();
+        expected.extend(keccak256([&b"Hi, Alice!"[..], &[0, 0, 0, 42], &[0xff]].concat()));
+        let expected = hex::encode(keccak256(expected));
+        assert_eq!(hex::encode(typed_data_v1_digest(&fields).unwrap()), expected);
+        assert!(typed_data_v1_digest(&[]).is_err());
+
+        // The dispatcher sends arrays to v1 and objects to v4
+        let wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        let v1 = wallet.prepare_sign_typed_data("eth_signTypedData".to_string(), serde_json::to_string(&fields).unwrap());
+        assert!(v1.starts_with(&format!("{}:&", expected)));
+        let mail = serde_json::to_string(&mail_typed_data(json!({ "name": "Bob", "wallet": "0xbBbBBBBbbBBBbbbBbbBbbbbBBbBbbbbBbBbbBBbB" }))).unwrap();
+        let v4 = wallet.prepare_sign_typed_data("eth_signTypedData".to_string(), mail);
+        assert!(v4.starts_with("be609aee343fb3c4b28e1df9e632fca64fcfaede20f02e86244efddf30957bd2:&"));
     }
-}
 
-pub fn convert_to_xpub(xpub_str : String) -> String{
-    let zpub_bytes = match bs58::decode(&xpub_str).with_check(None).into_vec(){
-        Ok(zpub_bytes) => zpub_bytes,
-        Err(_) => return "Error: Invalid extended public key.".to_string(),
-    };
-    let new_bytes = &zpub_bytes[4..];
-    let new_prefix = hex_to_vec("0488b21e").unwrap();
-    let mut vec = Vec::from(new_bytes);
-    for i in (0..new_prefix.len()).rev() {
-        vec.insert(0, new_prefix[i]);
+    #[test]
+    fn create_addresses() {
+        let sender = "0x6ac7ea33f8831ea9dcc53393aaa88b25a785dbf0";
+        assert_eq!(create_address(sender, 0), "0xcd234a471b72ba2f1ccf0a70fcaba648a5eecd8d");
+        assert_eq!(create_address(sender, 1), "0x343c43a37d37dff08ae8c4a11544c718abb4fcf8");
+        assert_eq!(create_address(sender, 2), "0xf778b86fa74e846c4f0a1fbd1335fe81c00a0c91");
+        assert!(create_address("", 0).starts_with("Error"));
     }
-    return bs58::encode(vec).with_check().into_string();
-}
-fn gas_price_to_string(gas_price: U256) -> String {
-    return gas_price.to_string();
-}
-// Convert a decimal string to a U256 gas price.
-fn gas_price_from_string(s: &str) -> U256 {
-    let res = match U256::from_dec_str(s){
-        Ok(res) => res,
-        Err(_) => U256::zero(),
-    };
-    return res;
-}
-pub fn hex_to_vec(hex_string: &str) -> Option<Vec<u8>> {
-    if hex_string.len() % 2 != 0 { return None; }
-    let mut bytes = Vec::new();
-    for chunk in hex_string.as_bytes().chunks(2) {
-        if let Ok(byte) = u8::from_str_radix(std::str::from_utf8(chunk).unwrap(), 16) {
-            bytes.push(byte);
-        }else{
-            return None; 
-        }
+
+    #[test]
+    fn create2_addresses() {
+        // EIP-1014 examples
+        let zero = "0x0000000000000000000000000000000000000000";
+        assert_eq!(create2_address(zero, "0x00", &keccak256([0x00])), "0x4d1a2e2bb4f88f0250f26ffff098b0b30b26bf38");
+        assert_eq!(
+            create2_address("0xdeadbeef00000000000000000000000000000000", "0x00", &keccak256([0x00])),
+            "0xb928f69bb1d91cd65274e3c79d8986362984fda3"
+        );
+        assert_eq!(
+            create2_address("0x00000000000000000000000000000000deadbeef", "0xcafebabe", &keccak256([0xde, 0xad, 0xbe, 0xef])),
+            "0x60f3f640a8508fc6a86d45df051962668e1e8ac7"
+        );
+        assert_eq!(create2_address(zero, &format!("0x{}", "00".repeat(33)), &[0u8; 32]), "Error: Salt must be at most 32 bytes of hex.");
+
+        let wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        assert_eq!(wallet.predict_create2_address(zero.to_string(), "0x00".to_string(), "0x00".to_string()), "0x4d1a2e2bb4f88f0250f26ffff098b0b30b26bf38");
     }
-    return Some(bytes);
-}
-fn decode_abi_string(hex_str: &str) -> Option<String> {
-    let hex = hex_str.trim_start_matches("0x");
-    if hex.len() >= 128 {
-        // The first 64 characters are the offset; the next 64 characters contain the string length.
-        let len_hex = &hex[64..128];
-        let len = usize::from_str_radix(len_hex, 16).ok()?;
-        let start = 128;
-        let end = start + len * 2;
-        if hex.len() < end {
-            return None;
-        }
-        let data_hex = &hex[start..end];
-        let bytes = hex_to_vec(data_hex)?;
-        return String::from_utf8(bytes).ok();
-    } else {
-        let bytes = hex_to_vec(hex)?;
-        let s = bytes.into_iter().take_while(|&b| b != 0).collect::<Vec<u8>>();
-        return String::from_utf8(s).ok();
+
+    #[test]
+    fn failed_validations_are_not_restored() {
+        let mut wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        wallet.known_tokens.push(KnownToken { address: RECIPIENT.to_string(), decimals: 0, symbol: String::new(), name: String::new() });
+        wallet.known_tokens.push(KnownToken { address: "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48".to_string(), decimals: 6, symbol: "USDC".to_string(), name: "USD Coin".to_string() });
+        let restored = Wallet::from_json(wallet.to_json()).unwrap();
+        assert_eq!(restored.known_tokens.len(), 1);
+        assert_eq!(restored.known_tokens[0].symbol, "USDC");
     }
-}
-pub fn wei_to_eth(wei: U256) -> f64 {
-    // Convert U256 to a string, then parse as f64.
-    // Note: This approach works well for typical balances,
-    // but may lose precision for extremely large values.
-    let wei_str = wei.to_string();
-    let wei_f64: f64 = match wei_str.parse(){
-        Ok(f6) => f6,
-        Err(_) => return 0.0,
-    };
-    // Divide by 10^18 to get Ether
-    return wei_f64 / 1e18;
-}
-pub fn chunk_and_label(final_str: &str, chunk_size: usize) -> Vec<String> {
-    let total_chunks = (final_str.len() + chunk_size - 1) / chunk_size; // Calculate the number of chunks
-    final_str
-        .chars() // Iterate over characters to respect character boundaries
-        .collect::<Vec<_>>() // Collect characters into a vector for chunking
-        .chunks(chunk_size) // Chunk the vector
-        .enumerate() // Provide index for each chunk
-        .map(|(index, chunk)| {
-            let chunk_str = chunk.iter().collect::<String>(); // Convert chunk to string
-            format!("({}/{}){}", index, total_chunks, chunk_str) // Format with index and total
-        })
-        .collect() // Collect into a vector of strings
-}
-// Helper function to encode ERC20 transfer data.
-pub fn encode_transfer(recipient: &str, amount: U256) -> Vec<u8> {
-    let mut data = Vec::new();
-    // Function selector for transfer(address,uint256): "a9059cbb"
-    let selector = hex::decode("a9059cbb").expect("Invalid function selector");
-    data.extend(selector);
 
-    // Encode recipient address.
-    let recipient_clean = recipient.trim_start_matches("0x");
-    let recipient_bytes = hex::decode(recipient_clean).expect("Invalid recipient address");
-    let mut padded_recipient = vec![0u8; 12]; // 32 - 20 = 12 bytes of zero padding.
-    padded_recipient.extend(recipient_bytes);
-    data.extend(padded_recipient);
+    #[test]
+    fn typed_data_v1_arrays() {
+        // ethereumjs-abi solidityPack packs array elements at 256 bits, strings and bytesN stay raw
+        let fields = vec![
+            json!({ "type": "string[]", "name": "words", "value": ["a", "bc"] }),
+            json!({ "type": "bytes4[]", "name": "tags", "value": ["0x01020304"] }),
+            json!({ "type": "int8[]", "name": "deltas", "value": [-1] }),
+            json!({ "type": "bool[2]", "name": "flags", "value": [true, false] }),
+            json!({ "type": "address[]", "name": "to", "value": [RECIPIENT] }),
+        ];
+        let packed = [
+            hex::encode("abc"),
+            "01020304".to_string(),
+            "ff".repeat(32),
+            format!("{}01", "00".repeat(31)),
+            "00".repeat(32),
+            format!("{}{}", "00".repeat(12), "35".repeat(20)),
+        ].concat();
+        let mut expected = keccak256(b"string[] wordsbytes4[] tagsint8[] deltasbool[2] flagsaddress[] to").to_vec();
+        expected.extend(keccak256(hex::decode(packed).unwrap()));
+        assert_eq!(typed_data_v1_digest(&fields).unwrap(), keccak256(expected));
+
+        let too_many = vec![json!({ "type": "bool[1]", "name": "flags", "value": [true, false] })];
+        assert!(typed_data_v1_digest(&too_many).is_err());
+    }
 
-    // Encode amount as a 32-byte big-endian integer.
-    let mut amount_bytes = [0u8; 32];
-    amount.to_big_endian(&mut amount_bytes);
-    data.extend_from_slice(&amount_bytes);
+    fn export_tx(hash: &str, kind: &str, direction: &str, value_wei: &str, fee_wei: &str, status: &str) -> EtherscanTx {
+        serde_json::from_value(json!({
+            "hash": hash, "direction": direction, "from_address": "0x1111111111111111111111111111111111111111", "to_address": RECIPIENT,
+            "value_eth": 0.0, "value_wei": value_wei, "block_number": "100", "timestamp": "1704067200",
+            "kind": kind, "fee_wei": fee_wei, "status": status,
+        })).unwrap()
+    }
 
-    return data;
-}
-pub fn extract_u16s(input: &str) -> Result<(u16, u16), &'static str> {
-        let parts: Vec<&str> = input.split('/').collect();
-        if parts.len() != 3 {
-            return Err("Error: Invalid format.");
-        }
-        let first_u16 = parts[1].parse::<u16>().map_err(|_| "Error: Failed to parse first number.")?;
-        let second_u16 = parts[2].parse::<u16>().map_err(|_| "Error: Failed to parse second number.")?;
-        return Ok((first_u16, second_u16))
-}
-pub fn append_integers_as_bytes(vec: &mut Vec<u8>, addressdepth: u16, changedepth: u16) {
-    let addressdepth_bytes = addressdepth.to_le_bytes();
-    let changedepth_bytes = changedepth.to_le_bytes();
-    vec.extend_from_slice(&addressdepth_bytes);
-    vec.extend_from_slice(&changedepth_bytes);
-}
+    #[test]
+    fn export_rows_charge_fees_once() {
+        let chain = chain_info(1);
+        let mut token = export_tx("0xa", "erc20", "sent", "1500000", "21000000000000", "success");
+        token.token_symbol = Some("USDC".to_string());
+        token.token_decimals = Some(6);
+        let entries = vec![
+            export_tx("0xa", "native", "sent", "1000000000000000000", "21000000000000", "success"),
+            token,
+            export_tx("0xb", "native", "sent", "5", "42000000000000", "failed"),
+            export_tx("0xc", "native", "received", "2000000000000000000", "21000000000000", "success"),
+        ];
+        let rows = export_rows(&entries, &chain);
+        // The native row of 0xa pays the fee, the token row of the same hash doesn't
+        assert_eq!((rows[0].amount.as_str(), rows[0].fee.as_deref()), ("1", Some("0.000021")));
+        assert_eq!((rows[1].asset.as_str(), rows[1].amount.as_str(), rows[1].fee.as_deref()), ("USDC", "1.5", None));
+        // A failed transaction moves nothing but still pays, a received one pays nothing
+        assert_eq!((rows[2].amount.as_str(), rows[2].fee.as_deref()), ("0", Some("0.000042")));
+        assert!(rows[2].is_fee_only());
+        assert_eq!((rows[3].amount.as_str(), rows[3].fee.as_deref()), ("2", None));
+
+        let koinly = export_history(&entries, ExportFormat::Koinly, &chain);
+        let lines: Vec<&str> = koinly.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[3], format!("2024-01-01T00:00:00Z,0.000042,ETH,,,,,,,cost,native to {},0xb", RECIPIENT));
+        assert_eq!(lines[4], "2024-01-01T00:00:00Z,,,2,ETH,,,,,,native from 0x1111111111111111111111111111111111111111,0xc");
+    }
 
-//NFT Encoding helper functions, ERC721 ERC1155
-pub fn encode_erc721_transfer(from: &str, to: &str, token_id: U256) -> Vec<u8> {
-    let mut data = Vec::new();
-    // function selector for transferFrom(address,address,uint256)
-    data.extend(hex::decode("23b872dd").expect("Invalid selector"));
+    #[test]
+    fn export_marks_unknown_decimals_and_formulas() {
+        let chain = chain_info(1);
+        let mut spam = export_tx("0xd", "erc20", "received", "1000000", "0", "success");
+        spam.token_symbol = Some("=HYPERLINK(\"http://x\")".to_string());
+        let csv = export_history(&[spam], ExportFormat::Csv, &chain);
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.contains(",\"'=HYPERLINK(\"\"http://x\"\")\","));
+        assert!(row.ends_with(",,,,success,\"unknown decimals, raw amount 1000000\""));
+
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@SUM(A1)"), "'@SUM(A1)");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("USDC"), "USDC");
+    }
 
-    // from
-    let from_clean = from.trim_start_matches("0x");
-    let from_bytes = hex::decode(from_clean).expect("Invalid from address");
-    let mut pad_from = vec![0u8; 12];
-    pad_from.extend(from_bytes);
-    data.extend(pad_from);
+    #[test]
+    fn format_units_trims_and_pads() {
+        assert_eq!(format_units(U256::from(1_500_000), 6), "1.5");
+        assert_eq!(format_units(U256::from(5), 18), "0.000000000000000005");
+        assert_eq!(format_units(U256::from(2_000_000), 6), "2");
+        assert_eq!(format_units(U256::zero(), 18), "0");
+        assert_eq!(format_units(U256::from(42), 0), "42");
+        assert_eq!(format_units(U256::MAX, 18), "115792089237316195423570985008687907853269984665640564039457.584007913129639935");
+    }
 
-    // to
-    let to_clean = to.trim_start_matches("0x");
-    let to_bytes = hex::decode(to_clean).expect("Invalid to address");
-    let mut pad_to = vec![0u8; 12];
-    pad_to.extend(to_bytes);
-    data.extend(pad_to);
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn portfolio_totals() {
+        const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+        let account = |path: &str, chain_id: u64, native: u64, usdc: Option<u64>| AccountBalance {
+            derivation_path: path.to_string(),
+            address: RECIPIENT.to_string(),
+            chain_id,
+            native: AssetBalance::new(chain_id, None, "ETH", "Ether", 18, U256::from(native) * U256::exp10(17)),
+            tokens: usdc.map(|u| AssetBalance::new(chain_id, Some(if path == "m/0/0" { USDC.to_string() } else { USDC.to_lowercase() }), "USDC", "USD Coin", 6, U256::from(u))).into_iter().collect(),
+        };
+        let mut snapshot = PortfolioSnapshot {
+            balances: vec![account("m/0/0", 1, 15, Some(1_500_000)), account("m/0/1", 1, 5, Some(500_000)), account("m/0/0", 10, 1, None)],
+            account_totals: Vec::new(),
+            chain_totals: Vec::new(),
+            errors: Vec::new(),
+        };
+        snapshot.compute_totals();
+
+        // Accounts list every asset on every chain, chains sum each asset over the accounts
+        assert_eq!(snapshot.account_totals.len(), 2);
+        assert_eq!(snapshot.account_totals[0].assets.len(), 3);
+        assert_eq!(snapshot.account_totals[1].assets.len(), 2);
+        assert_eq!(snapshot.chain_totals.len(), 2);
+        let mainnet = &snapshot.chain_totals[0];
+        assert_eq!(mainnet.native.balance_formatted, "2");
+        // The contract address matches case-insensitively
+        assert_eq!(mainnet.tokens.len(), 1);
+        assert_eq!(mainnet.tokens[0].balance, "2000000");
+        assert_eq!(mainnet.tokens[0].balance_formatted, "2");
+        assert_eq!(snapshot.chain_totals[1].native.balance_formatted, "0.1");
+    }
 
-    // tokenId
-    let mut id_bytes = [0u8; 32];
-    token_id.to_big_endian(&mut id_bytes);
-    data.extend_from_slice(&id_bytes);
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn portfolio_sync_reports_bad_accounts() {
+        let mut portfolio = Portfolio::new("not an xpub".to_string());
+        portfolio.add_account("m/0/0".to_string());
+        let snapshot: PortfolioSnapshot = serde_json::from_str(&futures::executor::block_on(portfolio.sync())).unwrap();
+        assert_eq!(snapshot.errors.len(), 1);
+        assert!(snapshot.errors[0].starts_with("Account m/0/0: Error"));
+    }
 
-    data
-}
+    const SIWE_EXAMPLE: &str = "service.invalid wants you to sign in with your Ethereum account:\n\
+0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2\n\
+\n\
+I accept the ServiceOrg Terms of Service: https://service.invalid/tos\n\
+\n\
+URI: https://service.invalid/login\n\
+Version: 1\n\
+Chain ID: 1\n\
+Nonce: 32891756\n\
+Issued At: 2021-09-30T16:25:24Z\n\
+Expiration Time: 2021-10-01T16:25:24Z\n\
+Resources:\n\
+- ipfs://bafybeiemxf5abjwjbikoz4mc3a3dla6ual3jsgpdr4cjr3oz3evfyavhwq/\n\
+- https://example.com/my-web2-claim.json";
+
+    #[test]
+    fn siwe_parse_example() {
+        let siwe = SiweMessage::parse(SIWE_EXAMPLE).unwrap();
+        assert_eq!(siwe.domain, "service.invalid");
+        assert_eq!(siwe.address, "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2");
+        assert_eq!(siwe.statement.as_deref(), Some("I accept the ServiceOrg Terms of Service: https://service.invalid/tos"));
+        assert_eq!(siwe.uri, "https://service.invalid/login");
+        assert_eq!(siwe.chain_id, 1);
+        assert_eq!(siwe.nonce, "32891756");
+        assert_eq!(siwe.expiration_time.as_deref(), Some("2021-10-01T16:25:24Z"));
+        assert_eq!(siwe.resources.len(), 2);
+        assert_eq!(parse_rfc3339(&siwe.issued_at), Some(1_633_019_124));
+
+        assert!(SiweMessage::parse("hello").is_err());
+        assert!(SiweMessage::parse(&SIWE_EXAMPLE.replace("Nonce: 32891756\n", "")).is_err());
+        assert!(SiweMessage::parse(&SIWE_EXAMPLE.replace("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2", "0x1234")).is_err());
+    }
 
-pub fn encode_erc1155_balance(owner: &str, token_id: U256) -> Vec<u8> {
-    let mut data = Vec::new();
-    // function selector for balanceOf(address,uint256)
-    data.extend(hex::decode("00fdd58e").expect("Invalid selector"));
+    #[test]
+    fn siwe_warnings() {
+        let siwe = SiweMessage::parse(SIWE_EXAMPLE).unwrap();
+        let codes = |w: Vec<SiweWarning>| w.into_iter().map(|w| w.code).collect::<Vec<_>>();
+        let issued = 1_633_019_124;
 
-    // owner
-    let owner_clean = owner.trim_start_matches("0x");
-    let owner_bytes = hex::decode(owner_clean).expect("Invalid owner address");
-    let mut pad_owner = vec![0u8; 12];
-    pad_owner.extend(owner_bytes);
-    data.extend(pad_owner);
+        // A matching origin, address (any case), chain and time is clean
+        assert!(siwe.warnings("0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2", 1, "https://service.invalid/login", issued + 60).is_empty());
 
-    // tokenId
-    let mut id_bytes = [0u8; 32];
-    token_id.to_big_endian(&mut id_bytes);
-    data.extend_from_slice(&id_bytes);
+        let all = codes(siwe.warnings(RECIPIENT, 5, "https://evil.invalid", issued + 2 * 86_400));
+        assert_eq!(all, ["domain_mismatch", "address_mismatch", "wrong_chain", "expired"]);
+        assert_eq!(codes(siwe.warnings(&siwe.address, 1, "service.invalid", issued - 600)), ["issued_in_future"]);
 
-    data
-}
+        let mut odd = siwe.clone();
+        odd.version = "2".to_string();
+        odd.nonce = "abc".to_string();
+        odd.not_before = Some("2021-09-30T17:00:00Z".to_string());
+        assert_eq!(codes(odd.warnings(&siwe.address, 1, "service.invalid", issued)), ["unsupported_version", "invalid_nonce", "not_yet_valid"]);
+    }
 
+    #[cfg(feature = "rpc")]
+    #[test]
+    fn discovery_stops_at_the_gap() {
+        use std::cell::RefCell;
+        let used: Vec<String> = ["m/0/0", "m/0/3", "m/1/1"].iter().map(|p| derive_address(XPUB, p).unwrap()).collect();
+        let batches = RefCell::new(Vec::new());
+        let scan = |gap_limit: u32, max_branches: u32| {
+            batches.borrow_mut().clear();
+            let found = futures::executor::block_on(scan_accounts(XPUB, gap_limit, max_branches, |addresses: Vec<String>| {
+                batches.borrow_mut().push(addresses.len());
+                let activity = addresses.iter()
+                    .map(|a| if used.contains(a) { (U256::from(1), U256::zero()) } else { (U256::zero(), U256::zero()) })
+                    .collect::<Vec<_>>();
+                async move { Ok(activity) }
+            })).unwrap();
+            found.into_iter().map(|a| a.derivation_path).collect::<Vec<_>>()
+        };
 
-pub fn encode_erc1155_transfer(from: &str, to: &str, token_id: U256, amount: U256) -> Vec<u8> {
-    let mut data = Vec::new();
-    // function selector for safeTransferFrom(address,address,uint256,uint256,bytes)
-    data.extend(hex::decode("f242432a").expect("Invalid selector"));
+        // Two unused in a row end branch 0 before m/0/3, and the empty branch 2 ends the scan
+        assert_eq!(scan(2, 5), ["m/0/0", "m/1/1"]);
+        assert_eq!(*batches.borrow(), [2, 1, 2, 2, 2]);
+        assert_eq!(scan(3, 5), ["m/0/0", "m/0/3", "m/1/1"]);
+        assert_eq!(scan(3, 1), ["m/0/0", "m/0/3"]);
 
-    // from
-    let from_clean = from.trim_start_matches("0x");
-    let from_bytes = hex::decode(from_clean).expect("Invalid from address");
-    let mut pad_from = vec![0u8; 12];
-    pad_from.extend(from_bytes);
-    data.extend(pad_from);
+        // Batches only cover the rest of the gap window, at most DISCOVERY_BATCH_SIZE at a time
+        scan(120, 1);
+        assert_eq!(*batches.borrow(), [50, 50, 24]);
+        scan(0, 1);
+        assert_eq!(*batches.borrow(), [20, 4]);
 
-    // to
-    let to_clean = to.trim_start_matches("0x");
-    let to_bytes = hex::decode(to_clean).expect("Invalid to address");
-    let mut pad_to = vec![0u8; 12];
-    pad_to.extend(to_bytes);
-    data.extend(pad_to);
+        let failed = futures::executor::block_on(scan_accounts(XPUB, 2, 1, |_| async { Ok(Vec::new()) }));
+        assert_eq!(failed.unwrap_err(), "Error: Failed to query account activity.");
+    }
 
-    // tokenId
-    let mut id_bytes = [0u8; 32];
-    token_id.to_big_endian(&mut id_bytes);
-    data.extend(&id_bytes);
+    // Left-pads a hex value to one 32-byte ABI word
+    fn abi_word(value: &str) -> String {
+        format!("{:0>64}", value.trim_start_matches("0x").to_lowercase())
+    }
 
-    // amount
-    let mut amt_bytes = [0u8; 32];
-    amount.to_big_endian(&mut amt_bytes);
-    data.extend(&amt_bytes);
+    #[test]
+    fn erc721_calldata() {
+        let from = "0x1111111111111111111111111111111111111111";
+        let head = format!("{}{}{}", abi_word(from), abi_word(RECIPIENT), abi_word("2a"));
+
+        let safe = encode_erc721_safe_transfer(from, RECIPIENT, "42", "").unwrap();
+        assert_eq!(hex::encode(safe), format!("42842e0e{}", head));
+        let with_data = encode_erc721_safe_transfer(from, RECIPIENT, "42", "0xdeadbeef").unwrap();
+        assert_eq!(hex::encode(with_data), format!("b88d4fde{}{}{}{:0<64}", head, abi_word("80"), abi_word("4"), "deadbeef"));
+
+        let approve = encode_erc721_approve(RECIPIENT, "1").unwrap();
+        assert_eq!(hex::encode(approve), format!("095ea7b3{}{}", abi_word(RECIPIENT), abi_word("1")));
+        let approve_all = encode_set_approval_for_all(RECIPIENT, true).unwrap();
+        assert_eq!(hex::encode(approve_all), format!("a22cb465{}{}", abi_word(RECIPIENT), abi_word("1")));
+        let revoke_all = encode_set_approval_for_all(RECIPIENT, false).unwrap();
+        assert_eq!(hex::encode(revoke_all), format!("a22cb465{}{}", abi_word(RECIPIENT), abi_word("0")));
+
+        assert_eq!(encode_erc721_safe_transfer(from, RECIPIENT, "0x2a", "").unwrap_err(), "Error: Failed to parse token ID.");
+        assert_eq!(encode_erc721_safe_transfer(from, RECIPIENT, "42", "0xzz").unwrap_err(), "Error: Failed to decode the data field.");
+        assert!(encode_erc721_approve("0x1234", "1").is_err());
+    }
 
-    // offset to the dynamic `bytes data` (5 * 32 = 160)
-    let mut offset = [0u8; 32];
-    offset[31] = 160;
-    data.extend(&offset);
+    #[test]
+    fn erc1155_batch_calldata() {
+        let from = "0x1111111111111111111111111111111111111111";
+        let ids = ["1".to_string(), "2".to_string()];
+        let batch = encode_erc1155_batch_transfer(from, RECIPIENT, &ids, &["10".to_string(), "20".to_string()], "").unwrap();
+        let expected = [
+            abi_word(from), abi_word(RECIPIENT), abi_word("a0"), abi_word("100"), abi_word("160"),
+            abi_word("2"), abi_word("1"), abi_word("2"),
+            abi_word("2"), abi_word("a"), abi_word("14"),
+            abi_word("0"),
+        ].concat();
+        assert_eq!(hex::encode(batch), format!("2eb2c2d6{}", expected));
+
+        let owners = [from.to_string(), RECIPIENT.to_string()];
+        let balances = encode_erc1155_balance_of_batch(&owners, &ids).unwrap();
+        let expected = [
+            abi_word("40"), abi_word("a0"),
+            abi_word("2"), abi_word(from), abi_word(RECIPIENT),
+            abi_word("2"), abi_word("1"), abi_word("2"),
+        ].concat();
+        assert_eq!(hex::encode(balances), format!("4e1273f4{}", expected));
+
+        assert!(encode_erc1155_batch_transfer(from, RECIPIENT, &ids, &["10".to_string()], "").is_err());
+        assert!(encode_erc1155_batch_transfer(from, RECIPIENT, &[], &[], "").is_err());
+        assert_eq!(encode_erc1155_batch_transfer(from, RECIPIENT, &ids, &["10".to_string(), "x".to_string()], "").unwrap_err(), "Error: Failed to parse amount.");
+        assert!(encode_erc1155_balance_of_batch(&owners[..1], &ids).is_err());
+    }
 
-    // length of `data` = 0
-    data.extend(&[0u8; 32]);
+    #[test]
+    fn token_uris_resolve_through_gateways() {
+        let ipfs = "https://ipfs.io/ipfs/";
+        let arweave = "https://arweave.net";
+        assert_eq!(gateway_url("ipfs://QmHash/1.json", ipfs, arweave), "https://ipfs.io/ipfs/QmHash/1.json");
+        assert_eq!(gateway_url("ipfs://ipfs/QmHash/1.json", ipfs, arweave), "https://ipfs.io/ipfs/QmHash/1.json");
+        assert_eq!(gateway_url(" ar://TxId ", ipfs, arweave), "https://arweave.net/TxId");
+        assert_eq!(gateway_url("https://example.com/1.json", ipfs, arweave), "https://example.com/1.json");
+
+        // The ERC1155 {id} is the 64-digit lowercase hex of the token ID
+        let resolved = resolve_token_uri("ipfs://QmHash/{id}.json", U256::from(314_592u64), ipfs, arweave);
+        assert_eq!(resolved, format!("https://ipfs.io/ipfs/QmHash/{:0>64}.json", "4cce0"));
+
+        assert_eq!(decode_data_uri("data:application/json;base64,eyJuYW1lIjoiQSJ9").unwrap(), br#"{"name":"A"}"#);
+        assert_eq!(decode_data_uri("data:application/json,%7B%22name%22%3A%22A%22%7D").unwrap(), br#"{"name":"A"}"#);
+        assert_eq!(decode_data_uri("data:text/plain,100%25%zz").unwrap(), b"100%%zz");
+        assert_eq!(decode_data_uri("data:application/json;base64,!!"), None);
+        assert_eq!(decode_data_uri("https://example.com"), None);
+
+        let json = json!({"name": "A", "image": "ipfs://QmImage", "attributes": [{"trait_type": "Eyes", "value": "Blue"}, {"trait_type": "No value"}]});
+        let metadata = parse_nft_metadata(&json, ipfs, arweave);
+        assert_eq!(metadata.image.as_deref(), Some("https://ipfs.io/ipfs/QmImage"));
+        assert_eq!(metadata.attributes.len(), 1);
+    }
 
-    data
-}
+    #[test]
+    fn nft_holdings_net_received_minus_sent() {
+        let entry = |kind: &str, direction: &str, contract: &str, token_id: &str, value: &str| HistoryEntry {
+            hash: "0x1".to_string(),
+            block_number: Some(1),
+            log_index: Some(0),
+            timestamp: None,
+            kind: kind.to_string(),
+            direction: direction.to_string(),
+            from_address: RECIPIENT.to_string(),
+            to_address: RECIPIENT.to_string(),
+            contract_address: Some(contract.to_string()),
+            token_id: Some(token_id.to_string()),
+            value: value.to_string(),
+            status: "confirmed".to_string(),
+        };
+        let punks = "0xB47E3CD837DDF8E4C57F05D70AB865DE6E193BBB";
+        let items = "0x76be3b62873462d2142405439777e971754e8e77";
+        let entries = vec![
+            entry("erc721", "received", punks, "1", "1"),
+            entry("erc721", "sent", punks, "1", "1"),
+            entry("erc721", "received", punks, "2", "1"),
+            entry("erc1155", "received", items, "7", "5"),
+            entry("erc1155", "sent", items, "7", "2"),
+            entry("erc1155", "self", items, "8", "3"),
+            entry("erc20", "received", items, "9", "1"),
+            entry("erc721", "received", punks, "not a number", "1"),
+        ];
+        let holdings = nft_holding_candidates(&entries);
+        assert_eq!(holdings, [
+            (punks.to_lowercase(), "erc721".to_string(), U256::from(2)),
+            (items.to_string(), "erc1155".to_string(), U256::from(7)),
+        ]);
+    }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct EtherscanTx {
-    pub hash: String,
-    pub direction: String,
-    pub from_address: String,
-    pub to_address: String,
-    pub value_eth: f64,
-    pub value_wei: String,
-    pub block_number: String,
-    pub timestamp: String,
-}
\ No newline at end of file
+    #[test]
+    fn classify_contract_standards() {
+        let word = |v: u64| -> Result<Vec<u8>, String> { Ok(ethabi::encode(&[abi_uint(U256::from(v))])) };
+        let string = |v: &str| -> Result<Vec<u8>, String> { Ok(ethabi::encode(&[ethabi::Token::String(v.to_string())])) };
+        let bytes32 = |v: &str| -> Result<Vec<u8>, String> { Ok(format!("{:\0<32}", v).into_bytes()) };
+        let reverted = || -> Result<Vec<u8>, String> { Err("execution reverted".to_string()) };
+
+        // ERC721 with metadata, supportsInterface(0xffffffff) false
+        let nft = vec![word(1), word(0), word(1), word(1), word(0), word(0), word(0), reverted(), string("BAYC"), string("BoredApeYachtClub"), word(10_000)];
+        let info = classify_contract("0xbc4ca0eda7647a8ab7c2061c2e118a18a936f13d", &nft, None);
+        assert_eq!((info.standard.as_str(), info.builder.as_str()), ("erc721", "erc721_safe_transfer"));
+        assert!(info.interfaces.erc165 && info.interfaces.erc721_metadata && !info.interfaces.erc721_enumerable);
+        assert_eq!(info.name.as_deref(), Some("BoredApeYachtClub"));
+
+        // A fallback that answers true to everything is not ERC-165 and claims no interfaces
+        let liar = vec![word(1), word(1), word(1), word(1), word(1), word(1), word(1), reverted(), reverted(), reverted(), reverted()];
+        let info = classify_contract("0x1", &liar, None);
+        assert!(!info.interfaces.erc165 && !info.interfaces.erc721 && !info.interfaces.erc1155);
+        assert_eq!((info.standard.as_str(), info.builder.as_str()), ("unknown", "contract_call"));
+
+        // MKR returns bytes32 symbol and name
+        let mkr = vec![reverted(), reverted(), reverted(), reverted(), reverted(), reverted(), reverted(), word(18), bytes32("MKR"), bytes32("Maker"), word(1_000)];
+        let info = classify_contract("0x9f8f72aa9304c8b593d555f12ef6589cc3a579a2", &mkr, None);
+        assert_eq!(info.standard, "erc20");
+        assert_eq!((info.symbol.as_deref(), info.name.as_deref(), info.decimals), (Some("MKR"), Some("Maker"), Some(18)));
+        assert_eq!(info.total_supply.as_deref(), Some("1000"));
+
+        // decimals() above 255 is not an ERC20 decimals value
+        let mut odd = mkr.clone();
+        odd[7] = word(256);
+        assert_eq!(classify_contract("0x1", &odd, None).standard, "unknown");
+    }
+
+    const USDC: &str = "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48";
+
+    #[test]
+    fn token_lists_parse_and_merge() {
+        let list = json!({
+            "name": "Example List",
+            "tokens": [
+                { "chainId": 1, "address": USDC, "decimals": 6, "symbol": "USDC", "name": "USD Coin", "logoURI": "ipfs://QmUsdc" },
+                { "chainId": 137, "address": "0x2791Bca1f2de4661ED88A30C99A7a9449Aa84174", "decimals": 6, "symbol": "USDC", "name": "USD Coin" },
+                { "chainId": 1, "address": USDC.to_lowercase(), "decimals": 6, "symbol": "USDC", "name": "USD Coin" },
+                { "chainId": 1, "address": "0x1234", "decimals": 18, "symbol": "BAD", "name": "Bad" },
+                { "chainId": 1, "address": RECIPIENT, "decimals": 256, "symbol": "BIG", "name": "Big" },
+                { "chainId": 1, "address": RECIPIENT, "decimals": 18, "symbol": "TWO WORDS", "name": "Spaces" },
+                { "chainId": 1, "address": "0x6B175474E89094C44Da98b954EedeAC495271d0F", "decimals": 18, "symbol": "DAI", "name": "Dai Stablecoin" },
+            ],
+        });
+        let (parsed, invalid, other_chains) = parse_token_list(&list.to_string(), 1).unwrap();
+        assert_eq!(parsed.name, "Example List");
+        assert_eq!(parsed.tokens.iter().map(|t| t.symbol.as_str()).collect::<Vec<_>>(), ["USDC", "DAI"]);
+        assert_eq!(other_chains, 1);
+        let errors = invalid.iter().map(|v| (v["index"].as_u64().unwrap(), v["error"].as_str().unwrap())).collect::<Vec<_>>();
+        assert_eq!(errors, [
+            (2, "Error: Duplicate token address."),
+            (3, "Error: Failed to parse the token address."),
+            (4, "Error: Invalid decimals."),
+            (5, "Error: Invalid symbol."),
+        ]);
+        assert_eq!(parse_token_list(r#"{"tokens":[]}"#, 1).err().as_deref(), Some("Error: The token list has no name."));
+        assert!(parse_token_list("[", 1).is_err());
+
+        let usdc = &parsed.tokens[0];
+        assert!(token_mismatches(usdc, Some(6), Some("USDC"), Some(" USD Coin ")).is_empty());
+        assert_eq!(token_mismatches(usdc, Some(18), Some("USDC.e"), None), ["decimals_mismatch", "symbol_mismatch"]);
+        assert_eq!(token_mismatches(usdc, None, Some("USDC"), Some("USD Coin")), ["not_erc20"]);
+
+        // A user token keeps its metadata and picks up the list as a source, the logo and any disagreement
+        let known = vec![KnownToken { address: USDC.to_lowercase(), decimals: 6, symbol: "USDC".to_string(), name: "USDC".to_string() }];
+        let mut dai = parsed.tokens[1].clone();
+        dai.mismatches = vec!["decimals_mismatch".to_string()];
+        let registry = merge_token_registry(&known, &[usdc.clone(), dai]);
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry[0].name, "USDC");
+        assert_eq!(registry[0].sources, ["user", "Example List"]);
+        assert_eq!(registry[0].flags, ["name_mismatch"]);
+        assert_eq!(registry[0].logo_uri.as_deref(), Some("ipfs://QmUsdc"));
+        assert_eq!(registry[1].sources, ["Example List"]);
+        assert_eq!(registry[1].flags, ["decimals_mismatch"]);
+    }
+
+    // Shares the first and last four hex characters with RECIPIENT
+    const POISONER: &str = "0x3535000000000000000000000000000000003535";
+
+    fn registry_token(address: &str, symbol: &str, flags: &[&str]) -> RegistryToken {
+        RegistryToken {
+            address: address.to_string(),
+            decimals: 18,
+            symbol: symbol.to_string(),
+            name: symbol.to_string(),
+            logo_uri: None,
+            sources: vec!["user".to_string()],
+            flags: flags.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn token_risk_heuristics() {
+        assert!(is_lookalike_address(RECIPIENT, POISONER));
+        assert!(is_lookalike_address(&RECIPIENT.to_uppercase().replacen("0X", "0x", 1), POISONER));
+        assert!(!is_lookalike_address(RECIPIENT, RECIPIENT));
+        assert!(!is_lookalike_address(RECIPIENT, "0x3536000000000000000000000000000000003535"));
+        assert!(!is_lookalike_address(RECIPIENT, "0x35353535"));
+
+        let reasons = |risk: TokenRisk| (risk.score, risk.level, risk.reasons);
+        assert_eq!(reasons(assess_token(USDC, "USDC", "USD Coin", 1, &[])), (0, "low".to_string(), vec![]));
+
+        let fake = assess_token(RECIPIENT, "usdc", "USD Coin", 1, &[]);
+        assert_eq!(fake.impersonates.as_deref(), Some("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48"));
+        assert_eq!(reasons(fake), (80, "high".to_string(), vec!["impersonation".to_string()]));
+        assert_eq!(assess_token(RECIPIENT, "ETH", "Ether", 1, &[]).reasons, ["impersonation"]);
+        assert_eq!(reasons(assess_token(RECIPIENT, "UЅDC", "USD Coin", 1, &[])), (40, "medium".to_string(), vec!["non_ascii_characters".to_string()]));
+        assert_eq!(
+            assess_token(RECIPIENT, "$ claim-rewards.com", "Visit www.rewards.xyz", 1, &[]).reasons,
+            ["url_in_name", "url_in_symbol"],
+        );
+
+        // Registry tokens without flags are genuine for their symbol, flagged ones are a mismatch
+        let registry = vec![registry_token(POISONER, "GOOD", &[]), registry_token(USDC, "USDC", &["decimals_mismatch"])];
+        assert_eq!(assess_token(RECIPIENT, "GOOD", "Good", 1, &registry).reasons, ["impersonation", "not_in_registry"]);
+        assert_eq!(assess_token(POISONER, "GOOD", "Good", 1, &registry).score, 0);
+        assert_eq!(reasons(assess_token(USDC, "USDC", "USD Coin", 1, &registry)), (30, "medium".to_string(), vec!["registry_mismatch".to_string()]));
+        assert_eq!(assess_token("0x2222222222222222222222222222222222222222", "NEW", "New", 1, &registry).reasons, ["not_in_registry"]);
+    }
+
+    #[test]
+    fn transfer_risk_heuristics() {
+        let paid = export_tx("0xa", "native", "sent", "1000000000000000000", "0", "success");
+        let mut poison = export_tx("0xb", "erc20", "received", "0", "0", "success");
+        poison.from_address = POISONER.to_string();
+        poison.contract_address = Some(USDC.to_string());
+        poison.token_symbol = Some("USDC".to_string());
+        let mut spoof = export_tx("0xc", "erc20", "sent", "0", "0", "success");
+        spoof.to_address = POISONER.to_string();
+        spoof.contract_address = Some(USDC.to_string());
+        let mut dust = export_tx("0xd", "native", "received", "1", "0", "success");
+        dust.from_address = "0x2222222222222222222222222222222222222222".to_string();
+        let mut fake_token = export_tx("0xe", "erc20", "received", "5000000", "0", "success");
+        fake_token.contract_address = Some(RECIPIENT.to_string());
+        fake_token.token_symbol = Some("USDT".to_string());
+        fake_token.token_decimals = Some(6);
+
+        let risks = assess_transfers(&[paid, poison, spoof, dust, fake_token], 1, &[]);
+        let summary = risks.iter().map(|r| (r.hash.as_str(), r.score, r.reasons.join(","))).collect::<Vec<_>>();
+        assert_eq!(summary, [
+            ("0xa", 0, "".to_string()),
+            ("0xb", 90, "zero_value_transfer,lookalike_counterparty".to_string()),
+            ("0xc", 100, "zero_value_transfer_from_wallet,lookalike_counterparty".to_string()),
+            ("0xd", 10, "dust".to_string()),
+            ("0xe", 80, "token_impersonation".to_string()),
+        ]);
+        assert_eq!(risks[1].lookalike_of.as_deref(), Some(RECIPIENT));
+        assert_eq!(risks[2].counterparty, POISONER);
+    }
+
+    #[test]
+    fn recipient_checks() {
+        let friend = "0x2222222222222222222222222222222222222222";
+        let exchange = "0x4444444444444444444444444444444444444444";
+        let contacts = vec![
+            AddressBookEntry { label: "Friend".to_string(), address: friend.to_string(), ens: None, chains: vec![1], notes: None },
+            AddressBookEntry { label: "Exchange".to_string(), address: exchange.to_string(), ens: None, chains: vec![137], notes: None },
+        ];
+        let paid = vec![RECIPIENT.to_string()];
+        let own = "0x1111111111111111111111111111111111111111";
+        let check = |recipient: &str| assess_recipient(recipient, &paid, &contacts, &[], 1, own);
+
+        let known = check(&RECIPIENT.to_uppercase().replacen("0X", "0x", 1));
+        assert_eq!((known.score, known.warnings.len()), (0, 0));
+        let contact = check(friend);
+        assert_eq!((contact.score, contact.contact.as_deref()), (0, Some("Friend")));
+
+        let poisoned = check(POISONER);
+        assert_eq!((poisoned.score, poisoned.level.as_str()), (100, "high"));
+        assert_eq!(poisoned.warnings, ["lookalike", "first_time_recipient"]);
+        assert_eq!(poisoned.lookalike_of.as_deref(), Some(RECIPIENT));
+
+        let other_chain = check(exchange);
+        assert_eq!((other_chain.score, other_chain.contact), (20, None));
+        assert_eq!(other_chain.warnings, ["first_time_recipient", "contact_other_chain"]);
+
+        let token = check(USDC);
+        assert_eq!((token.score, token.token.as_deref()), (100, Some("USDC")));
+        assert_eq!(token.warnings, ["first_time_recipient", "token_contract_recipient"]);
+        let registry = vec![registry_token(friend, "FRND", &[])];
+        assert_eq!(assess_recipient(friend, &paid, &contacts, &registry, 1, own).token.as_deref(), Some("FRND"));
+
+        assert_eq!(check(own).warnings, ["own_address", "first_time_recipient"]);
+
+        // eth_getCode only adds a warning for contracts that aren't already known tokens
+        let mut contract = check(friend);
+        add_contract_warning(&mut contract, true);
+        assert_eq!((contract.is_contract, contract.score, contract.warnings.clone()), (Some(true), 20, vec!["contract_recipient".to_string()]));
+        let mut token = check(USDC);
+        add_contract_warning(&mut token, true);
+        assert_eq!((token.score, token.warnings.len()), (100, 2));
+        let mut wallet = check(friend);
+        add_contract_warning(&mut wallet, false);
+        assert_eq!((wallet.is_contract, wallet.score, wallet.level.as_str()), (Some(false), 0, "low"));
+    }
+
+    #[test]
+    fn address_book_contacts() {
+        let entry = address_book_entry(" Alice ", RECIPIENT, " Alice.ETH ", vec![1], "  ").unwrap();
+        assert_eq!((entry.label.as_str(), entry.ens.as_deref(), entry.notes.as_deref()), ("Alice", Some("alice.eth"), None));
+        assert_eq!(address_book_entry(" ", RECIPIENT, "", vec![], "").unwrap_err(), "Error: The contact has no label.");
+        assert_eq!(address_book_entry("Bob", "0x1234", "", vec![], "").unwrap_err(), "Error: Failed to parse the contact address.");
+        for ens in ["alice", ".eth", "alice.", "al ice.eth"] {
+            assert_eq!(address_book_entry("Bob", RECIPIENT, ens, vec![], "").unwrap_err(), "Error: Invalid ENS name.");
+        }
+
+        let everywhere = address_book_entry("Bob", "0x2222222222222222222222222222222222222222", "", vec![], "cold wallet").unwrap();
+        let contacts = vec![entry, everywhere];
+        assert_eq!(lookup_contact(&contacts, &RECIPIENT.to_uppercase().replacen("0X", "0x", 1), 1).map(|c| c.label.as_str()), Some("Alice"));
+        assert!(lookup_contact(&contacts, RECIPIENT, 137).is_none());
+        assert_eq!(lookup_contact(&contacts, "0x2222222222222222222222222222222222222222", 137).map(|c| c.label.as_str()), Some("Bob"));
+
+        // The counterparty is the recipient of sent entries and the sender otherwise
+        let sent = export_tx("0xa", "native", "sent", "1", "0", "success");
+        let mut received = export_tx("0xb", "native", "received", "1", "0", "success");
+        received.from_address = "0x2222222222222222222222222222222222222222".to_string();
+        let mut unknown = export_tx("0xc", "native", "received", "1", "0", "success");
+        unknown.counterparty_label = Some("stale".to_string());
+        let mut entries = vec![sent, received, unknown];
+        label_history(&mut entries, &contacts, 1);
+        assert_eq!(entries.iter().map(|e| e.counterparty_label.as_deref()).collect::<Vec<_>>(), [Some("Alice"), Some("Bob"), None]);
+
+        // Adding or importing an address that is already saved replaces its entry
+        let mut wallet = Wallet::new(String::new(), "m/0/0".to_string(), String::new(), 1);
+        assert!(wallet.add_contact("Alice".to_string(), RECIPIENT.to_string(), String::new(), vec![], String::new()).starts_with('{'));
+        let book = json!([
+            { "label": "Alice (new)", "address": RECIPIENT.to_uppercase().replacen("0X", "0x", 1) },
+            { "label": "", "address": RECIPIENT },
+        ]);
+        let result: Value = serde_json::from_str(&wallet.import_address_book(book.to_string())).unwrap();
+        assert_eq!(result["imported"], 1);
+        assert_eq!(result["invalid"][0]["index"], 1);
+        assert_eq!(wallet.address_book.len(), 1);
+        assert_eq!(wallet.address_book[0].label, "Alice (new)");
+        wallet.remove_contact(format!(" {} ", RECIPIENT));
+        assert!(wallet.address_book.is_empty());
+    }
+
+    #[test]
+    fn aggregate3_vectors() {
+        let calls = vec![(USDC.to_string(), hex::decode("313ce567").unwrap())];
+        let expected = [
+            abi_word("20"), abi_word("1"), abi_word("20"),
+            abi_word(USDC), abi_word("1"), abi_word("60"), abi_word("4"), format!("{:0<64}", "313ce567"),
+        ].concat();
+        assert_eq!(hex::encode(encode_aggregate3(&calls).unwrap()), format!("82ad56cb{}", expected));
+        assert!(encode_aggregate3(&[("0x1234".to_string(), Vec::new())]).is_err());
+
+        // [(true, uint256 6), (false, "")]
+        let output = [
+            abi_word("20"), abi_word("2"), abi_word("40"), abi_word("c0"),
+            abi_word("1"), abi_word("40"), abi_word("20"), abi_word("6"),
+            abi_word("0"), abi_word("40"), abi_word("0"),
+        ].concat();
+        let results = decode_aggregate3(&hex::decode(output).unwrap()).unwrap();
+        assert_eq!(results, [Ok(hex::decode(abi_word("6")).unwrap()), Err("Error: Call reverted.".to_string())]);
+        assert_eq!(decode_aggregate3(&[0u8; 16]).unwrap_err(), "Error: Failed to decode multicall result.");
+    }
+
+    #[test]
+    fn merge_history_pages_cursor_rules() {
+        let hashes = |entries: &[EtherscanTx]| entries.iter().map(|e| e.hash.clone()).collect::<Vec<_>>();
+        let page = |rows: &[(&str, u64)]| rows.iter().map(|(h, b)| history_tx(h, *b)).collect::<Vec<_>>();
+
+        // Short pages under the limit are the whole history
+        let (entries, cursor) = merge_history_pages(vec![(page(&[("a", 100)]), false), (page(&[("b", 120)]), false), (Vec::new(), true)], 10).unwrap();
+        assert_eq!((hashes(&entries), cursor), (vec!["b".to_string(), "a".to_string()], None));
+
+        // Over the limit the page is cut after a whole block, even if that returns more than the limit
+        let rows = page(&[("a", 100), ("b", 99), ("c", 99), ("d", 98), ("e", 97)]);
+        let (entries, cursor) = merge_history_pages(vec![(rows.clone(), false)], 3).unwrap();
+        assert_eq!((hashes(&entries), cursor), (vec!["a".to_string(), "b".to_string(), "c".to_string()], Some(98)));
+        let (entries, cursor) = merge_history_pages(vec![(rows, false)], 2).unwrap();
+        assert_eq!((entries.len(), cursor), (3, Some(98)));
+        let (entries, cursor) = merge_history_pages(vec![(page(&[("a", 50), ("b", 50)]), false)], 1).unwrap();
+        assert_eq!((entries.len(), cursor), (2, None));
+
+        // Rows of one block are ordered newest first by timestamp
+        let mut later = history_tx("late", 70);
+        later.timestamp = "71".to_string();
+        let (entries, _) = merge_history_pages(vec![(page(&[("early", 70)]), false), (vec![later], false)], 10).unwrap();
+        assert_eq!(hashes(&entries), ["late", "early"]);
+
+        // With several full pages the highest cut-off block wins
+        let native = page(&[("a", 100), ("b", 90)]);
+        let tokens = page(&[("c", 98), ("d", 95)]);
+        let (entries, cursor) = merge_history_pages(vec![(native, true), (tokens, true)], 10).unwrap();
+        assert_eq!((hashes(&entries), cursor), (vec!["a".to_string(), "c".to_string()], Some(95)));
+
+        // Nothing lies above a full page that ends in the newest block
+        let native = page(&[("a", 100), ("b", 100)]);
+        let tokens = page(&[("c", 100), ("d", 99)]);
+        assert_eq!(merge_history_pages(vec![(native, true), (tokens, true)], 10).unwrap_err(), 100);
+    }
+}
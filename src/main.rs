@@ -1,58 +1,486 @@
-use cardware_evm::Wallet;
+//Command line tool for driving the Cardware device flow from a terminal.
+//Build with `cargo run --features cli -- <command>`, see `cardware-evm --help`.
+use cardware_evm::{chain_info, chunk_and_label, decode_unsigned_tx, encode_erc1155_transfer, encode_erc721_transfer, encode_transfer, join_qr_chunks, Wallet};
+use clap::{Parser, Subcommand, ValueEnum};
+use ethereum_types::{Address, U256};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::io::Read;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+const CONFIG_ENV: &str = "CARDWARE_EVM_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "cardware-evm.json";
+
+#[derive(Parser)]
+#[command(name = "cardware-evm", version, about = "Watch-only EVM wallet for the Cardware device")]
+struct Cli {
+    /// JSON config file, defaults to $CARDWARE_EVM_CONFIG or ./cardware-evm.json
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    /// Account xpub/zpub from the device, overrides the config file
+    #[arg(long, global = true)]
+    xpub: Option<String>,
+    /// Account derivation path such as m/0/0, overrides the config file
+    #[arg(long, global = true)]
+    path: Option<String>,
+    /// RPC node URL, overrides the config file
+    #[arg(long, global = true)]
+    rpc_url: Option<String>,
+    /// Chain ID, overrides the config file
+    #[arg(long, global = true)]
+    chain_id: Option<u64>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Show the account address
+    Address,
+    /// Fetch the balance, nonce and gas prices from the RPC node
+    Sync,
+    /// Build an unsigned transaction payload for the device
+    Build {
+        #[command(subcommand)]
+        kind: BuildKind,
+        /// 0 = slow, 1 = medium, 2 = fast
+        #[arg(long, global = true, default_value_t = 1)]
+        fee_rate: i32,
+        /// Transaction type, auto uses the chain's default
+        #[arg(long, global = true, value_enum, default_value_t = TxType::Auto)]
+        tx_type: TxType,
+        /// Also split the payload into labelled QR chunks
+        #[arg(long, global = true)]
+        qr: bool,
+    },
+    /// Split a payload into labelled QR chunks, reads stdin when no payload is given
+    QrSplit {
+        payload: Option<String>,
+        #[arg(long)]
+        chunk_size: Option<usize>,
+    },
+    /// Join labelled QR chunks back into a payload, reads one chunk per line from stdin when none are given
+    QrJoin {
+        chunks: Vec<String>,
+    },
+    /// Combine an unsigned transaction and the device signature into a signed raw transaction
+    Combine {
+        /// The unsigned transaction, or "unsigned_tx:signature" as scanned from the device
+        unsigned_tx: String,
+        /// The base64 signature from the device
+        signature: Option<String>,
+    },
+    /// Decode an unsigned transaction payload, reads stdin when no payload is given
+    Decode {
+        payload: Option<String>,
+    },
+    /// Broadcast an unsigned transaction with the device signature
+    Broadcast {
+        /// The unsigned transaction, or "unsigned_tx:signature" as scanned from the device
+        unsigned_tx: String,
+        /// The base64 signature from the device
+        signature: Option<String>,
+    },
+    /// Show the transaction history from the block explorer, or from token transfer logs with --logs
+    History {
+        #[arg(long, default_value_t = 10)]
+        limit: u32,
+        /// Read ERC20/721/1155 transfer logs from the RPC node instead of the explorer
+        #[arg(long)]
+        logs: bool,
+        /// The next_cursor of the previous page
+        #[arg(long, default_value = "")]
+        cursor: String,
+        /// With --logs, the oldest block to scan
+        #[arg(long, default_value_t = 0)]
+        from_block: u64,
+        /// Explorer API URL, defaults to the config file or the chain registry
+        #[arg(long)]
+        api_url: Option<String>,
+        /// Explorer API key, overrides the config file
+        #[arg(long)]
+        api_key: Option<String>,
+        /// Print the explorer history as csv, koinly or cointracking CSV
+        #[arg(long)]
+        export: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BuildKind {
+    /// Native currency transfer, value in wei
+    Eth { to: String, value: String },
+    /// ERC20 transfer, amount in the token's smallest unit
+    Erc20 { contract: String, to: String, amount: String },
+    /// ERC721 transferFrom, or safeTransferFrom with --safe
+    Erc721 {
+        contract: String,
+        to: String,
+        token_id: String,
+        /// Use safeTransferFrom, which reverts if the recipient contract can't receive NFTs
+        #[arg(long)]
+        safe: bool,
+        /// Hex data passed to the receiver with --safe
+        #[arg(long, default_value = "")]
+        data: String,
+    },
+    /// ERC1155 safeTransferFrom
+    Erc1155 { contract: String, to: String, token_id: String, amount: String },
+    /// ERC1155 safeBatchTransferFrom, many ids in one transaction
+    Erc1155Batch {
+        contract: String,
+        to: String,
+        /// Comma separated token IDs
+        #[arg(long, value_delimiter = ',', required = true)]
+        ids: Vec<String>,
+        /// Comma separated amounts, one per token ID
+        #[arg(long, value_delimiter = ',', required = true)]
+        amounts: Vec<String>,
+        /// Hex data passed to the receiver
+        #[arg(long, default_value = "")]
+        data: String,
+    },
+    /// Contract call with raw calldata
+    Raw {
+        to: String,
+        data: String,
+        /// Value in wei
+        #[arg(long, default_value = "0")]
+        value: String,
+        #[arg(long, default_value = "100000")]
+        gas_limit: String,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum TxType {
+    Auto,
+    Legacy,
+    Eip1559,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Config {
+    xpub: String,
+    account_derivation_path: String,
+    rpc_url: String,
+    chain_id: u64,
+    explorer_api_url: String,
+    explorer_api_key: String,
+    qr_chunk_size: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            xpub: String::new(),
+            account_derivation_path: "m/0/0".to_string(),
+            rpc_url: String::new(),
+            chain_id: 1,
+            explorer_api_url: String::new(),
+            explorer_api_key: String::new(),
+            qr_chunk_size: 200,
+        }
+    }
+}
+
+//What a command prints, `json` with --json and `text` otherwise
+struct Output {
+    json: Value,
+    text: String,
+}
+
+impl Output {
+    fn new(json: Value, text: impl Into<String>) -> Output {
+        Output { json, text: text.into() }
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    println!("Hello, world!");
-    let mut wal = Wallet::new("zpub6qhLodRvBBKKmnMHWf3SUgucomzpcR8mRRw9V8sW9sEiLbVDwwN4N5y5tfojPWhKkuxyTtCsuv4W45q9MuxB95iFBVD2mXEyHrkmka1Woxq".to_string(),
-                              "m/0/0".to_string(),
-                              "https://mainnet.infura.io/v3/498507efe2844f7cb9d8f25dd6e8f92b".to_string(),1);
-    println!("Address: {:?}",wal.address());
-    let res = wal.sync().await;
-    println!("Res? {:?}",res);
-    
-    println!("Eth balance: {:?}",wal.balance());
-    let res = wal.send_eip1559("0x02A8665a18BBa2D1B4766e2D71977A781b97592e".to_string(),"544000000000",1);
-    println!("RES: {:?}",res);
-
-    let res5 = wal.prepare_sign_typed_data_v4("{\"domain\":{\"name\":\"Permit2\",\"chainId\":1,\"verifyingContract\":\"0x31c2f6fcff4f8759b3bd5bf0e1084a055615c768\"},\"message\":{\"details\":{\"token\":\"0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48\",\"amount\":\"1461501637330902918203684832716283019655932542975\",\"expiration\":\"1750587227\",\"nonce\":\"0\"},\"spender\":\"0x65b382653f7c31bc0af67f188122035461ec9c76\",\"sigDeadline\":\"1747997027\"},\"primaryType\":\"PermitSingle\",\"types\":{\"EIP712Domain\":[{\"name\":\"name\",\"type\":\"string\"},{\"name\":\"chainId\",\"type\":\"uint256\"},{\"name\":\"verifyingContract\",\"type\":\"address\"}],\"PermitSingle\":[{\"name\":\"details\",\"type\":\"PermitDetails\"},{\"name\":\"spender\",\"type\":\"address\"},{\"name\":\"sigDeadline\",\"type\":\"uint256\"}],\"PermitDetails\":[{\"name\":\"token\",\"type\":\"address\"},{\"name\":\"amount\",\"type\":\"uint160\"},{\"name\":\"expiration\",\"type\":\"uint48\"},{\"name\":\"nonce\",\"type\":\"uint48\"}]}}".to_string());
-    println!("EIP712 res: {:?}",res5);
-
-
-    // Test transaction history
-    println!("\n=== Testing Transaction History ===");
-    const ETHERSCAN_API_KEY: &str = "KAQABZ3CB12ETJC8QG6WT3DRI2IH95I8I7";
-    let tx_history = wal.get_tx_history("https://api.etherscan.io/v2/api", ETHERSCAN_API_KEY, 5).await;
-    println!("Transaction History (first 5 transactions):");
-    println!("{}", tx_history);
-    //estimate fee example:
-    //Erc20 Balance
-    /*
-    let erc_bal = wal.erc20_balance(["0xdAC17F958D2ee523a2206206994597C13D831ec7".to_string(),
-                                      "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string()].to_vec()).await;
-    println!("erc20 bal: {:?}",erc_bal);
-
-
-    //Broadcast example
-    //let res3 = wal.broadcast("ea80850cce4166008252089402a8665a18bba2d1b4766e2d71977a781b97592e857ea8ed40008081928080".to_string(),
-    //                          "swUgntcRQS9BzpOfHNB9sZUbK5rwE0M9hvBAfSBohJlN6Xs2ijDS+UVz9U8/QRkYNN+yVp3y2ptBxFkaulVt8Rw=".to_string()).await; 
-    //println!("res3: {:?}",res3); 
-
-    let erc_20_send = wal.erc20_transfer("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string(),
-                                       "0x02A8665a18BBa2D1B4766e2D71977A781b97592e".to_string(),
-                                       "1000000",2);
-
-    println!("erc 20 send: {:?}",erc_20_send);
-
-    //let res3 = wal.broadcast("f869078447596cda8302710094a0b86991c6218b36c1d19d4a2e9eb0ce3606eb4880b844a9059cbb00000000000000000000000002a8665a18bba2d1b4766e2d71977a781b97592e00000000000000000000000000000000000000000000000000000000000f4240018080".to_string(),
-    //                          "XQdtleo1VPCj0YGpghoStvRZB2heI+4/c5X5PdN2T9tAQSsZSa1ssE0iEDW+KK1KQrEx2ko9Mv98QOae8c/UHRw=".to_string()).await; 
-    //println!("res3: {:?}",res3); 
-    //let con_data = wal.validate_contract("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string()).await;
-    //println!("contract data {}: ",con_data);
-    println!("nonce: {:?}",wal.get_nonce());
-    println!("chain_id: {:?}",wal.get_chain_id());
-    println!("hex to b64: {:?}",wal.hex_to_b64("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48".to_string()));
-    let res3 = wal.construct_signed_tx("0x02f8700108847735940085017e73c1dc82fe7b94a0b86991c6218b36c1d19d4a2e9eb0ce3606eb4880b844095ea7b3000000000000000000000000000000000022d473030f116ddee9f6b43ac78ba3ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffc0808080".to_string(),
-                                      "148v8u32WP1MW3JOk/Iz1OOKMZ8jLO4An1jcfGzx7AksGQtP+dm8lw6Zri94txhV+gQEeMrnvleImffPG1wQkRs=".to_string());
-    println!("reso {:?}",res3);
-    */
+    let cli = Cli::parse();
+    let result = match load_config(&cli) {
+        Ok(config) => run(cli.command, config).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(out) if cli.json => println!("{}", out.json),
+        Ok(out) => println!("{}", out.text),
+        Err(e) => {
+            if cli.json {
+                println!("{}", json!({ "error": e }));
+            } else {
+                eprintln!("{}", e);
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+// Reads the config file (a missing default file is fine) and applies the command line overrides
+fn load_config(cli: &Cli) -> Result<Config, String> {
+    let explicit = cli.config.clone().or_else(|| std::env::var_os(CONFIG_ENV).map(PathBuf::from));
+    let mut config = match &explicit {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("Error: Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&text).map_err(|e| format!("Error: Invalid config file {}: {}", path.display(), e))?
+        }
+        None => match std::fs::read_to_string(DEFAULT_CONFIG_FILE) {
+            Ok(text) => serde_json::from_str(&text).map_err(|e| format!("Error: Invalid config file {}: {}", DEFAULT_CONFIG_FILE, e))?,
+            Err(_) => Config::default(),
+        },
+    };
+    if let Some(xpub) = &cli.xpub { config.xpub = xpub.clone(); }
+    if let Some(path) = &cli.path { config.account_derivation_path = path.clone(); }
+    if let Some(rpc_url) = &cli.rpc_url { config.rpc_url = rpc_url.clone(); }
+    if let Some(chain_id) = cli.chain_id { config.chain_id = chain_id; }
+    Ok(config)
+}
+
+async fn run(command: Command, config: Config) -> Result<Output, String> {
+    match command {
+        Command::Address => {
+            let mut wallet = wallet(&config, false)?;
+            let address = check(wallet.address())?;
+            Ok(Output::new(json!({
+                "address": address,
+                "derivation_path": config.account_derivation_path,
+                "chain_id": config.chain_id,
+            }), address))
+        }
+        Command::Sync => {
+            let mut wallet = synced_wallet(&config).await?;
+            let address = wallet.address();
+            let chain = chain_info(config.chain_id);
+            let text = format!("Address: {}\nBalance: {} {}\nNonce:   {}", address, wallet.balance(), chain.native_symbol, wallet.get_nonce());
+            Ok(Output::new(json!({
+                "address": address,
+                "balance": wallet.balance(),
+                "symbol": chain.native_symbol,
+                "nonce": wallet.get_nonce(),
+                "chain_id": config.chain_id,
+                "pending_transactions": serde_json::from_str::<Value>(&wallet.pending_transactions()).unwrap_or(Value::Null),
+            }), text))
+        }
+        Command::Build { kind, fee_rate, tx_type, qr } => {
+            let mut wallet = synced_wallet(&config).await?;
+            let eip1559 = match tx_type {
+                TxType::Auto => chain_info(config.chain_id).default_tx_type == 2,
+                TxType::Legacy => false,
+                TxType::Eip1559 => true,
+            };
+            let payload = check(build(&mut wallet, kind, fee_rate, eip1559)?)?;
+            let decoded = decode_unsigned_tx(&payload)?;
+            let mut json = json!({ "payload": payload, "transaction": decoded });
+            let mut text = payload.clone();
+            if qr {
+                let chunks = chunk_and_label(&payload, config.qr_chunk_size.max(1));
+                text = chunks.join("\n");
+                json["qr_chunks"] = json!(chunks);
+            }
+            Ok(Output::new(json, text))
+        }
+        Command::QrSplit { payload, chunk_size } => {
+            let payload = arg_or_stdin(payload)?;
+            let chunks = chunk_and_label(&payload, chunk_size.unwrap_or(config.qr_chunk_size).max(1));
+            let text = chunks.join("\n");
+            Ok(Output::new(json!(chunks), text))
+        }
+        Command::QrJoin { chunks } => {
+            let chunks = if chunks.is_empty() {
+                read_stdin()?.lines().map(|l| l.to_string()).collect()
+            } else {
+                chunks
+            };
+            let payload = join_qr_chunks(&chunks)?;
+            Ok(Output::new(json!({ "payload": payload }), payload))
+        }
+        Command::Combine { unsigned_tx, signature } => {
+            let (unsigned_tx, signature) = split_signed(unsigned_tx, signature)?;
+            let wallet = wallet(&config, false)?;
+            let signed = check(wallet.construct_signed_tx(unsigned_tx, signature))?;
+            Ok(Output::new(json!({ "signed_tx": signed }), signed))
+        }
+        Command::Decode { payload } => {
+            let decoded = decode_unsigned_tx(&arg_or_stdin(payload)?)?;
+            let text = serde_json::to_string_pretty(&decoded).unwrap_or_default();
+            Ok(Output::new(decoded, text))
+        }
+        Command::Broadcast { unsigned_tx, signature } => {
+            let (unsigned_tx, signature) = split_signed(unsigned_tx, signature)?;
+            let mut wallet = wallet(&config, true)?;
+            wallet.address();
+            // The crate's EIP 1559 payloads have no 0x02 prefix, so route on the decoded type
+            let tx_hash = if decode_unsigned_tx(&unsigned_tx)?["type"] == 2 {
+                wallet.broadcast_eip1559(unsigned_tx, signature).await
+            } else {
+                wallet.broadcast(unsigned_tx, signature).await
+            };
+            let tx_hash = check(tx_hash)?;
+            let chain = chain_info(config.chain_id);
+            let mut json = json!({ "tx_hash": tx_hash });
+            if !chain.explorer_url.is_empty() {
+                json["explorer_url"] = json!(format!("{}/tx/{}", chain.explorer_url, tx_hash));
+            }
+            Ok(Output::new(json, tx_hash))
+        }
+        Command::History { limit, logs: true, cursor, from_block, .. } => {
+            let mut wallet = wallet(&config, true)?;
+            let page = check(wallet.get_log_history(cursor, limit, from_block).await)?;
+            let page: Value = serde_json::from_str(&page).map_err(|_| "Error: Failed to deserialize.".to_string())?;
+            let mut lines: Vec<String> = page["entries"].as_array().map(|entries| {
+                entries.iter().map(|e| format!("{} {:<8} {:<11} {} {}{}  block {}",
+                    e["hash"].as_str().unwrap_or(""),
+                    e["direction"].as_str().unwrap_or(""),
+                    e["kind"].as_str().unwrap_or(""),
+                    e["value"].as_str().unwrap_or("0"),
+                    e["contract_address"].as_str().unwrap_or(""),
+                    e["token_id"].as_str().map(|id| format!(" #{}", id)).unwrap_or_default(),
+                    e["block_number"].as_u64().map(|b| b.to_string()).unwrap_or_else(|| e["status"].as_str().unwrap_or("").to_string()),
+                )).collect()
+            }).unwrap_or_default();
+            if let Some(next) = page["next_cursor"].as_str() {
+                lines.push(format!("More: --cursor {}", next));
+            }
+            Ok(Output::new(page, lines.join("\n")))
+        }
+        Command::History { limit, cursor, api_url, api_key, export, .. } => {
+            let mut wallet = wallet(&config, false)?;
+            let api_url = api_url.unwrap_or(config.explorer_api_url);
+            let api_key = api_key.unwrap_or(config.explorer_api_key);
+            let page = check(wallet.get_tx_history_page(&api_url, &api_key, cursor, limit).await)?;
+            if let Some(format) = export {
+                let csv = check(wallet.export_tx_history(page.clone(), format))?;
+                let next_cursor = serde_json::from_str::<Value>(&page).map(|p| p["next_cursor"].clone()).unwrap_or(Value::Null);
+                return Ok(Output::new(json!({ "csv": csv, "next_cursor": next_cursor }), csv.trim_end()));
+            }
+            let page: Value = serde_json::from_str(&page).map_err(|_| "Error: Failed to deserialize.".to_string())?;
+            let mut lines: Vec<String> = page["entries"].as_array().map(|txs| {
+                txs.iter().map(|tx| format!("{} {:<8} {:<8} {} {}{}  block {}  {}",
+                    tx["hash"].as_str().unwrap_or(""),
+                    tx["direction"].as_str().unwrap_or(""),
+                    tx["kind"].as_str().unwrap_or(""),
+                    tx["value_wei"].as_str().unwrap_or("0"),
+                    tx["token_symbol"].as_str().unwrap_or("wei"),
+                    tx["token_id"].as_str().map(|id| format!(" #{}", id)).unwrap_or_default(),
+                    tx["block_number"].as_str().unwrap_or(""),
+                    tx["status"].as_str().unwrap_or(""),
+                )).collect()
+            }).unwrap_or_default();
+            if let Some(next) = page["next_cursor"].as_str() {
+                lines.push(format!("More: --cursor {}", next));
+            }
+            Ok(Output::new(page, lines.join("\n")))
+        }
+    }
+}
+
+// Builds the unsigned payload for a `build` subcommand, the wallet must already be synced
+fn build(wallet: &mut Wallet, kind: BuildKind, fee_rate: i32, eip1559: bool) -> Result<String, String> {
+    let (to, value, data, gas_limit) = match kind {
+        BuildKind::Eth { to, value } => {
+            parse_address(&to, "recipient")?;
+            return Ok(if eip1559 {
+                wallet.send_eip1559(to, &value, fee_rate)
+            } else {
+                wallet.send(to, &value, fee_rate)
+            });
+        }
+        BuildKind::Erc20 { contract, to, amount } => {
+            parse_address(&to, "recipient")?;
+            (contract, "0".to_string(), encode_transfer(&to, parse_amount(&amount)?), "160000".to_string())
+        }
+        BuildKind::Erc721 { contract, to, token_id, safe: true, data } => {
+            return Ok(if eip1559 {
+                wallet.erc721_safe_transfer_eip1559(contract, to, &token_id, data, fee_rate)
+            } else {
+                wallet.erc721_safe_transfer(contract, to, &token_id, data, fee_rate)
+            });
+        }
+        BuildKind::Erc721 { contract, to, token_id, .. } => {
+            parse_address(&to, "recipient")?;
+            let from = wallet.address();
+            (contract, "0".to_string(), encode_erc721_transfer(&from, &to, parse_amount(&token_id)?), "100000".to_string())
+        }
+        BuildKind::Erc1155 { contract, to, token_id, amount } => {
+            parse_address(&to, "recipient")?;
+            let from = wallet.address();
+            (contract, "0".to_string(), encode_erc1155_transfer(&from, &to, parse_amount(&token_id)?, parse_amount(&amount)?), "200000".to_string())
+        }
+        BuildKind::Erc1155Batch { contract, to, ids, amounts, data } => {
+            return Ok(if eip1559 {
+                wallet.erc1155_batch_transfer_eip1559(contract, to, ids, amounts, data, fee_rate)
+            } else {
+                wallet.erc1155_batch_transfer(contract, to, ids, amounts, data, fee_rate)
+            });
+        }
+        BuildKind::Raw { to, data, value, gas_limit } => {
+            let data = hex::decode(data.trim_start_matches("0x")).map_err(|_| "Error: Failed to decode the data field.".to_string())?;
+            (to, value, data, gas_limit)
+        }
+    };
+    parse_address(&to, "contract")?;
+    Ok(if eip1559 {
+        wallet.contract_call_eip1559(to, &value, hex::encode(data), &gas_limit, fee_rate)
+    } else {
+        wallet.contract_call(to, &value, hex::encode(data), &gas_limit, fee_rate)
+    })
+}
+
+fn wallet(config: &Config, needs_rpc: bool) -> Result<Wallet, String> {
+    if config.xpub.is_empty() {
+        return Err("Error: No xpub, set \"xpub\" in the config file or pass --xpub.".to_string());
+    }
+    if needs_rpc && config.rpc_url.is_empty() {
+        return Err("Error: No RPC URL, set \"rpc_url\" in the config file or pass --rpc-url.".to_string());
+    }
+    Ok(Wallet::new(config.xpub.clone(), config.account_derivation_path.clone(), config.rpc_url.clone(), config.chain_id))
+}
+
+async fn synced_wallet(config: &Config) -> Result<Wallet, String> {
+    let mut wallet = wallet(config, true)?;
+    check(wallet.address())?;
+    check(wallet.sync().await)?;
+    Ok(wallet)
+}
+
+// Library calls report failures as strings starting with "Error"
+fn check(result: String) -> Result<String, String> {
+    if result.starts_with("Error") { Err(result) } else { Ok(result) }
+}
+
+fn parse_address(address: &str, name: &str) -> Result<Address, String> {
+    Address::from_str(address).map_err(|_| format!("Error: Failed to parse the {} address.", name))
+}
+
+fn parse_amount(amount: &str) -> Result<U256, String> {
+    U256::from_dec_str(amount).map_err(|_| format!("Error: Failed to parse {}.", amount))
+}
+
+// The device shows "unsigned_tx:signature", accept it whole or as two arguments
+fn split_signed(unsigned_tx: String, signature: Option<String>) -> Result<(String, String), String> {
+    match signature {
+        Some(sig) => Ok((unsigned_tx, sig)),
+        None => unsigned_tx.split_once(':')
+            .map(|(tx, sig)| (tx.to_string(), sig.trim_start_matches('&').to_string()))
+            .ok_or_else(|| "Error: No transaction signature given.".to_string()),
+    }
+}
+
+fn arg_or_stdin(arg: Option<String>) -> Result<String, String> {
+    match arg {
+        Some(a) => Ok(a),
+        None => Ok(read_stdin()?.trim().to_string()),
+    }
+}
+
+fn read_stdin() -> Result<String, String> {
+    let mut input = String::new();
+    std::io::stdin().read_to_string(&mut input).map_err(|e| format!("Error: Failed to read stdin: {}", e))?;
+    Ok(input)
 }